@@ -0,0 +1,41 @@
+//! Compares parsing a large ASCII file with a monospace font through the
+//! fast path, which maps chars straight to glyphs, against shaping it.
+
+use std::{path::PathBuf, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use duck_wgpu::{document::Document, fonts::FontSource};
+
+fn ascii_fast_path(c: &mut Criterion) {
+    let path =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/fonts/DejaVuSansMono.ttf");
+    let data = std::fs::read(path).expect("failed to read font");
+    let mut source = FontSource::new();
+    let id = source
+        .load_from_bytes(Arc::new(data), 0)
+        .expect("failed to load font");
+    let fonts = [source.get_font(id)];
+    let text = (0..2000)
+        .map(|i| format!("    let value_{i} = compute(input[{i}], {i} * 2) + offset; // line {i}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    // the fast path needs the default ligatures turned off
+    let ligatures_off = [b"liga", b"clig", b"calt"].map(|tag| (swash::tag_from_bytes(tag), 0));
+
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(20);
+    for (name, features) in [("shaped", &[][..]), ("fast_path", &ligatures_off[..])] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut document = Document::from_str(&text);
+                document.set_features(features);
+                document.parse(&fonts, 16.).expect("failed to parse");
+                document
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ascii_fast_path);
+criterion_main!(benches);
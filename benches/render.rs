@@ -0,0 +1,48 @@
+//! Compares repainting only the dirty rect of a frame, as when typing on
+//! one line, against repainting the whole frame. Needs a GPU or software
+//! adapter.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use duck_wgpu::gfx::{
+    color,
+    compositor::Compositor,
+    image_cache::ImageCache,
+    types::Rect,
+    wgpu_context::{DeviceOptions, WgpuContext},
+};
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 800;
+const LINE_HEIGHT: f32 = 16.;
+
+fn dirty_rects(c: &mut Criterion) {
+    let mut context =
+        WgpuContext::new_headless(WIDTH, HEIGHT, 1., color::WHITE, &DeviceOptions::default());
+    let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+    // a screen full of text, one rect per glyph
+    let mut compositor = Compositor::new();
+    compositor.begin();
+    for line in 0..(HEIGHT as f32 / LINE_HEIGHT) as u32 {
+        let y = line as f32 * LINE_HEIGHT;
+        for column in 0..WIDTH / 8 {
+            let x = column as f32 * 8.;
+            compositor.draw_rect_auto([x + 1., y + 3., 6., 10.], color::BLACK.with_alpha(224));
+        }
+    }
+    let display_list = compositor.end();
+    let line = [Rect::new(0., 5. * LINE_HEIGHT, WIDTH as f32, LINE_HEIGHT)];
+
+    let mut group = c.benchmark_group("render");
+    for (name, dirty_rects) in [("full_frame", None), ("dirty_line", Some(&line[..]))] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                assert!(context.render_to_texture(&mut image_cache, &display_list, dirty_rects));
+                context.device.poll(wgpu::Maintain::Wait);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dirty_rects);
+criterion_main!(benches);
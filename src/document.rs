@@ -1,133 +1,1960 @@
-use std::io;
+use std::{collections::HashMap, fmt::{Display, Write as _}, io, ops::Range};
 
-use swash::{text::{cluster::{Parser, Token, CharCluster, SourceRange}, Script}, shape::cluster::Glyph};
+use swash::{GlyphId, Tag, scale::{image::Content, Render, ScaleContext, Source, StrikeWith}, zeno::{Angle, Command, PathData, Transform}, text::{cluster::{Parser, Token, CharCluster, SourceRange}, Codepoint, Script}, shape::{cluster::Glyph, Direction}};
+use unicode_bidi::{BidiInfo, Level};
 
-use crate::{layout::Layout, fonts::{Font, ShapeContext}};
+use crate::{layout::{Cluster, Decoration, Layout, Line, Synthesis, WritingMode}, fonts::{Font, FontId, FontSource, FontStyle, FontWeight, ShapeContext}, gfx::{color::{self, Color}, types::Rect}};
 
 pub struct Document {
     rope: ropey::Rope,
     pub layout: Layout,
     is_dirty: bool,
+    /// Paragraph index and source byte range still to be reshaped after an
+    /// edit. When `None` and `is_dirty` is set the whole document is parsed.
+    dirty: Option<(usize, Range<usize>)>,
+    tab_width: usize,
+    /// Scale factor of the display, applied to the size given to
+    /// [`Document::parse`].
+    scale: f32,
+    language: Option<String>,
+    default_color: Color,
+    /// Styled byte ranges, later spans taking precedence over earlier ones.
+    styles: Vec<(Range<usize>, Style)>,
+    show_whitespace: bool,
+    whitespace_color: Color,
+    line_ending: LineEnding,
+    /// Whether lines are shaped on rayon's thread pool.
+    parallel: bool,
+    /// OpenType features and their values, applied when shaping.
+    features: Vec<(Tag, u32)>,
+    missing_glyph_style: MissingGlyphStyle,
+    /// Byte offset of the end of the selection that stays put.
+    anchor: usize,
+    /// Byte offset of the cursor, the end of the selection that moves.
+    head: usize,
+    /// Horizontal position kept by consecutive vertical cursor moves, so
+    /// that passing a short line doesn't pull the cursor to the left.
+    goal_x: Option<f32>,
 }
 
+/// Line break convention of a document.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum LineEnding {
+    /// `\n` breaks lines, a `\r` on its own is ignored.
+    #[default]
+    Lf,
+    /// `\r\n` breaks lines. Lines are broken at `\n` like [`LineEnding::Lf`],
+    /// so this only changes the line break inserted by editing.
+    CrLf,
+    /// `\r` breaks lines, as does `\n`. `\r\n` is a single line break.
+    Cr,
+}
+
+impl LineEnding {
+    /// Returns the most common line ending in `text`, or
+    /// [`LineEnding::Lf`] if there are no line breaks.
+    pub fn detect(text: &ropey::Rope) -> LineEnding {
+        let (mut lf, mut crlf, mut cr) = (0, 0, 0);
+        let mut bytes = text.bytes().peekable();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'\r' if bytes.peek() == Some(&b'\n') => {
+                    bytes.next();
+                    crlf += 1;
+                }
+                b'\r' => cr += 1,
+                b'\n' => lf += 1,
+                _ => {}
+            }
+        }
+        if cr > lf && cr > crlf {
+            LineEnding::Cr
+        } else if crlf > lf {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    /// Returns the text of a line break.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// Styling of a span of text.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Style {
+    /// Color of the text, or `None` for the document's default color.
+    pub color: Option<Color>,
+    pub weight: FontWeight,
+    pub style: FontStyle,
+    /// Multiplier applied to the document's font size.
+    pub size: f32,
+    /// Lines drawn under or through the text.
+    pub decoration: Decoration,
+    /// Extra space between clusters, as a fraction of the font size, e.g.
+    /// 0.05 to spread out a heading. Negative values tighten the text.
+    /// Ligatures and combining sequences are clusters, so they're never
+    /// spread apart.
+    pub letter_spacing: f32,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            color: None,
+            weight: FontWeight::NORMAL,
+            style: FontStyle::Normal,
+            size: 1.,
+            decoration: Decoration::default(),
+            letter_spacing: 0.,
+        }
+    }
+}
+
+/// How clusters no font has glyphs for are drawn, see
+/// [`Document::set_missing_glyph_style`].
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum MissingGlyphStyle {
+    /// The `.notdef` glyph of the last font, which may be blank.
+    #[default]
+    Notdef,
+    /// A box outline with the cluster's first code point in hex inside,
+    /// like a last resort font.
+    HexBox,
+    /// A filled box.
+    SolidBox,
+    /// Nothing, the cluster takes up no space.
+    Hidden,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// No fonts were given to shape with.
+    NoFonts,
+    /// A font produced no glyphs for the cluster at `cluster`.
+    ShapingMismatch { font_index: usize, cluster: usize },
+    /// The shaper returned glyphs for a cluster index that wasn't added.
+    ClusterOutOfRange { font_index: usize },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Source information for a cluster produced by the parser.
+struct SourceCluster {
+    range: Range<usize>,
+    is_whitespace: bool,
+    is_tab: bool,
+    /// True for control characters other than tabs, e.g. a `\r` that
+    /// doesn't break the line, which keep their place but take no space.
+    is_control: bool,
+    /// Bidi embedding level of the cluster.
+    level: u8,
+}
+
+const DEFAULT_TAB_WIDTH: usize = 4;
+const DEFAULT_WHITESPACE_COLOR: Color = Color::new(0, 0, 0, 64);
+/// Emboldening for a synthetic bold face, as a fraction of the font size.
+const SYNTHETIC_BOLD_STRENGTH: f32 = 1. / 32.;
+/// Slant of a synthetic italic face, in degrees.
+const SYNTHETIC_SKEW: f32 = 14.;
+/// Advance of a missing glyph box, as a fraction of the font size.
+const MISSING_GLYPH_ADVANCE: f32 = 0.8;
+
 impl Document {
     pub fn from_str(text: &str) -> Document {
+        Document::from_rope(ropey::Rope::from_str(text))
+    }
+
+    /// Reads a document from `reader`, failing if it isn't valid UTF-8.
+    pub fn from_reader<T: io::Read>(reader: T) -> io::Result<Document> {
+        Ok(Document::from_rope(ropey::Rope::from_reader(reader)?))
+    }
+
+    /// Reads a document from `reader`, replacing invalid UTF-8 sequences
+    /// with U+FFFD so that arbitrary files can still be opened.
+    pub fn from_reader_lossy<T: io::Read>(mut reader: T) -> io::Result<Document> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(Document::from_str(&String::from_utf8_lossy(&bytes)))
+    }
+
+    /// Creates a document around an existing rope, without copying it. The
+    /// line ending is detected from its contents.
+    pub fn from_rope(rope: ropey::Rope) -> Document {
         Document {
-            rope: ropey::Rope::from_str(text),
+            line_ending: LineEnding::detect(&rope),
+            rope,
             layout: Layout::new(),
             is_dirty: true,
+            dirty: None,
+            tab_width: DEFAULT_TAB_WIDTH,
+            scale: 1.,
+            language: None,
+            default_color: color::BLACK,
+            styles: Vec::new(),
+            show_whitespace: false,
+            whitespace_color: DEFAULT_WHITESPACE_COLOR,
+            parallel: false,
+            features: Vec::new(),
+            missing_glyph_style: MissingGlyphStyle::default(),
+            anchor: 0,
+            head: 0,
+            goal_x: None,
         }
     }
 
-    pub fn from_reader<T: io::Read>(mut reader: T) -> io::Result<Document> {
-        let rope = ropey::Rope::from_reader(reader)?;
-        Ok(Document {
-            rope,
-            layout: Layout::new(),
-            is_dirty: true,
-        })
+    /// Sets the distance between tab stops, in columns of the primary
+    /// font's space advance.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        if tab_width != self.tab_width {
+            self.tab_width = tab_width;
+            self.invalidate();
+        }
+    }
+
+    /// Overrides the line ending detected when the document was loaded.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if line_ending != self.line_ending {
+            self.line_ending = line_ending;
+            self.invalidate();
+        }
     }
 
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    /// Sets the scale factor of the display the document is shown on. The
+    /// whole document is reshaped at the new pixel size by the next parse.
+    pub fn set_scale(&mut self, scale: f32) {
+        if scale != self.scale {
+            self.scale = scale;
+            self.invalidate();
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Sets whether lines are shaped in parallel when parsing. The layout
+    /// is the same either way. Has no effect unless the `parallel` feature
+    /// is enabled.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    pub fn parallel(&self) -> bool {
+        self.parallel
+    }
+
+    /// Sets the OpenType features used when shaping, e.g. turning off
+    /// `liga` and `calt` to show code without ligatures. With ligatures
+    /// off, a monospace primary font and no bold or italic spans, lines of
+    /// plain ASCII skip shaping.
+    pub fn set_features(&mut self, features: &[(Tag, u32)]) {
+        if features != self.features.as_slice() {
+            self.features = features.to_vec();
+            self.invalidate();
+        }
+    }
+
+    /// Sets the direction text flows in, which needs the text to be shaped
+    /// again. See [`Layout::set_writing_mode`].
+    pub fn set_writing_mode(&mut self, writing_mode: WritingMode) {
+        if writing_mode != self.layout.writing_mode() {
+            self.layout.set_writing_mode(writing_mode);
+            self.invalidate();
+        }
+    }
+
+    /// Enables hyphenating words when wrapping, see
+    /// [`Layout::set_hyphenation`]. Words are hyphenated as US English, and
+    /// only when built with the `hyphenation` feature.
+    pub fn set_hyphenation(&mut self, hyphenation: bool) {
+        if hyphenation != self.layout.hyphenation() {
+            self.layout.set_hyphenation(hyphenation);
+            self.invalidate();
+        }
+    }
+
+    /// Sets how clusters that none of the fonts passed to
+    /// [`Document::parse`] have glyphs for are drawn. The boxes aren't
+    /// glyphs, they're drawn from [`Layout::missing_glyph_rects`].
+    pub fn set_missing_glyph_style(&mut self, style: MissingGlyphStyle) {
+        if style != self.missing_glyph_style {
+            self.missing_glyph_style = style;
+            self.invalidate();
+        }
+    }
+
+    pub fn missing_glyph_style(&self) -> MissingGlyphStyle {
+        self.missing_glyph_style
+    }
+
+    /// Sets the BCP 47 language tag (e.g. `"ar"`, `"sr-Latn"`) passed to the
+    /// shaper. When unset the language of the current locale is used.
+    pub fn set_language(&mut self, language: Option<&str>) {
+        if language != self.language.as_deref() {
+            self.language = language.map(String::from);
+            self.invalidate();
+        }
+    }
+
+    /// Sets the color of text.
+    pub fn set_default_color(&mut self, color: Color) {
+        if color != self.default_color {
+            self.default_color = color;
+            self.invalidate();
+        }
+    }
+
+    /// Sets whether markers for spaces, tabs and line breaks are drawn.
+    /// Markers don't affect layout, so this doesn't need a new parse.
+    pub fn set_show_whitespace(&mut self, show_whitespace: bool) {
+        self.show_whitespace = show_whitespace;
+    }
+
+    pub fn show_whitespace(&self) -> bool {
+        self.show_whitespace
+    }
+
+    /// Sets the color whitespace markers are drawn with.
+    pub fn set_whitespace_color(&mut self, color: Color) {
+        self.whitespace_color = color;
+    }
+
+    pub fn whitespace_color(&self) -> Color {
+        self.whitespace_color
+    }
+
+    /// Applies `style` to the text in `byte_range`, replacing any style
+    /// previously applied to it.
+    ///
+    /// Fonts with a matching weight and style are preferred for the span
+    /// when parsing, before falling back to the other fonts.
+    pub fn apply_style(&mut self, byte_range: Range<usize>, style: Style) {
+        if byte_range.is_empty() {
+            return;
+        }
+        self.styles.push((byte_range.clone(), style));
+        self.mark_dirty(byte_range, 0);
+    }
+
+    /// Marks the whole document as needing to be parsed again.
+    fn invalidate(&mut self) {
+        self.is_dirty = true;
+        self.dirty = None;
+    }
+
+    /// Replaces the text in `byte_range` with `replacement`.
+    ///
+    /// Only the lines touched by the edit are reshaped by the next call to
+    /// [`Document::parse`]; the layout of other lines is kept and their
+    /// source offsets shifted.
+    pub fn edit(&mut self, byte_range: Range<usize>, replacement: &str) {
+        let start = self.rope.byte_to_char(byte_range.start);
+        let end = self.rope.byte_to_char(byte_range.end);
+        self.rope.remove(start..end);
+        self.rope.insert(start, replacement);
+
+        // text inserted inside a span takes on its style
+        let map = |offset: usize| {
+            if offset <= byte_range.start {
+                offset
+            } else if offset >= byte_range.end {
+                offset - byte_range.len() + replacement.len()
+            } else {
+                byte_range.start + replacement.len()
+            }
+        };
+        for (range, _) in &mut self.styles {
+            *range = map(range.start)..map(range.end);
+        }
+        self.styles.retain(|(range, _)| !range.is_empty());
+        self.anchor = map(self.anchor);
+        self.head = map(self.head);
+        self.goal_x = None;
+
+        let delta = replacement.len() as isize - byte_range.len() as isize;
+        self.mark_dirty(byte_range, delta);
+    }
+
+    /// Inserts `text` at the byte offset `offset`.
+    pub fn insert(&mut self, offset: usize, text: &str) {
+        self.edit(offset..offset, text);
+    }
+
+    /// Removes the text in `byte_range`.
+    pub fn delete(&mut self, byte_range: Range<usize>) {
+        self.edit(byte_range, "");
+    }
+
+    /// Returns the length of the document in bytes.
+    pub fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    /// Returns the length of the document in chars.
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    /// Returns the number of lines in the document, as counted by ropey,
+    /// which breaks lines on all Unicode line endings. A document ending in
+    /// a line break has an empty last line.
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    /// Returns the byte offset of the char at `char_idx`.
+    ///
+    /// Panics if `char_idx` is past the end of the document.
+    pub fn char_to_byte(&self, char_idx: usize) -> usize {
+        self.rope.char_to_byte(char_idx)
+    }
+
+    /// Returns the index of the char containing the byte at `byte_idx`.
+    ///
+    /// Panics if `byte_idx` is past the end of the document.
+    pub fn byte_to_char(&self, byte_idx: usize) -> usize {
+        self.rope.byte_to_char(byte_idx)
+    }
+
+    /// Returns the text of the document.
+    pub fn text(&self) -> ropey::RopeSlice<'_> {
+        self.rope.slice(..)
+    }
+
+    pub fn rope(&self) -> &ropey::Rope {
+        &self.rope
+    }
+
+    /// Returns the rope for editing it in place. Since the edits aren't
+    /// known, the whole document is parsed again by the next
+    /// [`Document::parse`]; prefer [`Document::edit`] for small changes.
+    /// Style ranges aren't moved to follow the edits.
+    pub fn rope_mut(&mut self) -> &mut ropey::Rope {
+        self.invalidate();
+        &mut self.rope
+    }
+
+    /// Returns the cursor position before the byte offset `offset`, stepping
+    /// over whole clusters and `\r\n` pairs.
+    pub fn prev_cursor_position(&self, offset: usize) -> usize {
+        let mut char_idx = self.rope.byte_to_char(offset);
+        while char_idx > 0 {
+            char_idx -= 1;
+            let offset = self.rope.char_to_byte(char_idx);
+            if self.is_cursor_position(offset) {
+                return offset;
+            }
+        }
+        0
+    }
+
+    /// Returns the cursor position after the byte offset `offset`, stepping
+    /// over whole clusters and `\r\n` pairs.
+    pub fn next_cursor_position(&self, offset: usize) -> usize {
+        let mut char_idx = self.rope.byte_to_char(offset);
+        while char_idx < self.rope.len_chars() {
+            char_idx += 1;
+            let offset = self.rope.char_to_byte(char_idx);
+            if self.is_cursor_position(offset) {
+                return offset;
+            }
+        }
+        self.rope.len_bytes()
+    }
+
+    /// Returns the grapheme boundary after the byte offset `offset`. Unlike
+    /// [`Document::next_cursor_position`] this steps into ligatures, and
+    /// doesn't need the document to be parsed. A line break, including
+    /// `\r\n`, is a single grapheme.
+    pub fn next_grapheme_boundary(&self, offset: usize) -> usize {
+        if offset >= self.rope.len_bytes() {
+            return self.rope.len_bytes();
+        }
+        let line = self.rope.byte_to_line(offset);
+        self.grapheme_boundaries(line)
+            .into_iter()
+            .find(|&boundary| boundary > offset)
+            .unwrap_or(self.rope.len_bytes())
+    }
+
+    /// Returns the grapheme boundary before the byte offset `offset`, see
+    /// [`Document::next_grapheme_boundary`].
+    pub fn prev_grapheme_boundary(&self, offset: usize) -> usize {
+        if offset == 0 {
+            return 0;
+        }
+        let offset = offset.min(self.rope.len_bytes());
+        let line = self.rope.byte_to_line(offset - 1);
+        self.grapheme_boundaries(line)
+            .into_iter()
+            .rev()
+            .find(|&boundary| boundary < offset)
+            .unwrap_or(0)
+    }
+
+    /// Selects the text between the byte offsets `anchor` and `head`, with
+    /// the cursor at `head`. Offsets past the end of the document are
+    /// clamped.
+    ///
+    /// Edits move the selection along with the text around it, so text
+    /// inserted at the cursor ends up after it.
+    pub fn set_selection(&mut self, anchor: usize, head: usize) {
+        let len = self.rope.len_bytes();
+        self.anchor = anchor.min(len);
+        self.head = head.min(len);
+        self.goal_x = None;
+    }
+
+    /// Returns the selected byte range, which is empty if nothing is
+    /// selected.
+    pub fn selection(&self) -> Range<usize> {
+        let (anchor, head) = (self.anchor(), self.cursor());
+        anchor.min(head)..anchor.max(head)
+    }
+
+    /// Returns the byte offset of the end of the selection that stays put
+    /// when it's extended.
+    pub fn anchor(&self) -> usize {
+        // the rope may have shrunk through `rope_mut`
+        self.anchor.min(self.rope.len_bytes())
+    }
+
+    /// Returns the byte offset of the cursor.
+    pub fn cursor(&self) -> usize {
+        self.head.min(self.rope.len_bytes())
+    }
+
+    /// Moves the cursor to the previous cursor position in logical order,
+    /// or to the start of the selection if there is one.
+    pub fn move_left(&mut self) {
+        let selection = self.selection();
+        let offset = if selection.is_empty() {
+            self.prev_cursor_position(selection.start)
+        } else {
+            selection.start
+        };
+        self.set_selection(offset, offset);
+    }
+
+    /// Moves the cursor to the next cursor position in logical order, or to
+    /// the end of the selection if there is one.
+    pub fn move_right(&mut self) {
+        let selection = self.selection();
+        let offset = if selection.is_empty() {
+            self.next_cursor_position(selection.end)
+        } else {
+            selection.end
+        };
+        self.set_selection(offset, offset);
+    }
+
+    /// Moves the cursor to the nearest position on the visual line above,
+    /// or to the start of the document from the first line. Consecutive
+    /// vertical moves keep the horizontal position they started at. Only
+    /// valid after [`Layout::finish`].
+    pub fn move_up(&mut self) {
+        self.move_vertically(false, false);
+    }
+
+    /// Moves the cursor to the nearest position on the visual line below,
+    /// or to the end of the document from the last line, see
+    /// [`Document::move_up`].
+    pub fn move_down(&mut self) {
+        self.move_vertically(true, false);
+    }
+
+    /// Extends the selection to the previous cursor position.
+    pub fn extend_left(&mut self) {
+        let offset = self.prev_cursor_position(self.cursor());
+        self.set_selection(self.anchor(), offset);
+    }
+
+    /// Extends the selection to the next cursor position.
+    pub fn extend_right(&mut self) {
+        let offset = self.next_cursor_position(self.cursor());
+        self.set_selection(self.anchor(), offset);
+    }
+
+    /// Extends the selection to the visual line above, see
+    /// [`Document::move_up`].
+    pub fn extend_up(&mut self) {
+        self.move_vertically(false, true);
+    }
+
+    /// Extends the selection to the visual line below, see
+    /// [`Document::move_down`].
+    pub fn extend_down(&mut self) {
+        self.move_vertically(true, true);
+    }
+
+    fn move_vertically(&mut self, down: bool, extend: bool) {
+        let Some(rect) = self.layout.cursor_rect(self.cursor()) else {
+            return;
+        };
+        let goal_x = self.goal_x.unwrap_or(rect.x);
+        let offset = self
+            .vertical_target(rect, goal_x, down)
+            .unwrap_or(if down { self.rope.len_bytes() } else { 0 });
+        let anchor = if extend { self.anchor() } else { offset };
+        self.set_selection(anchor, offset);
+        self.goal_x = Some(goal_x);
+    }
+
+    /// Returns the cursor position at `x` on the line above or below the
+    /// one holding the caret `rect`, or `None` if there is no such line.
+    fn vertical_target(&self, rect: Rect, x: f32, down: bool) -> Option<usize> {
+        let layout = &self.layout;
+        let hit = layout.hit_test(rect.x, rect.y + rect.height * 0.5)?;
+        let line = if down {
+            hit.line + 1
+        } else {
+            hit.line.checked_sub(1)?
+        };
+        let target = layout.lines.get(line)?;
+        let top = layout.lines[..line]
+            .iter()
+            .map(|line| line.above + line.below)
+            .sum::<f32>();
+        let hit = layout.hit_test(x, top + (target.above + target.below) * 0.5)?;
+        Some(layout.hit_offset(&hit))
+    }
+
+    /// Returns the grapheme boundaries of the line `line_idx`, from its
+    /// start to the end of its line break.
+    fn grapheme_boundaries(&self, line_idx: usize) -> Vec<usize> {
+        let start = self.rope.line_to_byte(line_idx);
+        let line = self.rope.line(line_idx);
+        let text = line_text(line, true);
+        let mut boundaries = vec![start];
+        boundaries.extend(grapheme_ends(&text).into_iter().map(|end| start + end));
+        boundaries.push(start + line.len_bytes());
+        boundaries
+    }
+
+    /// Returns false for offsets inside a cluster or between `\r` and `\n`.
+    /// Clusters are only known for the parts of the document that have
+    /// been parsed since the last edit.
+    fn is_cursor_position(&self, offset: usize) -> bool {
+        if offset > 0
+            && offset < self.rope.len_bytes()
+            && self.rope.byte(offset - 1) == b'\r'
+            && self.rope.byte(offset) == b'\n'
+        {
+            return false;
+        }
+        self.layout
+            .cluster_range(offset)
+            .map_or(true, |range| range.start == offset)
+    }
+
+    /// Marks the lines touched by `byte_range`, which has just changed
+    /// length by `delta` bytes, to be reshaped by the next parse.
+    fn mark_dirty(&mut self, byte_range: Range<usize>, delta: isize) {
+        if self.is_dirty && self.dirty.is_none() {
+            // a full parse is already pending
+            return;
+        }
+        let mut lines = self.layout.paragraphs_touching(byte_range.clone());
+        let mut region = byte_range;
+        match self.dirty.take() {
+            Some((index, dirty)) => {
+                lines = lines.start.min(index)..lines.end.max(index);
+                region = region.start.min(dirty.start)..region.end.max(dirty.end);
+            }
+            None if lines.is_empty() => {
+                // nothing to anchor the edit to
+                self.invalidate();
+                return;
+            }
+            None => {}
+        }
+        // the empty line after a final line break is only produced when
+        // parsing up to the end of the document, so it has to go too
+        if lines.end + 1 == self.layout.paragraph_count()
+            && self.layout.paragraph_range(lines.end).map_or(false, |r| r.is_empty())
+        {
+            lines.end += 1;
+        }
+        if let Some(removed) = self.layout.remove_paragraphs(lines.clone(), delta) {
+            region = region.start.min(removed.start)..region.end.max(removed.end);
+        }
+        let end = (region.end as isize + delta) as usize;
+        self.dirty = Some((lines.start, region.start..end));
+        self.is_dirty = true;
+    }
+
+    /// Shapes the text changed since the last parse into
+    /// [`Document::layout`]. Fonts are tried in order for each cluster.
+    /// `size` is in logical pixels and multiplied by the scale set with
+    /// [`Document::set_scale`].
+    ///
+    /// On error the whole document is parsed again on the next call.
     pub fn parse(
         &mut self,
         fonts: &[&Font],
         size: f32,
-    ) {
+    ) -> Result<(), ParseError> {
         if !self.is_dirty {
             // no need to do this again!
-            return;
+            return Ok(());
         }
-        self.layout.reset();
+        let size = size * self.scale;
+        let result = match self.dirty.take() {
+            Some((index, range)) if range.len() < self.rope.len_bytes() => {
+                let mut layout = Layout::new();
+                layout.set_writing_mode(self.layout.writing_mode());
+                layout.set_hyphenation(self.layout.hyphenation());
+                let result = parse_lines(&self.rope, range, &mut layout, fonts, size, self.tab_width, self.language.as_deref(), self.default_color, &self.styles, self.line_ending, &self.features, self.missing_glyph_style, self.parallel);
+                if result.is_ok() {
+                    self.layout.insert_paragraphs(index, layout);
+                }
+                result
+            }
+            _ => {
+                self.layout.reset();
+                let range = 0..self.rope.len_bytes();
+                parse_lines(&self.rope, range, &mut self.layout, fonts, size, self.tab_width, self.language.as_deref(), self.default_color, &self.styles, self.line_ending, &self.features, self.missing_glyph_style, self.parallel)
+            }
+        };
+        match result {
+            Ok(()) => self.is_dirty = false,
+            Err(_) => self.invalidate(),
+        }
+        result
+    }
+}
 
-        let mut shapers = fonts.iter().copied().map(ShapeContext::new).collect::<Vec<_>>();
-        let mut cluster = CharCluster::new();
-        let mut line_no = 1;
-        for line in self.rope.lines() {
-            // TODO: this should be par_iter()-able, but probably needs thread_local!
-            // variables for ALL the things
-            for shaper in shapers.iter_mut() {
-                shaper.reset();
-            }
-            let mut doc_indices = Vec::with_capacity(line.len_chars());
-            // TODO: things are a bit messy now with respect to \r\r\r\n combinations
-            // we're purposly ignoring \r, but ropey splits lines for each extra
-            // \r in an \r\r\r(etc)\n block, which is arguably the right thing to do
-            // but not how emacs does it
-            let has_linebreak = line.char(line.len_chars() - 1) == '\n';
-            let mut parser = Parser::new(
-                Script::Latin,
-                line.chars().filter(|&c| c != '\r' && c != '\n').map({
-                    let mut offset = 0usize;
-                    move |ch| {
-                        let len = ch.len_utf8();
-                        let current_offset = offset as u32;
-                        offset += len;
-                        Token {
-                            ch,
-                            offset: current_offset,
-                            len: len as u8,
-                            info: ch.into(),
-                            data: 0,
-                        }
-                    }
-                }),
-            );
-            while parser.next(&mut cluster) {
-                let SourceRange { start: i, end: j } = cluster.range();
-                doc_indices.push((line_no, i as usize, j as usize));
-                for shaper in shapers.iter_mut() {
-                    shaper.add_cluster(&cluster);
+/// Shapes the lines of `rope` in `range` into `layout`, numbering paragraphs
+/// from zero. `range` must start at the beginning of a line and end after a
+/// line break or at the end of the rope.
+#[allow(clippy::too_many_arguments)]
+fn parse_lines(
+    rope: &ropey::Rope,
+    range: Range<usize>,
+    layout: &mut Layout,
+    fonts: &[&Font],
+    size: f32,
+    tab_width: usize,
+    language: Option<&str>,
+    color: Color,
+    styles: &[(Range<usize>, Style)],
+    line_ending: LineEnding,
+    features: &[(Tag, u32)],
+    missing_glyph_style: MissingGlyphStyle,
+    parallel: bool,
+) -> Result<(), ParseError> {
+    if fonts.is_empty() {
+        return Err(ParseError::NoFonts);
+    }
+    let primary = fonts[0].fontref();
+    let space_id = primary.charmap().map(' ');
+    let space_advance = primary.glyph_metrics(&[]).scale(size).advance_width(space_id);
+    let tab_stop = space_advance * tab_width as f32;
+    let line_metrics = fonts[0].metrics().scale(size);
+    let mut line_no = 0;
+    let mut line_offset = range.start;
+    let mut paragraph_start = range.start;
+    let lines = line_ranges(rope, range.clone(), line_ending);
+    let vertical = layout.writing_mode() == WritingMode::VerticalRl;
+    let skip_ascii = !vertical && can_skip_shaping(fonts, styles, features);
+    let shaped = shape_lines(rope, &lines, fonts, size, language, features, vertical, skip_ascii, parallel);
+    for ((line_range, has_linebreak), shaped) in lines.into_iter().zip(shaped) {
+        let line = rope.byte_slice(line_range);
+        let (doc_indices, shapes) = shaped?;
+        let hyphen_points = if layout.hyphenation() {
+            hyphenation_points(&line.to_string(), line_offset)
+        } else {
+            Vec::new()
+        };
+        let can_hyphenate = |offset: usize| hyphen_points.binary_search(&offset).is_ok();
+        let mut prev_font_index = 0;
+        let mut prev_style = doc_indices.first().map_or_else(Style::default, |idx| style_at(styles, idx.range.start));
+        let mut prev_level = doc_indices.first().map_or(0, |idx| idx.level);
+        let mut glyphs: Vec<Glyph> = Vec::with_capacity(1);
+        let mut clusters: Vec<Cluster> = Vec::with_capacity(1);
+        let mut prev_range_start = line_offset;
+        let mut prev_range_end = line_offset;
+        let mut line_x = 0.;
+        for (i, idx) in doc_indices.iter().enumerate() {
+            let style = style_at(styles, idx.range.start);
+            let (font_index, cluster, is_invisible) = choose_font(fonts, &shapes, i, &style)?;
+            let is_missing = !idx.is_control && !is_invisible && !cluster.is_empty() && cluster.iter().all(|g| g.id == 0);
+            let missing_box = is_missing
+                && matches!(missing_glyph_style, MissingGlyphStyle::HexBox | MissingGlyphStyle::SolidBox);
+            // tabs are expanded to the next tab stop using the primary font's space glyph
+            let tab_glyph;
+            let control_glyph;
+            let box_glyph;
+            let (font_index, cluster) = if idx.is_tab && tab_stop > 0. {
+                let next_stop = ((line_x / tab_stop).floor() + 1.) * tab_stop;
+                tab_glyph = Glyph {
+                    id: space_id,
+                    advance: next_stop - line_x,
+                    ..Default::default()
+                };
+                (0, std::slice::from_ref(&tab_glyph))
+            } else if idx.is_control {
+                // an empty space glyph, so the cursor can still stop on
+                // either side of it
+                control_glyph = Glyph {
+                    id: space_id,
+                    ..Default::default()
+                };
+                (0, std::slice::from_ref(&control_glyph))
+            } else if is_invisible || (is_missing && missing_glyph_style == MissingGlyphStyle::Hidden) {
+                // e.g. a variation selector split from its base, which would
+                // otherwise leave an empty .notdef glyph behind
+                (prev_font_index, &[][..])
+            } else if missing_box {
+                // the box is drawn separately, over the space it leaves
+                box_glyph = Glyph {
+                    id: space_id,
+                    advance: size * MISSING_GLYPH_ADVANCE,
+                    ..Default::default()
+                };
+                (0, std::slice::from_ref(&box_glyph))
+            } else {
+                (font_index, cluster)
+            };
+            if font_index != prev_font_index || idx.level != prev_level || style != prev_style {
+                if !glyphs.is_empty() {
+                    let run_color = prev_style.color.unwrap_or(color);
+                    let run_size = size * prev_style.size;
+                    let synthesis = synthesis_for(fonts[prev_font_index], &prev_style, run_size);
+                    layout.push_run(line_no, fonts[prev_font_index], prev_range_start..prev_range_end, glyphs, clusters, run_size, prev_level, run_color, synthesis, prev_style.decoration, prev_style.letter_spacing * run_size);
+                    glyphs = Vec::with_capacity(1);
+                    clusters = Vec::with_capacity(1);
                 }
+                prev_font_index = font_index;
+                prev_level = idx.level;
+                prev_style = style;
+                prev_range_start = idx.range.start;
             }
-            let shapes = shapers.iter_mut().map(|s| s.shape()).collect::<Vec<_>>();
-            let mut prev_font_index = 0;
-            let mut glyphs: Vec<Glyph> = Vec::with_capacity(1);
-            let mut prev_range_start = 0;
-            let mut prev_range_end = 0;
-            for (i, idx) in doc_indices.iter().enumerate() {
-                println!("cluster: {:?} ", line.get_byte_slice(idx.1..idx.2));
-                let mut best = None;
-                for (font_index, shape) in shapes.iter().enumerate() {
-                    let cluster = shape.get(i).unwrap();
-                    let num_complete = cluster.iter().filter(|g| g.id != 0).count();
-                    println!("    {} num_complete={} len={}", font_index, num_complete, cluster.len());
-                    let ratio = num_complete as f32 / cluster.len() as f32;
-                    let len = cluster.len();
-                    // if num_complete == cluster.len() {
-                    //     best = Some((font_index, cluster, num_complete));
-                    //     break;
-                    // } else
-                    if let &Some((_, _, prev_ratio, prev_len)) = &best {
-                        if prev_ratio < ratio || (prev_ratio == ratio && prev_len > len) {
-                            best = Some((font_index, cluster, ratio, len));
-                        }
-                    } else {
-                        best = Some((font_index, cluster, ratio, len));
-                    }
+
+            prev_range_end = idx.range.end;
+            if cluster.is_empty() {
+                // merged into the preceding cluster, so the cursor can't land
+                // between them, or dropped at the start of a run
+                if let Some(last) = clusters.last_mut() {
+                    last.range.end = idx.range.end;
+                    last.hyphen_after = can_hyphenate(idx.range.end);
                 }
-                println!("    BEST = {:?}", best);
-                let Some((font_index, cluster, _, _)) = best else { panic!("should be imposible if we have fonts") };
-                if font_index != prev_font_index {
-                    if !glyphs.is_empty() {
-                        self.layout.push_run(line_no, prev_font_index, prev_range_start..prev_range_end, glyphs, size, fonts[prev_font_index].metrics);
-                        glyphs = Vec::with_capacity(1);
+                continue;
+            }
+            clusters.push(Cluster {
+                range: idx.range.clone(),
+                glyphs: glyphs.len()..(glyphs.len() + cluster.len()),
+                is_whitespace: idx.is_whitespace,
+                is_tab: idx.is_tab,
+                hyphen_after: can_hyphenate(idx.range.end),
+                missing: if missing_box {
+                    rope.byte_slice(idx.range.clone()).chars().next()
+                } else {
+                    None
+                },
+            });
+            // shaping is done at the document's size, sized spans are scaled
+            let scale = if idx.is_tab { 1. } else { style.size };
+            let start = glyphs.len();
+            glyphs.extend(cluster.iter().map(|g| Glyph {
+                x: g.x * scale,
+                y: g.y * scale,
+                advance: g.advance * scale,
+                ..*g
+            }));
+            // spacing follows every cluster, the layout takes it off the
+            // last one of each line. tabs are left alone to keep tab stops
+            if !idx.is_tab && !idx.is_control {
+                glyphs[glyphs.len() - 1].advance += style.letter_spacing * size * style.size;
+            }
+            line_x += glyphs[start..].iter().map(|g| g.advance).sum::<f32>();
+        }
+
+        if !glyphs.is_empty() {
+            let run_color = prev_style.color.unwrap_or(color);
+            let run_size = size * prev_style.size;
+            let synthesis = synthesis_for(fonts[prev_font_index], &prev_style, run_size);
+            layout.push_run(line_no, fonts[prev_font_index], prev_range_start..prev_range_end, glyphs, clusters, run_size, prev_level, run_color, synthesis, prev_style.decoration, prev_style.letter_spacing * run_size);
+        }
+        line_offset += line.len_bytes();
+        if has_linebreak {
+            // the line break ends on the last byte of the line
+            layout.end_paragraph(line_no, paragraph_start..line_offset, Some(line_offset - 1), line_metrics);
+            paragraph_start = line_offset;
+            line_no += 1;
+        }
+    }
+    // the final line has no line break, and is only empty if it's the
+    // last line of the document, which for an empty document is its only
+    // line
+    if paragraph_start < line_offset || line_offset == rope.len_bytes() {
+        layout.end_paragraph(line_no, paragraph_start..line_offset, None, line_metrics);
+    }
+    Ok(())
+}
+
+/// Clusters and glyphs of a line, as returned by [`shape_line`].
+type ShapedLine = (Vec<SourceCluster>, Vec<Vec<Vec<Glyph>>>);
+
+/// Shapes each of `lines` of `rope` with each of `fonts`, returning the
+/// results in the same order as `lines`. With `skip_ascii` set, lines of
+/// plain ASCII are mapped straight to the primary font's glyphs by
+/// [`map_ascii_line`] instead. With `parallel` set and the
+/// `parallel` feature enabled the lines are shaped on rayon's thread pool,
+/// each thread using its own shapers since harfbuzz buffers can't be shared.
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(not(feature = "parallel"), allow(unused_variables))]
+fn shape_lines(
+    rope: &ropey::Rope,
+    lines: &[(Range<usize>, bool)],
+    fonts: &[&Font],
+    size: f32,
+    language: Option<&str>,
+    features: &[(Tag, u32)],
+    vertical: bool,
+    skip_ascii: bool,
+    parallel: bool,
+) -> Vec<Result<ShapedLine, ParseError>> {
+    let shape = |(shapers, cluster): &mut (Vec<ShapeContext>, CharCluster), (line_range, has_linebreak): &(Range<usize>, bool)| {
+        let line = rope.byte_slice(line_range.clone());
+        let text = line_text(line, *has_linebreak);
+        if skip_ascii {
+            if let Some(shaped) = map_ascii_line(&text, line_range.start, fonts, size) {
+                return Ok(shaped);
+            }
+        }
+        let mut clusters = Vec::with_capacity(line.len_chars());
+        let shapes = shape_line(&text, line_range.start, shapers, cluster, language, &mut clusters)?;
+        Ok((clusters, shapes))
+    };
+    let init = || {
+        let shapers = fonts
+            .iter()
+            .map(|font| {
+                let mut shaper = ShapeContext::new(font, size);
+                shaper.set_features(features);
+                shaper.set_vertical(vertical);
+                shaper
+            })
+            .collect::<Vec<_>>();
+        (shapers, CharCluster::new())
+    };
+    #[cfg(feature = "parallel")]
+    if parallel {
+        use rayon::prelude::*;
+        return lines
+            .par_iter()
+            .map_init(init, &shape)
+            .collect();
+    }
+    let mut state = init();
+    lines.iter().map(|line| shape(&mut state, line)).collect()
+}
+
+/// Measures `text` as it would be laid out by [`Document::parse`] with
+/// `fonts` at `size`, without wrapping, and without building runs. Returns
+/// the advance of the widest line and the total height of the lines. Every
+/// `\n` starts a new line, so an empty string is one line high.
+pub fn measure(text: &str, fonts: &[&Font], size: f32) -> Result<(f32, f32), ParseError> {
+    if fonts.is_empty() {
+        return Err(ParseError::NoFonts);
+    }
+    let mut shapers = fonts.iter().map(|font| ShapeContext::new(font, size)).collect::<Vec<_>>();
+    let primary = fonts[0].fontref();
+    let space_id = primary.charmap().map(' ');
+    let space_advance = primary.glyph_metrics(&[]).scale(size).advance_width(space_id);
+    let tab_stop = space_advance * DEFAULT_TAB_WIDTH as f32;
+    let style = Style::default();
+    let mut cluster = CharCluster::new();
+    let mut width = 0f32;
+    let mut height = 0.;
+    for line in text.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let mut clusters = Vec::new();
+        let shapes = shape_line(line, 0, &mut shapers, &mut cluster, None, &mut clusters)?;
+        let mut x = 0.;
+        let mut metrics = Vec::new();
+        for (i, source) in clusters.iter().enumerate() {
+            let (font_index, glyphs, is_invisible) = choose_font(fonts, &shapes, i, &style)?;
+            if source.is_tab && tab_stop > 0. {
+                x = ((x / tab_stop).floor() + 1.) * tab_stop;
+                metrics.push(fonts[0].metrics().scale(size));
+            } else if !is_invisible && !source.is_control {
+                x += glyphs.iter().map(|g| g.advance).sum::<f32>();
+                metrics.push(fonts[font_index].metrics().scale(size));
+            }
+        }
+        if metrics.is_empty() {
+            // empty lines still take up a line of the primary font
+            metrics.push(fonts[0].metrics().scale(size));
+        }
+        let mut line = Line::default();
+        line.set_metrics(metrics);
+        width = width.max(x);
+        height += line.above + line.below;
+    }
+    Ok((width, height))
+}
+
+/// Writes the finished layout of `document` as an SVG image, with the top
+/// left of the layout at `origin`. The fonts of the runs are looked up in
+/// `source`, which must be the source they were loaded from.
+///
+/// Each distinct glyph is defined once, as a path built from its outline at
+/// the run's size or as an embedded PNG for color bitmap glyphs, and placed
+/// with `<use>`. Glyphs with neither, e.g. spaces, are left out.
+pub fn export_svg(document: &Document, source: &FontSource, origin: (f32, f32)) -> String {
+    let layout = &document.layout;
+    let (width, height) = layout.total_size();
+    let mut context = ScaleContext::new();
+    // glyph defs by font, size, coords, synthesis and glyph id, `None` if
+    // there's nothing to draw
+    let mut ids: HashMap<(FontId, u32, &[i16], u32, u32, GlyphId), Option<usize>> = HashMap::new();
+    let mut defs = String::new();
+    let mut def_count = 0;
+    let mut body = String::new();
+    for g in layout.positioned_glyphs(origin) {
+        let key = (g.font_id, g.size.to_bits(), g.coords, g.synthesis.embolden.to_bits(), g.synthesis.skew.to_bits(), g.id);
+        let id = *ids.entry(key).or_insert_with(|| {
+            let mut scaler = context
+                .builder(source.get_fontref(g.font_id))
+                .size(g.size)
+                .normalized_coords(g.coords)
+                .build();
+            let def = Render::new(&[Source::ColorBitmap(StrikeWith::BestFit)])
+                .render(&mut scaler, g.id)
+                .filter(|image| image.content == Content::Color)
+                .and_then(|image| svg_image(&image.data, image.placement))
+                .or_else(|| {
+                    let mut outline = scaler.scale_outline(g.id)?;
+                    let Synthesis { embolden, skew } = g.synthesis;
+                    if embolden != 0. {
+                        outline.embolden(embolden, embolden);
                     }
-                    prev_font_index = font_index;
-                    prev_range_start = idx.1;
-                }
+                    if skew != 0. {
+                        outline.transform(&Transform::skew(Angle::from_degrees(skew), Angle::ZERO));
+                    }
+                    svg_path(&outline.path())
+                })?;
+            let id = def_count;
+            def_count += 1;
+            let _ = writeln!(defs, r#"<g id="g{}">{}</g>"#, id, def);
+            Some(id)
+        });
+        let Some(id) = id else { continue };
+        let color = g.color;
+        let _ = write!(body, r##"<use href="#g{}" x="{}" y="{}" fill="#{:02x}{:02x}{:02x}""##, id, g.x, g.y, color.r, color.g, color.b);
+        if color.a != 255 {
+            let _ = write!(body, r#" fill-opacity="{}""#, color.a as f32 / 255.);
+        }
+        body.push_str("/>\n");
+    }
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n<defs>\n{}</defs>\n{}</svg>\n",
+        width + origin.0 * 2.,
+        height + origin.1 * 2.,
+        defs,
+        body,
+    )
+}
+
+/// Returns SVG path data for a glyph outline, relative to the glyph origin.
+/// Outlines are y-up, so they're flipped to the SVG coordinate system.
+fn svg_path(path: &impl PathData) -> Option<String> {
+    let mut data = String::new();
+    for command in path.commands() {
+        let _ = match command {
+            Command::MoveTo(p) => write!(data, "M{} {}", p.x, -p.y),
+            Command::LineTo(p) => write!(data, "L{} {}", p.x, -p.y),
+            Command::CurveTo(a, b, p) => {
+                write!(data, "C{} {} {} {} {} {}", a.x, -a.y, b.x, -b.y, p.x, -p.y)
+            }
+            Command::QuadTo(a, p) => write!(data, "Q{} {} {} {}", a.x, -a.y, p.x, -p.y),
+            Command::Close => write!(data, "Z"),
+        };
+    }
+    if data.is_empty() {
+        return None;
+    }
+    Some(format!(r#"<path d="{}"/>"#, data))
+}
+
+/// Returns an SVG image embedding RGBA glyph data as a PNG, relative to the
+/// glyph origin.
+fn svg_image(data: &[u8], placement: swash::zeno::Placement) -> Option<String> {
+    use image::ImageEncoder;
+
+    if placement.width == 0 || placement.height == 0 {
+        return None;
+    }
+    let mut png = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut png)
+        .write_image(data, placement.width, placement.height, image::ColorType::Rgba8)
+        .map_err(|e| log::error!("Failed to encode glyph image: {e}"))
+        .ok()?;
+    Some(format!(
+        r#"<image x="{}" y="{}" width="{}" height="{}" href="data:image/png;base64,{}"/>"#,
+        placement.left,
+        -placement.top,
+        placement.width,
+        placement.height,
+        base64(&png),
+    ))
+}
+
+/// Encodes `data` as standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Splits `text`, a line without its line break, into clusters and shapes
+/// them with each of `shapers`. The clusters are appended to `clusters`,
+/// with source ranges starting at `offset`; the glyphs of each cluster are
+/// returned for each shaper.
+fn shape_line(
+    text: &str,
+    offset: usize,
+    shapers: &mut [ShapeContext],
+    cluster: &mut CharCluster,
+    language: Option<&str>,
+    clusters: &mut Vec<SourceCluster>,
+) -> Result<Vec<Vec<Vec<Glyph>>>, ParseError> {
+    let bidi = BidiInfo::new(text, None);
+    let tokens = tokens(text);
+    let mut shapes = vec![Vec::new(); shapers.len()];
+    for (script, level, tokens) in shaping_runs(&tokens, &bidi.levels) {
+        let direction = if level.is_rtl() {
+            Direction::RightToLeft
+        } else {
+            Direction::LeftToRight
+        };
+        for shaper in shapers.iter_mut() {
+            shaper.reset();
+            shaper.set_segment_properties(script, language, direction);
+        }
+        let mut parser = Parser::new(script, tokens.iter().copied());
+        while parser.next(cluster) {
+            let SourceRange { start: i, end: j } = cluster.range();
+            clusters.push(SourceCluster {
+                range: (offset + i as usize)..(offset + j as usize),
+                is_whitespace: cluster.info().is_whitespace(),
+                is_tab: cluster.chars().iter().any(|c| c.ch == '\t'),
+                is_control: cluster.chars().iter().any(|c| c.ch.is_control() && c.ch != '\t'),
+                level: level.number(),
+            });
+            for shaper in shapers.iter_mut() {
+                shaper.add_cluster(cluster);
+            }
+        }
+        for (font_index, (shape, shaper)) in shapes.iter_mut().zip(shapers.iter_mut()).enumerate() {
+            shape.extend(shaper.shape().ok_or(ParseError::ClusterOutOfRange { font_index })?);
+        }
+    }
+    Ok(shapes)
+}
+
+/// Returns true if lines of plain ASCII can be mapped to glyphs one char at
+/// a time, without shaping, and come out the same: the primary font is
+/// monospace, `features` turn off the ligatures harfbuzz applies by
+/// default, and nothing would pick another font for covered text, i.e.
+/// the primary font and all `styles` have the default weight and style.
+fn can_skip_shaping(fonts: &[&Font], styles: &[(Range<usize>, Style)], features: &[(Tag, u32)]) -> bool {
+    let default = Style::default();
+    let is_default = |weight: FontWeight, style: FontStyle| weight == default.weight && style == default.style;
+    let Some(primary) = fonts.first() else {
+        return false;
+    };
+    let properties = primary.properties();
+    let ligatures_off = [b"liga", b"clig", b"calt"].iter().all(|tag| {
+        let tag = swash::tag_from_bytes(tag);
+        // later values of a feature override earlier ones
+        features.iter().rev().find(|(t, _)| *t == tag).map_or(false, |&(_, value)| value == 0)
+    });
+    primary.metrics().is_monospace
+        && ligatures_off
+        && is_default(properties.weight, properties.style)
+        && styles.iter().all(|(_, style)| is_default(style.weight, style.style))
+}
+
+/// Maps `text`, a line without its line break, to the glyphs of the
+/// primary font in `fonts` one char per cluster, with the same result as
+/// [`shape_line`] when [`can_skip_shaping`] holds. The other fonts get no
+/// glyphs, since the primary font covers every cluster. Returns `None` if
+/// the line isn't all printable ASCII, spaces and tabs, or the font lacks a
+/// glyph for any of it, in which case it has to be shaped.
+fn map_ascii_line(text: &str, offset: usize, fonts: &[&Font], size: f32) -> Option<ShapedLine> {
+    let font = fonts.first()?;
+    let fontref = font.fontref();
+    let charmap = fontref.charmap();
+    let glyph_metrics = fontref.glyph_metrics(font.coords()).scale(size);
+    let mut clusters = Vec::with_capacity(text.len());
+    let mut glyphs = Vec::with_capacity(text.len());
+    for (i, ch) in text.char_indices() {
+        let is_whitespace = ch == ' ' || ch == '\t';
+        if !is_whitespace && !ch.is_ascii_graphic() {
+            return None;
+        }
+        // tabs are replaced by the space glyph when building runs, so they
+        // don't need a glyph of their own
+        let id = charmap.map(if ch == '\t' { ' ' } else { ch });
+        if id == 0 {
+            return None;
+        }
+        clusters.push(SourceCluster {
+            range: (offset + i)..(offset + i + 1),
+            is_whitespace,
+            is_tab: ch == '\t',
+            is_control: false,
+            level: 0,
+        });
+        glyphs.push(vec![Glyph {
+            id,
+            advance: glyph_metrics.advance_width(id),
+            ..Default::default()
+        }]);
+    }
+    let mut shapes = vec![vec![Vec::new(); clusters.len()]; fonts.len()];
+    shapes[0] = glyphs;
+    Some((clusters, shapes))
+}
+
+/// Returns the byte offsets in `text`, plus `offset`, that words may be
+/// hyphenated at, in order. Always empty without the `hyphenation` feature.
+#[cfg(feature = "hyphenation")]
+fn hyphenation_points(text: &str, offset: usize) -> Vec<usize> {
+    use hyphenation::{Hyphenator, Language, Load, Standard};
+    use std::sync::OnceLock;
+
+    static DICTIONARY: OnceLock<Option<Standard>> = OnceLock::new();
+    let dictionary = DICTIONARY.get_or_init(|| {
+        Standard::from_embedded(Language::EnglishUS)
+            .map_err(|e| log::error!("Failed to load hyphenation dictionary: {e}"))
+            .ok()
+    });
+    let Some(dictionary) = dictionary else {
+        return Vec::new();
+    };
+    let mut points = Vec::new();
+    let mut words = text.char_indices().peekable();
+    while let Some((start, ch)) = words.next() {
+        if !ch.is_alphabetic() {
+            continue;
+        }
+        let mut end = start + ch.len_utf8();
+        while let Some(&(i, ch)) = words.peek() {
+            if !ch.is_alphabetic() {
+                break;
+            }
+            end = i + ch.len_utf8();
+            words.next();
+        }
+        let word = &text[start..end];
+        points.extend(dictionary.hyphenate(word).breaks.into_iter().map(|i| offset + start + i));
+    }
+    points
+}
+
+#[cfg(not(feature = "hyphenation"))]
+fn hyphenation_points(_text: &str, _offset: usize) -> Vec<usize> {
+    Vec::new()
+}
+
+/// Returns the tokens of `text` for the cluster parser, with byte offsets.
+fn tokens(text: &str) -> Vec<Token> {
+    text.chars().scan(0usize, |offset, ch| {
+        let len = ch.len_utf8();
+        let current_offset = *offset as u32;
+        *offset += len;
+        Some(Token {
+            ch,
+            offset: current_offset,
+            len: len as u8,
+            info: ch.into(),
+            data: 0,
+        })
+    }).collect()
+}
+
+/// Returns the byte offsets of the ends of the grapheme clusters of `text`,
+/// segmented the same way as when shaping but regardless of how glyphs
+/// were merged.
+fn grapheme_ends(text: &str) -> Vec<usize> {
+    let tokens = tokens(text);
+    // levels only split runs, which doesn't change the clusters
+    let levels = vec![Level::ltr(); text.len()];
+    let mut cluster = CharCluster::new();
+    let mut ends = Vec::new();
+    for (script, _, tokens) in shaping_runs(&tokens, &levels) {
+        let mut parser = Parser::new(script, tokens.iter().copied());
+        while parser.next(&mut cluster) {
+            ends.push(cluster.range().end as usize);
+        }
+    }
+    ends
+}
+
+/// Picks the font to draw the cluster at `index` with, from the glyphs each
+/// font shaped it to: the first font with no `.notdef` glyphs in the
+/// cluster, however many glyphs it takes. If no font covers all of it, the
+/// one with glyphs for the largest share of the cluster, then the one with
+/// the fewest glyphs. Fonts matching `style` come first, then fonts earlier
+/// in the stack, so they win exact ties. Clusters no font has glyphs for
+/// fall through to the last font. Also returns true if no font has
+/// anything to draw for the cluster.
+fn choose_font<'a>(
+    fonts: &[&Font],
+    shapes: &'a [Vec<Vec<Glyph>>],
+    index: usize,
+    style: &Style,
+) -> Result<(usize, &'a [Glyph], bool), ParseError> {
+    // fonts matching the style get the first pick
+    let matches_style = |font: &Font| {
+        let properties = font.properties();
+        properties.weight == style.weight && properties.style == style.style
+    };
+    let font_order = (0..fonts.len())
+        .filter(|&i| matches_style(fonts[i]))
+        .chain((0..fonts.len()).filter(|&i| !matches_style(fonts[i])));
+    let mut best = None;
+    // true if no font has anything to draw for the cluster
+    let mut is_invisible = true;
+    for font_index in font_order {
+        let cluster = shapes[font_index]
+            .get(index)
+            .ok_or(ParseError::ShapingMismatch { font_index, cluster: index })?;
+        is_invisible &= cluster.iter().all(|g| g.id == 0 && g.advance == 0.);
+        let num_complete = cluster.iter().filter(|g| g.id != 0).count();
+        let len = cluster.len();
+        if len > 0 && num_complete == len {
+            // complete coverage beats any ratio, and the fonts after this
+            // one can only tie
+            best = Some((font_index, cluster, 1., len));
+            break;
+        }
+        let ratio = if len > 0 {
+            num_complete as f32 / len as f32
+        } else {
+            0.
+        };
+        if let &Some((_, _, prev_ratio, prev_len)) = &best {
+            if prev_ratio < ratio || (prev_ratio == ratio && prev_len > len) {
+                best = Some((font_index, cluster, ratio, len));
+            }
+        } else {
+            best = Some((font_index, cluster, ratio, len));
+        }
+    }
+    let Some((mut font_index, mut cluster, ratio, _)) = best else {
+        return Err(ParseError::NoFonts);
+    };
+    if ratio == 0. && !is_invisible {
+        // no font has the cluster, so it's drawn with the .notdef glyphs of
+        // the last font, usually a last resort font
+        font_index = fonts.len() - 1;
+        cluster = &shapes[font_index][index];
+    }
+    Ok((font_index, cluster.as_slice(), is_invisible))
+}
 
-                prev_range_end = idx.2;
-                glyphs.extend(cluster.iter().cloned());
+/// Returns the text of `line` without its line break, `\n`, `\r\n` or
+/// `\r`, if `has_linebreak` is set. Any other `\r` is kept, so that byte
+/// offsets into the text line up with the rope.
+fn line_text(line: ropey::RopeSlice, has_linebreak: bool) -> String {
+    let mut text = line.to_string();
+    if has_linebreak {
+        if text.ends_with('\n') {
+            text.pop();
+        }
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    text
+}
+
+/// Splits `range` of `rope` into lines at the line breaks of
+/// `line_ending`, returning the byte range of each line, including its line
+/// break, and whether it has one. Like [`ropey::Rope::lines`], a range
+/// ending in a line break is followed by an empty line.
+fn line_ranges(
+    rope: &ropey::Rope,
+    range: Range<usize>,
+    line_ending: LineEnding,
+) -> Vec<(Range<usize>, bool)> {
+    let mut lines = Vec::new();
+    let mut start = range.start;
+    let mut bytes = rope.byte_slice(range.clone()).bytes().enumerate().peekable();
+    while let Some((i, byte)) = bytes.next() {
+        let end = match byte {
+            b'\n' => range.start + i + 1,
+            b'\r' if line_ending == LineEnding::Cr => {
+                if bytes.peek().map(|&(_, next)| next) == Some(b'\n') {
+                    bytes.next();
+                    range.start + i + 2
+                } else {
+                    range.start + i + 1
+                }
             }
+            _ => continue,
+        };
+        lines.push((start..end, true));
+        start = end;
+    }
+    lines.push((start..range.end, false));
+    lines
+}
 
-            if !glyphs.is_empty() {
-                self.layout.push_run(line_no, prev_font_index, prev_range_start..prev_range_end, glyphs, size, fonts[prev_font_index].metrics);
+/// Returns the emboldening and slant needed to draw text in `style` with
+/// `font` at `size`, when the font's own weight or style doesn't match.
+fn synthesis_for(font: &Font, style: &Style, size: f32) -> Synthesis {
+    let properties = font.properties();
+    let is_bold = |weight: FontWeight| weight.0 >= FontWeight::SEMIBOLD.0;
+    let embolden = if is_bold(style.weight) && !is_bold(properties.weight) {
+        size * SYNTHETIC_BOLD_STRENGTH
+    } else {
+        0.
+    };
+    let skew = if style.style != FontStyle::Normal && properties.style == FontStyle::Normal {
+        SYNTHETIC_SKEW
+    } else {
+        0.
+    };
+    Synthesis { embolden, skew }
+}
+
+/// Returns the style of the text at byte `offset`.
+fn style_at(styles: &[(Range<usize>, Style)], offset: usize) -> Style {
+    styles
+        .iter()
+        .rev()
+        .find(|(range, _)| range.contains(&offset))
+        .map_or_else(Style::default, |&(_, style)| style)
+}
+
+/// Splits `tokens` into runs of the same script and bidi embedding level,
+/// given the level of each byte of the line. Characters shared between
+/// scripts (spaces, punctuation, combining marks) take the script of the
+/// text before them, or after them at the start of the line.
+fn shaping_runs<'a>(tokens: &'a [Token], levels: &[Level]) -> Vec<(Script, Level, &'a [Token])> {
+    let is_shared = |script: Script| {
+        matches!(script, Script::Common | Script::Inherited | Script::Unknown)
+    };
+    let mut runs = Vec::new();
+    let Some(first) = tokens.first() else {
+        return runs;
+    };
+    let mut script = tokens
+        .iter()
+        .map(|token| token.ch.script())
+        .find(|&script| !is_shared(script))
+        .unwrap_or(Script::Latin);
+    let mut level = levels[first.offset as usize];
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let token_script = token.ch.script();
+        let token_level = levels[token.offset as usize];
+        let script_changed = !is_shared(token_script) && token_script != script;
+        if script_changed || token_level != level {
+            if i > start {
+                runs.push((script, level, &tokens[start..i]));
+            }
+            if !is_shared(token_script) {
+                script = token_script;
             }
-            if has_linebreak {
-                line_no += 1;
-                // TODO: indicate to the layout that there is a linebreak (so we can display the cursor at the right place (and show symbols if that's a mode?)?)
+            level = token_level;
+            start = i;
+        }
+    }
+    runs.push((script, level, &tokens[start..]));
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn font_source() -> (FontSource, FontId) {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSans.ttf");
+        (source, id)
+    }
+
+    /// Parses `text` with the fixture fonts `fonts`, in fallback order, and
+    /// lays it out without wrapping.
+    fn parse_with(text: &str, fonts: &[&str]) -> Document {
+        let mut source = FontSource::new();
+        let ids = fonts.iter().map(|name| source.load_fixture(name)).collect::<Vec<_>>();
+        let fonts = ids.iter().map(|&id| source.get_font(id)).collect::<Vec<_>>();
+        let mut document = Document::from_str(text);
+        document.parse(&fonts, 16.).unwrap();
+        document.layout.finish();
+        document
+    }
+
+    fn line_count(text: &str, line_ending: LineEnding) -> usize {
+        let mut document = Document::from_str(text);
+        document.set_line_ending(line_ending);
+        line_ranges(document.rope(), 0..document.len_bytes(), document.line_ending()).len()
+    }
+
+    #[test]
+    fn lone_cr_breaks_lines_only_in_cr_mode() {
+        assert_eq!(line_count("a\r\r\r\nb", LineEnding::Lf), 2);
+        assert_eq!(line_count("a\r\r\r\nb", LineEnding::CrLf), 2);
+        assert_eq!(line_count("a\r\r\r\nb", LineEnding::Cr), 4);
+    }
+
+    #[test]
+    fn parsed_lines_follow_the_line_ending() {
+        let (source, id) = font_source();
+        let mut document = Document::from_str("a\r\r\r\nb");
+        // two lone CRs outnumber the one CRLF
+        assert_eq!(document.line_ending(), LineEnding::Cr);
+        let cases = [(LineEnding::Cr, 4), (LineEnding::Lf, 2), (LineEnding::CrLf, 2)];
+        for (line_ending, count) in cases {
+            document.set_line_ending(line_ending);
+            document.parse(&[source.get_font(id)], 16.).unwrap();
+            assert_eq!(document.layout.paragraph_count(), count, "{:?}", line_ending);
+        }
+    }
+
+    #[test]
+    fn line_text_keeps_mid_line_cr() {
+        let rope = ropey::Rope::from_str("a\r\r\r\nb");
+        let lines = line_ranges(&rope, 0..rope.len_bytes(), LineEnding::Lf);
+        let texts = lines
+            .iter()
+            .map(|(range, has_linebreak)| line_text(rope.byte_slice(range.clone()), *has_linebreak))
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["a\r\r", "b"]);
+    }
+
+    #[test]
+    fn bold_face_needs_no_synthetic_bold() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSans-Bold.ttf");
+        let font = source.get_font(id);
+        assert!(font.properties().weight.0 >= FontWeight::SEMIBOLD.0);
+        let bold = Style { weight: FontWeight::BOLD, ..Default::default() };
+        assert_eq!(synthesis_for(font, &bold, 32.).embolden, 0.);
+    }
+
+    #[test]
+    fn synthetic_skew_widens_glyphs() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        assert_eq!(font.properties().style, FontStyle::Normal);
+        let italic = Style { style: FontStyle::Italic, ..Default::default() };
+        let skew = synthesis_for(font, &italic, 32.).skew;
+        assert_ne!(skew, 0.);
+        let mut context = ScaleContext::new();
+        let mut scaler = context.builder(font.fontref()).size(32.).build();
+        let glyph_id = font.fontref().charmap().map('l');
+        let upright = scaler.scale_outline(glyph_id).unwrap();
+        let mut skewed = scaler.scale_outline(glyph_id).unwrap();
+        skewed.transform(&Transform::skew(Angle::from_degrees(skew), Angle::ZERO));
+        assert!(skewed.bounds().width() > upright.bounds().width());
+    }
+
+    #[test]
+    fn offset_conversions_round_trip() {
+        let document = Document::from_str("héllo\nwörld 🦀\n");
+        assert_eq!(document.len_bytes(), 19);
+        assert_eq!(document.len_chars(), 14);
+        assert_eq!(document.len_lines(), 3);
+        assert_eq!(document.char_to_byte(2), 3);
+        assert_eq!(document.byte_to_char(3), 2);
+        assert_eq!(document.char_to_byte(13), 18);
+        // a byte inside a char maps to that char
+        assert_eq!(document.byte_to_char(2), 1);
+        for char_idx in 0..=document.len_chars() {
+            let byte_idx = document.char_to_byte(char_idx);
+            assert_eq!(document.byte_to_char(byte_idx), char_idx);
+        }
+        assert_eq!(document.text().line(1).to_string(), "wörld 🦀\n");
+    }
+
+    #[test]
+    fn lossy_loading_replaces_invalid_bytes() {
+        let bytes: &[u8] = &[0xFF, b'a'];
+        assert!(Document::from_reader(bytes).is_err());
+        let document = Document::from_reader_lossy(bytes).unwrap();
+        assert_eq!(document.text().to_string(), "\u{FFFD}a");
+    }
+
+    #[test]
+    fn empty_lines_are_laid_out() {
+        for (text, count) in [("", 1), ("\n", 2), ("\n\n", 3)] {
+            let document = parse_with(text, &["DejaVuSans.ttf"]);
+            assert_eq!(document.layout.paragraph_count(), count, "{:?}", text);
+            assert_eq!(document.layout.lines.len(), count, "{:?}", text);
+            for line in &document.layout.lines {
+                // enough height to show a cursor
+                assert!(line.above + line.below > 0.);
             }
         }
-        self.is_dirty = false;
+    }
+
+    #[test]
+    fn edit_keeps_other_lines() {
+        let (source, id) = font_source();
+        let fonts = [source.get_font(id)];
+        let mut document = Document::from_str("one\ntwo\nthree");
+        document.parse(&fonts, 16.).unwrap();
+        let glyphs = |document: &Document, line_no: usize| {
+            document.layout.paragraph_runs(line_no).unwrap()[0].glyphs.as_ptr()
+        };
+        let (first, last) = (glyphs(&document, 0), glyphs(&document, 2));
+        document.edit(5..6, "wooo");
+        document.parse(&fonts, 16.).unwrap();
+        assert_eq!(document.layout.paragraph_count(), 3);
+        assert_eq!(glyphs(&document, 0), first);
+        assert_eq!(glyphs(&document, 2), last);
+        assert_eq!(document.layout.paragraph_range(2), Some(11..16));
+    }
+
+    #[test]
+    fn parsing_without_fonts_fails() {
+        let mut document = Document::from_str("text");
+        assert!(matches!(document.parse(&[], 16.), Err(ParseError::NoFonts)));
+        assert!(matches!(measure("text", &[], 16.), Err(ParseError::NoFonts)));
+    }
+
+    #[test]
+    fn variation_selector_leaves_no_empty_glyph() {
+        let document = parse_with("0\u{FE0F}1", &["InterVariable.ttf", "NotoEmoji-Regular.ttf"]);
+        let glyphs = document.layout.lines[0]
+            .runs
+            .iter()
+            .flat_map(|run| &run.glyphs)
+            .collect::<Vec<_>>();
+        // only the digits have anything to draw, and no font is missing
+        // a glyph for the selector
+        assert_eq!(glyphs.iter().filter(|g| g.advance > 0.).count(), 2);
+        assert!(glyphs.iter().all(|g| g.id != 0));
+    }
+
+    #[test]
+    fn parallel_parse_matches_serial_parse() {
+        let mut source = FontSource::new();
+        let ids = ["DejaVuSans.ttf", "NotoEmoji-Regular.ttf"].map(|name| source.load_fixture(name));
+        let fonts = ids.map(|id| source.get_font(id));
+        let text = (0..32)
+            .map(|i| format!("line {i}: Hello, world!\t\u{05E9}\u{05DC}\u{05D5}\u{05DD} \u{1F600}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let runs = |parallel: bool| {
+            let mut document = Document::from_str(&text);
+            document.set_parallel(parallel);
+            document.parse(&fonts, 16.).unwrap();
+            document.layout.finish();
+            document
+                .layout
+                .lines
+                .iter()
+                .flat_map(|line| &line.runs)
+                .map(|run| {
+                    let glyphs = run.glyphs.iter().map(|g| (g.id, g.x, g.y, g.advance));
+                    (run.font_id, run.range.clone(), glyphs.collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(runs(true), runs(false));
+    }
+
+    #[test]
+    fn clusters_fall_back_through_the_fonts() {
+        let mut source = FontSource::new();
+        let ids = ["DejaVuSans.ttf", "NotoEmoji-Regular.ttf", "FDArrayTest257.otf"]
+            .map(|name| source.load_fixture(name));
+        let mut document = Document::from_str("a\u{1F680}\u{65E5}");
+        document.parse(&ids.map(|id| source.get_font(id)), 16.).unwrap();
+        document.layout.finish();
+        let runs = document.layout.lines[0]
+            .runs
+            .iter()
+            .map(|run| (run.range.clone(), run.font_id))
+            .collect::<Vec<_>>();
+        assert_eq!(runs, [(0..1, ids[0]), (1..5, ids[1]), (5..8, ids[2])]);
+    }
+
+    #[test]
+    fn vertical_glyphs_advance_downwards() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("FDArrayTest257.otf");
+        let mut document = Document::from_str("\u{65E5}\u{672C}\u{3042}");
+        document.set_writing_mode(WritingMode::VerticalRl);
+        document.parse(&[source.get_font(id)], 16.).unwrap();
+        document.layout.finish();
+        let glyphs = document.layout.positioned_glyphs((0., 0.)).collect::<Vec<_>>();
+        assert_eq!(glyphs.len(), 3);
+        assert!(glyphs.iter().all(|g| g.id != 0 && g.advance > 0.));
+        // each glyph is below the previous one, in the same column
+        for pair in glyphs.windows(2) {
+            assert!(pair[1].y > pair[0].y, "{} <= {}", pair[1].y, pair[0].y);
+            assert!((pair[1].x - pair[0].x).abs() < 1.);
+        }
+    }
+
+    #[test]
+    fn grapheme_boundaries_ignore_ligatures() {
+        let document = parse_with("fi", &["DejaVuSans.ttf"]);
+        let glyphs = document.layout.lines[0]
+            .runs
+            .iter()
+            .flat_map(|run| &run.glyphs)
+            .count();
+        // DejaVu Sans shapes "fi" to a single ligature glyph
+        assert_eq!(glyphs, 1);
+        assert_eq!(document.next_grapheme_boundary(0), 1);
+        assert_eq!(document.next_grapheme_boundary(1), 2);
+        assert_eq!(document.prev_grapheme_boundary(2), 1);
+        assert_eq!(document.prev_grapheme_boundary(1), 0);
+    }
+
+    #[test]
+    fn flag_is_one_grapheme() {
+        let flag = "\u{1F1EF}\u{1F1F5}";
+        let fonts = ["DejaVuSans.ttf", "NotoEmoji-Regular.ttf"];
+        let document = parse_with(&format!("a{flag}b"), &fonts);
+        assert_eq!(document.next_grapheme_boundary(1), 1 + flag.len());
+        assert_eq!(document.prev_grapheme_boundary(1 + flag.len()), 1);
+        // from inside the flag
+        assert_eq!(document.next_grapheme_boundary(5), 1 + flag.len());
+        assert_eq!(document.prev_grapheme_boundary(5), 1);
+    }
+
+    #[test]
+    fn runs_resolve_through_the_source() {
+        let mut source = FontSource::new();
+        // the font parsed with isn't the first in the source, so its index
+        // in the fonts passed to parse is a different font
+        let inter = source.load_fixture("InterVariable.ttf");
+        let dejavu = source.load_fixture("DejaVuSans.ttf");
+        let mut document = Document::from_str("text");
+        document.parse(&[source.get_font(dejavu)], 16.).unwrap();
+        document.layout.finish();
+        let emoji = source.load_fixture("NotoEmoji-Regular.ttf");
+        let run = &document.layout.lines[0].runs[0];
+        assert_eq!(run.font_id, dejavu);
+        assert!(![inter, emoji].contains(&run.font_id));
+        let face = source.get_fontref(run.font_id);
+        assert_eq!(face.key, source.get_fontref(dejavu).key);
+    }
+
+    #[test]
+    #[cfg(feature = "hyphenation")]
+    fn long_words_are_hyphenated_only_when_enabled() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let hyphen = font.fontref().charmap().map('-');
+        // the last glyph of each line
+        let line_ends = |hyphenation: bool| {
+            let mut document = Document::from_str("hyphenation");
+            document.set_hyphenation(hyphenation);
+            document.parse(&[font], 16.).unwrap();
+            document.layout.set_wrap_width(Some(40.));
+            document.layout.finish();
+            document
+                .layout
+                .lines
+                .iter()
+                .map(|line| line.runs.last().unwrap().glyphs.last().unwrap().id)
+                .collect::<Vec<_>>()
+        };
+        let hyphenated = line_ends(true);
+        assert!(hyphenated.len() > 1);
+        assert_eq!(hyphenated[0], hyphen);
+        assert!(!line_ends(false).contains(&hyphen));
+    }
+
+    #[test]
+    fn overlong_line_ends_in_an_ellipsis() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let ellipsis = font.fontref().charmap().map('\u{2026}');
+        let mut document = Document::from_str("Hello, world");
+        document.parse(&[font], 16.).unwrap();
+        document.layout.finish();
+        let width = document.layout.lines[0].advance();
+
+        document.layout.set_ellipsis(Some(width));
+        document.layout.finish();
+        assert!(!document.layout.is_truncated());
+
+        // slightly too narrow for the whole string
+        document.layout.set_ellipsis(Some(width - 1.));
+        document.layout.finish();
+        assert!(document.layout.is_truncated());
+        let line = &document.layout.lines[0];
+        assert!(line.advance() <= width - 1.);
+        let last = line.runs.last().unwrap().glyphs.last().unwrap();
+        assert_eq!(last.id, ellipsis);
+    }
+
+    #[test]
+    fn complete_coverage_beats_fewer_glyphs() {
+        let mut source = FontSource::new();
+        let ids = ["DejaVuSans.ttf", "InterVariable.ttf"].map(|name| source.load_fixture(name));
+        let fonts = ids.map(|id| source.get_font(id));
+        let glyphs = |ids: &[GlyphId]| {
+            ids.iter()
+                .map(|&id| Glyph { id, advance: 10., ..Default::default() })
+                .collect::<Vec<_>>()
+        };
+        // the first font misses half of the cluster, the second has all of
+        // it but needs more glyphs
+        let shapes = [vec![glyphs(&[4, 0])], vec![glyphs(&[1, 2, 3])]];
+        let (font_index, cluster, _) = choose_font(&fonts, &shapes, 0, &Style::default()).unwrap();
+        assert_eq!(font_index, 1);
+        assert_eq!(cluster.len(), 3);
+        // on an exact tie the earlier font wins
+        let shapes = [vec![glyphs(&[1, 2])], vec![glyphs(&[3, 4])]];
+        let (font_index, _, _) = choose_font(&fonts, &shapes, 0, &Style::default()).unwrap();
+        assert_eq!(font_index, 0);
+    }
+
+    #[test]
+    fn cursor_steps_over_ligatures() {
+        let mut document = parse_with("afib", &["DejaVuSans.ttf"]);
+        let mut offsets = Vec::new();
+        for _ in 0..4 {
+            document.move_right();
+            offsets.push(document.cursor());
+        }
+        // the cursor can't land inside the "fi" ligature
+        assert_eq!(offsets, [1, 3, 4, 4]);
+        document.move_left();
+        document.move_left();
+        assert_eq!(document.cursor(), 1);
+    }
+
+    #[test]
+    fn cursor_moves_across_wrapped_lines() {
+        let mut document = parse_with("aaa aaa aaa", &["DejaVuSans.ttf"]);
+        document.layout.set_wrap_width(Some(40.));
+        document.layout.finish();
+        assert_eq!(document.layout.lines.len(), 3);
+        document.set_selection(1, 1);
+        document.move_down();
+        assert_eq!(document.cursor(), 5);
+        document.move_down();
+        assert_eq!(document.cursor(), 9);
+        document.move_up();
+        assert_eq!(document.cursor(), 5);
+        document.extend_up();
+        assert_eq!(document.selection(), 1..5);
+    }
+
+    #[test]
+    fn ascii_fast_path_matches_shaping() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSansMono.ttf");
+        let fonts = [source.get_font(id)];
+        let features = [b"liga", b"clig", b"calt"].map(|tag| (swash::tag_from_bytes(tag), 0));
+        assert!(can_skip_shaping(&fonts, &[], &features));
+        let text = "fn main() { let x = 1 + 2; }";
+        let (fast_clusters, fast_shapes) = map_ascii_line(text, 3, &fonts, 16.).unwrap();
+        let mut shapers = [ShapeContext::new(fonts[0], 16.)];
+        shapers[0].set_features(&features);
+        let mut clusters = Vec::new();
+        let mut cluster = CharCluster::new();
+        let shapes = shape_line(text, 3, &mut shapers, &mut cluster, None, &mut clusters).unwrap();
+
+        let ranges = |clusters: &[SourceCluster]| {
+            clusters
+                .iter()
+                .map(|c| (c.range.clone(), c.is_whitespace))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ranges(&fast_clusters), ranges(&clusters));
+        assert_eq!(fast_shapes[0].len(), shapes[0].len());
+        for (fast, shaped) in fast_shapes[0].iter().zip(&shapes[0]) {
+            assert_eq!(fast.len(), 1);
+            assert_eq!(shaped.len(), 1);
+            let (fast, shaped) = (&fast[0], &shaped[0]);
+            assert_eq!(fast.id, shaped.id);
+            assert_eq!((fast.x, fast.y), (shaped.x, shaped.y));
+            assert!((fast.advance - shaped.advance).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn uncovered_code_point_follows_the_missing_glyph_style() {
+        let (source, id) = font_source();
+        let fonts = [source.get_font(id)];
+        let text = "a\u{10FFFD}b";
+        let parsed = |style: MissingGlyphStyle| {
+            let mut document = Document::from_str(text);
+            document.set_missing_glyph_style(style);
+            document.parse(&fonts, 16.).unwrap();
+            document.layout.finish();
+            document
+        };
+
+        let notdef = parsed(MissingGlyphStyle::Notdef);
+        let runs = &notdef.layout.lines[0].runs;
+        assert!(runs.iter().flat_map(|run| &run.glyphs).any(|g| g.id == 0));
+
+        let hex_box = parsed(MissingGlyphStyle::HexBox);
+        let clusters = hex_box.layout.lines[0]
+            .runs
+            .iter()
+            .flat_map(|run| &run.clusters)
+            .filter(|cluster| cluster.missing.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].missing, Some('\u{10FFFD}'));
+        assert_eq!(clusters[0].range, 1..5);
+
+        // hidden, the code point takes no space
+        let hidden = parsed(MissingGlyphStyle::Hidden);
+        let plain = parse_with("ab", &["DejaVuSans.ttf"]);
+        assert_eq!(
+            hidden.layout.lines[0].advance(),
+            plain.layout.lines[0].advance()
+        );
+    }
+
+    #[test]
+    fn total_size_covers_every_line() {
+        let document = parse_with("one\ntwo three\nfour", &["DejaVuSans.ttf"]);
+        let lines = &document.layout.lines;
+        assert_eq!(lines.len(), 3);
+        let (width, height) = document.layout.total_size();
+        let heights = lines.iter().map(|line| line.above + line.below).sum::<f32>();
+        assert_eq!(height, heights);
+        let widest = lines.iter().map(|line| line.advance()).fold(0., f32::max);
+        assert_eq!(width, widest);
     }
 }
@@ -1,18 +1,29 @@
 use std::{
+    ffi::{c_char, c_void},
     fmt::{Debug, Display},
-    ptr::{null, null_mut},
+    ops::{Deref, Range},
+    ptr::null_mut,
     sync::Arc,
 };
 
 pub use font_kit::family_name::FamilyName as FontFamily;
+pub use font_kit::properties::{
+    Properties as FontProperties, Stretch as FontStretch, Style as FontStyle,
+    Weight as FontWeight,
+};
 use harfbuzz::sys::{
-    hb_buffer_add, hb_buffer_create, hb_buffer_destroy, hb_buffer_get_glyph_infos,
+    hb_blob_create, hb_buffer_add, hb_buffer_create, hb_buffer_destroy, hb_buffer_get_glyph_infos,
     hb_buffer_get_glyph_positions, hb_buffer_get_length, hb_buffer_guess_segment_properties,
-    hb_buffer_reset, hb_buffer_set_content_type, hb_buffer_t, hb_face_create, hb_face_destroy,
-    hb_face_t, hb_font_create, hb_font_destroy, hb_font_get_ppem, hb_font_get_scale, hb_font_t,
-    hb_shape, HB_BUFFER_CONTENT_TYPE_UNICODE,
+    hb_buffer_reset, hb_buffer_set_content_type, hb_buffer_set_direction, hb_buffer_set_language,
+    hb_buffer_set_script, hb_buffer_t, hb_face_create, hb_feature_t, hb_face_destroy, hb_face_t,
+    hb_font_create, hb_font_destroy, hb_font_get_glyph_h_advance, hb_font_get_scale, hb_font_set_variations,
+    hb_font_t, hb_language_from_string, hb_language_get_default, hb_script_from_iso15924_tag,
+    hb_shape, hb_variation_t, HB_BUFFER_CONTENT_TYPE_UNICODE, HB_DIRECTION_LTR, HB_DIRECTION_RTL,
+    HB_DIRECTION_TTB,
+    HB_MEMORY_MODE_READONLY,
 };
-use swash::{FontRef, text::cluster::CharCluster, proxy::MetricsProxy, Metrics, CacheKey, shape::cluster::{Glyph, GlyphInfo}, GlyphId};
+use memmap2::Mmap;
+use swash::{FontRef, text::{cluster::{CharCluster, Parser, Token}, Codepoint, Script}, proxy::MetricsProxy, Metrics, CacheKey, shape::{cluster::{Glyph, GlyphInfo}, Direction}, GlyphId, Tag};
 
 #[derive(Debug)]
 pub enum FontKitError {
@@ -40,16 +51,60 @@ impl From<font_kit::error::SelectionError> for FontKitError {
     }
 }
 
+/// Storage for the contents of a font file.
+#[derive(Clone)]
+pub enum FontData {
+    Owned(Arc<Vec<u8>>),
+    /// The font file mapped into memory.
+    Mapped(Arc<Mmap>),
+}
+
+impl Deref for FontData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FontData::Owned(data) => data,
+            FontData::Mapped(map) => map,
+        }
+    }
+}
+
+impl FontData {
+    /// Creates a harfbuzz blob over the data, which keeps the data alive
+    /// for as long as harfbuzz holds onto the blob.
+    fn to_blob(&self) -> harfbuzz::Blob<'static> {
+        match self {
+            FontData::Owned(data) => harfbuzz::Blob::new_from_arc_vec(data.clone()),
+            FontData::Mapped(map) => {
+                unsafe extern "C" fn destroy(user_data: *mut c_void) {
+                    drop(Arc::from_raw(user_data as *const Mmap));
+                }
+                let len = map.len() as u32;
+                let ptr = map.as_ptr() as *const c_char;
+                let user_data = Arc::into_raw(map.clone()) as *mut c_void;
+                unsafe {
+                    let raw = hb_blob_create(ptr, len, HB_MEMORY_MODE_READONLY, user_data, Some(destroy));
+                    harfbuzz::Blob::from_raw(raw)
+                }
+            }
+        }
+    }
+}
+
 pub struct Font {
-    raw: Arc<Vec<u8>>,
+    raw: FontData,
     index: usize,
     blob: harfbuzz::Blob<'static>,
     hb_face: *mut hb_face_t,
     hb_font: *mut hb_font_t,
     hb_buffer: *mut hb_buffer_t,
-    pub metrics: Metrics,
+    metrics: Metrics,
     pub cache_key: CacheKey,
     offset: u32,
+    properties: FontProperties,
+    coords: Vec<i16>,
+    id: FontId,
 }
 
 impl Debug for Font {
@@ -58,6 +113,11 @@ impl Debug for Font {
     }
 }
 
+// harfbuzz fonts can be shared between threads once they've been set up,
+// and the font's own buffer is never used. Changing variations needs
+// `&mut self`.
+unsafe impl Sync for Font {}
+
 impl Drop for Font {
     fn drop(&mut self) {
         unsafe {
@@ -72,6 +132,92 @@ impl Font {
     pub fn fontref(&self) -> FontRef<'_> {
         FontRef { data: &self.raw, offset: self.offset, key: self.cache_key }
     }
+
+    /// Returns the id of the font in the [`FontSource`] that loaded it.
+    pub fn id(&self) -> FontId {
+        self.id
+    }
+
+    /// Returns the font's metrics in font units, for the current variation
+    /// coordinates. Use [`Metrics::scale`] to get them at a pixel size.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    /// Returns the weight, style and stretch of the loaded face, which may
+    /// differ from the properties it was matched with.
+    pub fn properties(&self) -> FontProperties {
+        self.properties
+    }
+
+    /// Sets the values of the font's variation axes (e.g. `wght`, `slnt`).
+    /// Axes that aren't listed use their default value.
+    pub fn set_variations(&mut self, axes: &[(Tag, f32)]) {
+        let variations = axes
+            .iter()
+            .map(|&(tag, value)| hb_variation_t { tag, value })
+            .collect::<Vec<_>>();
+        unsafe {
+            hb_font_set_variations(self.hb_font, variations.as_ptr(), variations.len() as u32);
+        }
+        let fr = self.fontref();
+        let coords = fr.variations().normalized_coords(axes).collect::<Vec<_>>();
+        let metrics = MetricsProxy::from_font(&fr).materialize_metrics(&fr, &coords);
+        self.coords = coords;
+        self.metrics = metrics;
+    }
+
+    /// Returns the horizontal advance of the glyph `glyph_id` in pixels at
+    /// `size`, for the current variation coordinates, without shaping.
+    /// Kerning and other shaping adjustments aren't applied. Returns 0 for
+    /// a glyph id the font doesn't have.
+    pub fn glyph_advance(&self, glyph_id: u32, size: f32) -> f32 {
+        if glyph_id >= self.metrics.glyph_count as u32 {
+            return 0.;
+        }
+        let mut x_scale: i32 = 0;
+        let mut y_scale: i32 = 0;
+        let advance = unsafe {
+            hb_font_get_scale(self.hb_font, &mut x_scale, &mut y_scale);
+            hb_font_get_glyph_h_advance(self.hb_font, glyph_id)
+        };
+        if x_scale == 0 {
+            return 0.;
+        }
+        advance as f32 / x_scale as f32 * size
+    }
+
+    /// Returns the names of the font's named instances (e.g. "SemiBold"),
+    /// empty if it isn't a variable font.
+    pub fn named_instances(&self) -> Vec<String> {
+        self.fontref()
+            .instances()
+            .filter_map(|instance| Some(instance.name(None)?.to_string()))
+            .collect()
+    }
+
+    /// Sets the font's variation axes to the values of the named instance
+    /// `name`, as listed by [`Font::named_instances`]. Returns false if the
+    /// font has no such instance.
+    pub fn set_named_instance(&mut self, name: &str) -> bool {
+        let fr = self.fontref();
+        let Some(instance) = fr.instances().find_by_name(name) else {
+            return false;
+        };
+        let axes = fr
+            .variations()
+            .map(|axis| axis.tag())
+            .zip(instance.values())
+            .collect::<Vec<_>>();
+        self.set_variations(&axes);
+        true
+    }
+
+    /// Returns the normalized variation coordinates of the font, for use
+    /// when rasterizing glyphs.
+    pub fn coords(&self) -> &[i16] {
+        &self.coords
+    }
     // pub fn render(&self, glyphs: &[Glyph]) {
     //     //let transform = Transform2F::default();
     //     for glyph in glyphs {
@@ -127,8 +273,15 @@ impl Font {
     //     }
 }
 
+/// Handle to a font owned by a [`FontSource`].
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FontId(usize);
+
+/// Finds and loads fonts, which it owns for as long as it lives. Loading
+/// returns a [`FontId`] to look the font up by.
 pub struct FontSource {
     raw: font_kit::source::SystemSource,
+    fonts: Vec<Font>,
 }
 
 impl FontSource {
@@ -138,16 +291,23 @@ impl FontSource {
     }
 
     /// Finds and loads a font matching the set of provided family priorities.
-    pub fn load(&mut self, families: &[FontFamily]) -> Result<Font, FontKitError> {
-        let handle = self
-            .raw
-            .select_best_match(families, &font_kit::properties::Properties::default())?;
+    pub fn load(&mut self, families: &[FontFamily]) -> Result<FontId, FontKitError> {
+        self.load_with(families, FontProperties::default())
+    }
+
+    /// Finds and loads the font best matching the set of provided family
+    /// priorities and the given weight, style and stretch.
+    pub fn load_with(
+        &mut self,
+        families: &[FontFamily],
+        properties: FontProperties,
+    ) -> Result<FontId, FontKitError> {
+        let handle = self.raw.select_best_match(families, &properties)?;
 
         let (data, index) = match handle {
             font_kit::handle::Handle::Path { path, font_index } => {
                 use std::io::Read;
 
-                dbg!(&path);
                 let mut buf = Vec::new();
                 let mut reader =
                     std::fs::File::open(path).map_err(font_kit::error::FontLoadingError::Io)?;
@@ -157,7 +317,81 @@ impl FontSource {
             }
             font_kit::handle::Handle::Memory { bytes, font_index } => (bytes, font_index),
         };
-        let blob = harfbuzz::Blob::new_from_arc_vec(data.clone());
+        let font = self.load_from_data(FontData::Owned(data), index)?;
+        Ok(self.add(font))
+    }
+
+    /// Like [`FontSource::load_with`], but memory maps the font file rather
+    /// than reading it, which saves memory and load time for large fonts.
+    pub fn load_mmap(
+        &mut self,
+        families: &[FontFamily],
+        properties: FontProperties,
+    ) -> Result<FontId, FontKitError> {
+        let handle = self.raw.select_best_match(families, &properties)?;
+
+        let (data, index) = match handle {
+            font_kit::handle::Handle::Path { path, font_index } => {
+                let file =
+                    std::fs::File::open(path).map_err(font_kit::error::FontLoadingError::Io)?;
+                // the font file is assumed not to change while it's mapped
+                let map = unsafe { Mmap::map(&file) }.map_err(font_kit::error::FontLoadingError::Io)?;
+                (FontData::Mapped(Arc::new(map)), font_index)
+            }
+            font_kit::handle::Handle::Memory { bytes, font_index } => {
+                (FontData::Owned(bytes), font_index)
+            }
+        };
+        let font = self.load_from_data(data, index)?;
+        Ok(self.add(font))
+    }
+
+    /// Loads the font at `index` in the font data `data`, without going
+    /// through the system font source.
+    pub fn load_from_bytes(&mut self, data: Arc<Vec<u8>>, index: u32) -> Result<FontId, FontKitError> {
+        let font = self.load_from_data(FontData::Owned(data), index)?;
+        Ok(self.add(font))
+    }
+
+    /// Loads the font file `name` from `tests/fixtures/fonts`, so tests
+    /// don't depend on the fonts installed on the system.
+    #[cfg(test)]
+    pub(crate) fn load_fixture(&mut self, name: &str) -> FontId {
+        let path = format!("{}/tests/fixtures/fonts/{name}", env!("CARGO_MANIFEST_DIR"));
+        let data = std::fs::read(&path).unwrap_or_else(|err| panic!("{path}: {err}"));
+        self.load_from_bytes(Arc::new(data), 0).unwrap()
+    }
+
+    fn add(&mut self, font: Font) -> FontId {
+        let id = font.id;
+        debug_assert_eq!(id, FontId(self.fonts.len()));
+        self.fonts.push(font);
+        id
+    }
+
+    /// Returns the font `id`.
+    ///
+    /// Panics if `id` was returned by a different source.
+    pub fn get_font(&self, id: FontId) -> &Font {
+        &self.fonts[id.0]
+    }
+
+    /// Returns the font `id` mutably, e.g. to set its variations.
+    ///
+    /// Panics if `id` was returned by a different source.
+    pub fn get_font_mut(&mut self, id: FontId) -> &mut Font {
+        &mut self.fonts[id.0]
+    }
+
+    /// Returns a reference to the font `id` for use with swash.
+    ///
+    /// Panics if `id` was returned by a different source.
+    pub fn get_fontref(&self, id: FontId) -> FontRef<'_> {
+        self.fonts[id.0].fontref()
+    }
+
+    fn load_from_data(&mut self, data: FontData, index: u32) -> Result<Font, FontKitError> {
+        let blob = data.to_blob();
         let (hb_face, hb_font, hb_buffer) = unsafe {
             let face = hb_face_create(blob.as_raw(), index);
             let hb_font = hb_font_create(face);
@@ -165,10 +399,6 @@ impl FontSource {
             // x_scale *= 2;
             // y_scale *= 2;
             // hb_font_set_scale(hb_font, x_scale, y_scale);
-            let mut x_ppem: u32 = 0;
-            let mut y_ppem: u32 = 0;
-            hb_font_get_ppem(hb_font, &mut x_ppem, &mut y_ppem);
-            dbg!(x_ppem, y_ppem);
             //hb_font_set_ppem(hb_font, 62, 62);
             let hb_buffer = hb_buffer_create();
             (face, hb_font, hb_buffer)
@@ -179,6 +409,17 @@ impl FontSource {
         let metrics = MetricsProxy::from_font(&fr).materialize_metrics(&fr, &[]);
         let cache_key = fr.key;
         let offset = fr.offset;
+        let attributes = fr.attributes();
+        let properties = FontProperties {
+            weight: FontWeight(attributes.weight().0 as f32),
+            style: match attributes.style() {
+                swash::Style::Normal => FontStyle::Normal,
+                swash::Style::Italic => FontStyle::Italic,
+                swash::Style::Oblique(_) => FontStyle::Oblique,
+            },
+            // font-kit stretches are ratios, with 1 as normal
+            stretch: FontStretch(attributes.stretch().to_percentage() / 100.),
+        };
         Ok(Font {
             raw: data,
             index,
@@ -189,6 +430,10 @@ impl FontSource {
             metrics,
             cache_key,
             offset,
+            properties,
+            coords: Vec::new(),
+            // the font is added to the source next
+            id: FontId(self.fonts.len()),
         })
     }
 }
@@ -197,18 +442,99 @@ impl Default for FontSource {
     fn default() -> Self {
         Self {
             raw: font_kit::source::SystemSource::new(),
+            fonts: Vec::new(),
         }
     }
 }
 
+/// An ordered list of fonts to fall back through when shaping, e.g. Latin,
+/// then CJK, then emoji, then symbols. The fonts are owned by the
+/// [`FontSource`] they were loaded from.
+pub struct FontStack {
+    ids: Vec<FontId>,
+}
+
+impl FontStack {
+    /// Loads the best match for each of `families`, in order of priority.
+    /// Families that can't be found are left out of the stack, so it's only
+    /// an error if none of them can be loaded.
+    pub fn load(source: &mut FontSource, families: &[FontFamily]) -> Result<FontStack, FontKitError> {
+        let mut ids = Vec::with_capacity(families.len());
+        let mut error = None;
+        for family in families {
+            match source.load(std::slice::from_ref(family)) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    log::warn!("Failed to load font family {:?}: {}", family, e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if ids.is_empty() => Err(e),
+            _ => Ok(FontStack { ids }),
+        }
+    }
+
+    /// Returns the fonts of the stack from `source`, in order, as taken by
+    /// [`crate::document::Document::parse`].
+    pub fn fonts<'a>(&self, source: &'a FontSource) -> Vec<&'a Font> {
+        self.ids.iter().map(|&id| source.get_font(id)).collect()
+    }
+
+    /// Returns the ids of the fonts of the stack, in order.
+    pub fn ids(&self) -> &[FontId] {
+        &self.ids
+    }
+
+    /// Returns the first font of the stack, whose metrics are used for
+    /// tabs and empty lines.
+    pub fn primary(&self) -> Option<FontId> {
+        self.ids.first().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+/// Glyphs a cluster was shaped to, as returned by
+/// [`ShapeContext::shape_clusters`].
+#[derive(Clone, Debug)]
+pub struct ShapedCluster {
+    /// Byte range of the cluster, in the offsets of the tokens it was
+    /// parsed from.
+    pub source_range: Range<usize>,
+    /// Glyphs of the cluster, empty if they were merged into an earlier
+    /// cluster, e.g. the second character of a ligature.
+    pub glyphs: Vec<Glyph>,
+}
+
+impl ShapedCluster {
+    /// Returns the text of the cluster, given the text whose offsets its
+    /// tokens had.
+    pub fn source<'a>(&self, text: &'a str) -> &'a str {
+        &text[self.source_range.clone()]
+    }
+}
+
 pub struct ShapeContext<'a> {
     font: &'a Font,
+    size: f32,
     hb_buffer: *mut hb_buffer_t,
     cluster_count: u32,
+    /// Source ranges of the clusters added since the last reset.
+    ranges: Vec<Range<usize>>,
+    features: Vec<hb_feature_t>,
+    vertical: bool,
 }
 
 impl<'a> ShapeContext<'a> {
-    pub fn new(font: &'a Font) -> ShapeContext<'a> {
+    pub fn new(font: &'a Font, size: f32) -> ShapeContext<'a> {
         let hb_buffer = unsafe {
             let buf = hb_buffer_create();
             hb_buffer_set_content_type(buf, HB_BUFFER_CONTENT_TYPE_UNICODE);
@@ -216,31 +542,119 @@ impl<'a> ShapeContext<'a> {
         };
         ShapeContext {
             font,
+            size,
             hb_buffer,
             cluster_count: 0,
+            ranges: Vec::new(),
+            features: Vec::new(),
+            vertical: false,
         }
     }
 
+    /// Sets the OpenType features applied to the whole buffer when shaping,
+    /// e.g. `(swash::tag_from_bytes(b"liga"), 0)` to turn off ligatures or
+    /// `(tag_from_bytes(b"zero"), 1)` for slashed zeros. Features the font
+    /// doesn't have are ignored. Unlike the segment properties these are
+    /// kept by [`ShapeContext::reset`].
+    pub fn set_features(&mut self, features: &[(Tag, u32)]) {
+        self.features = features
+            .iter()
+            .map(|&(tag, value)| hb_feature_t {
+                tag,
+                value,
+                start: 0,
+                end: u32::MAX,
+            })
+            .collect();
+    }
+
+    /// Shapes text top to bottom for vertical writing, overriding the
+    /// direction set with [`ShapeContext::set_segment_properties`]. The
+    /// advance of each glyph is then its vertical advance, downwards. Kept
+    /// by [`ShapeContext::reset`].
+    pub fn set_vertical(&mut self, vertical: bool) {
+        self.vertical = vertical;
+    }
+
     pub fn add_cluster(&mut self, cluster: &CharCluster) {
         cluster.chars().iter().for_each(|c| {
             let code_point = c.ch as u32;
             unsafe { hb_buffer_add(self.hb_buffer, code_point, self.cluster_count) };
         });
+        self.ranges.push(cluster.range().to_range());
         self.cluster_count += 1;
     }
 
-    pub fn shape(&mut self) -> Vec<Vec<Glyph>> {
+    /// Adds all of `text` as one run, clustered with the first script found
+    /// in it. Meant for short labels, text from a document goes through
+    /// [`ShapeContext::add_cluster`] with its runs already split by script.
+    pub fn add_str(&mut self, text: &str) {
+        let script = text
+            .chars()
+            .map(|ch| ch.script())
+            .find(|script| !matches!(script, Script::Common | Script::Inherited | Script::Unknown))
+            .unwrap_or(Script::Latin);
+        let tokens = text.char_indices().map(|(offset, ch)| Token {
+            ch,
+            offset: offset as u32,
+            len: ch.len_utf8() as u8,
+            info: ch.into(),
+            data: 0,
+        });
+        let mut parser = Parser::new(script, tokens);
+        let mut cluster = CharCluster::new();
+        while parser.next(&mut cluster) {
+            self.add_cluster(&cluster);
+        }
+    }
+
+    /// Sets the script, language and direction of the text in the buffer.
+    /// These are cleared by [`ShapeContext::reset`]; any left unset are
+    /// guessed from the buffer contents when shaping.
+    pub fn set_segment_properties(&mut self, script: Script, language: Option<&str>, direction: Direction) {
+        // opentype script tags are mostly the lowercased iso 15924 tags,
+        // harfbuzz takes care of the case
+        let script = unsafe { hb_script_from_iso15924_tag(script.to_opentype()) };
+        let language = match language {
+            Some(language) => unsafe {
+                hb_language_from_string(language.as_ptr() as *const _, language.len() as i32)
+            },
+            None => unsafe { hb_language_get_default() },
+        };
+        let direction = match direction {
+            Direction::LeftToRight => HB_DIRECTION_LTR,
+            Direction::RightToLeft => HB_DIRECTION_RTL,
+        };
+        unsafe {
+            hb_buffer_set_script(self.hb_buffer, script);
+            hb_buffer_set_language(self.hb_buffer, language);
+            hb_buffer_set_direction(self.hb_buffer, direction);
+        }
+    }
+
+    /// Shapes the clusters added since the last reset, returning the glyphs
+    /// of each cluster. Returns `None` if harfbuzz produced a glyph for a
+    /// cluster that wasn't added.
+    pub fn shape(&mut self) -> Option<Vec<Vec<Glyph>>> {
         let mut res = Vec::with_capacity(self.cluster_count as usize);
         for _ in 0..(self.cluster_count) {
             res.push(Vec::with_capacity(1));
         }
-        let scale = 32.;
+        let scale = self.size;
         let mut x_scale: i32 = 0;
         let mut y_scale: i32 = 0;
         unsafe {
             hb_font_get_scale(self.font.hb_font, &mut x_scale, &mut y_scale);
+            if self.vertical {
+                hb_buffer_set_direction(self.hb_buffer, HB_DIRECTION_TTB);
+            }
             hb_buffer_guess_segment_properties(self.hb_buffer);
-            hb_shape(self.font.hb_font, self.hb_buffer, null(), 0);
+            hb_shape(
+                self.font.hb_font,
+                self.hb_buffer,
+                self.features.as_ptr(),
+                self.features.len() as u32,
+            );
             let len = hb_buffer_get_length(self.hb_buffer) as usize;
             let info = hb_buffer_get_glyph_infos(self.hb_buffer, null_mut());
             let pos = hb_buffer_get_glyph_positions(self.hb_buffer, null_mut());
@@ -249,12 +663,18 @@ impl<'a> ShapeContext<'a> {
                 let codepoint = info.codepoint;
                 let cluster = info.cluster;
                 if cluster >= self.cluster_count {
-                    panic!("more clusters than prepared for");
+                    return None;
                 }
                 let pos = *pos.add(offset);
                 let x = pos.x_offset as f32 / x_scale as f32 * scale;
-                let y = pos.x_offset as f32 / y_scale as f32 * scale;
-                let advance = pos.x_advance as f32 / x_scale as f32 * scale;
+                let y = pos.y_offset as f32 / y_scale as f32 * scale;
+                // vertical advances point down, which is negative in
+                // harfbuzz's y up coordinates
+                let advance = if self.vertical {
+                    -pos.y_advance as f32 / y_scale as f32 * scale
+                } else {
+                    pos.x_advance as f32 / x_scale as f32 * scale
+                };
                 let g = Glyph {
                     id: codepoint as GlyphId,
                     x,
@@ -266,7 +686,24 @@ impl<'a> ShapeContext<'a> {
                 res[cluster as usize].push(g);
             }
         }
-        res
+        Some(res)
+    }
+
+    /// Shapes like [`ShapeContext::shape`], pairing the glyphs of each
+    /// cluster with its source range, to see how the text was mapped to
+    /// glyphs.
+    pub fn shape_clusters(&mut self) -> Option<Vec<ShapedCluster>> {
+        let shaped = self.shape()?;
+        Some(
+            shaped
+                .into_iter()
+                .zip(&self.ranges)
+                .map(|(glyphs, range)| ShapedCluster {
+                    source_range: range.clone(),
+                    glyphs,
+                })
+                .collect(),
+        )
     }
 
     pub fn reset(&mut self) {
@@ -275,6 +712,7 @@ impl<'a> ShapeContext<'a> {
             hb_buffer_set_content_type(self.hb_buffer, HB_BUFFER_CONTENT_TYPE_UNICODE);
         }
         self.cluster_count = 0;
+        self.ranges.clear();
     }
 }
 
@@ -283,3 +721,93 @@ impl<'a> Drop for ShapeContext<'a> {
         unsafe { hb_buffer_destroy(self.hb_buffer) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shapes `text` with `font` and `features`, returning the glyph ids.
+    fn glyph_ids(font: &Font, text: &str, features: &[(Tag, u32)]) -> Vec<GlyphId> {
+        let mut shaper = ShapeContext::new(font, 16.);
+        shaper.set_features(features);
+        shaper.add_str(text);
+        shaper.shape().unwrap().into_iter().flatten().map(|g| g.id).collect()
+    }
+
+    #[test]
+    fn ids_resolve_to_their_fonts() {
+        let mut source = FontSource::new();
+        let names = ["DejaVuSans.ttf", "InterVariable.ttf"];
+        let ids = names.map(|name| source.load_fixture(name));
+        assert_ne!(ids[0], ids[1]);
+        for (id, name) in ids.into_iter().zip(names) {
+            assert_eq!(source.get_font(id).id(), id);
+            let path = format!("{}/tests/fixtures/fonts/{name}", env!("CARGO_MANIFEST_DIR"));
+            let len = std::fs::metadata(path).unwrap().len() as usize;
+            assert_eq!(source.get_fontref(id).data.len(), len, "{name}");
+        }
+    }
+
+    #[test]
+    fn weight_changes_advances() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("InterVariable.ttf");
+        let font = source.get_font_mut(id);
+        let glyph_id = font.fontref().charmap().map('m') as u32;
+        let wght = swash::tag_from_bytes(b"wght");
+        font.set_variations(&[(wght, 100.)]);
+        let thin = font.glyph_advance(glyph_id, 16.);
+        font.set_variations(&[(wght, 900.)]);
+        let black = font.glyph_advance(glyph_id, 16.);
+        assert!(thin > 0.);
+        assert!(black > thin, "{black} <= {thin}");
+    }
+
+    #[test]
+    fn named_instance_changes_advances() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("InterVariable.ttf");
+        let font = source.get_font_mut(id);
+        let instances = font.named_instances();
+        assert!(instances.iter().any(|name| name == "Thin"), "{instances:?}");
+        let glyph_id = font.fontref().charmap().map('m') as u32;
+        assert!(font.set_named_instance("Thin"));
+        let thin = font.glyph_advance(glyph_id, 16.);
+        assert!(font.set_named_instance("Black"));
+        let black = font.glyph_advance(glyph_id, 16.);
+        assert!(black > thin, "{black} <= {thin}");
+        assert!(!font.set_named_instance("Wide"));
+    }
+
+    #[test]
+    fn calt_can_be_turned_off() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("InterVariable.ttf");
+        let font = source.get_font(id);
+        let fontref = font.fontref();
+        let plain = ['-', '>'].map(|ch| fontref.charmap().map(ch));
+        // Inter joins the two into an arrow by default
+        assert_ne!(glyph_ids(font, "->", &[]), plain);
+        let calt = swash::tag_from_bytes(b"calt");
+        assert_eq!(glyph_ids(font, "->", &[(calt, 0)]), plain);
+    }
+
+    #[test]
+    fn ligature_maps_both_chars_to_one_glyph() {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSans.ttf");
+        let font = source.get_font(id);
+        let mut shaper = ShapeContext::new(font, 16.);
+        shaper.add_str("fi");
+        let clusters = shaper.shape_clusters().unwrap();
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].source("fi"), "f");
+        assert_eq!(clusters[1].source("fi"), "i");
+        // the ligature is in the first cluster and the second is empty
+        assert_eq!(clusters[0].glyphs.len(), 1);
+        assert!(clusters[1].glyphs.is_empty());
+        let charmap = font.fontref().charmap();
+        assert_ne!(clusters[0].glyphs[0].id, charmap.map('f'));
+        assert_ne!(clusters[0].glyphs[0].id, 0);
+    }
+}
@@ -1,25 +1,30 @@
-use guillotiere::{size2, SimpleAtlasAllocator};
+use guillotiere::{size2, AllocId, AtlasAllocator};
 use wgpu::{
     BindGroup, Extent3d, Queue, Texture, TextureDescriptor, TextureDimension, TextureFormat,
     TextureUsages, TextureView,
 };
 
-use super::wgpu_context::WgpuContext;
+use super::{image_cache::COLOR_FORMAT, wgpu_context::Renderer};
 
 pub struct Atlas {
-    allocator: SimpleAtlasAllocator,
+    allocator: AtlasAllocator,
     extent: Extent3d,
     format: TextureFormat,
     texture: Texture,
     view: TextureView,
     pub bind_group: BindGroup,
     block_size: u32,
+    /// Transparent pixels kept around each allocation, so that filtering
+    /// doesn't pick up the edges of neighboring images.
+    padding: u32,
     buffer: Vec<u8>,
     dirty: bool,
 }
 
 impl Atlas {
-    pub fn new(wgpu: &WgpuContext, max_size: u32, format: TextureFormat) -> Atlas {
+    /// Creates an atlas of `max_size` pixels square, leaving `padding`
+    /// transparent pixels around each allocation.
+    pub fn new(wgpu: &Renderer, max_size: u32, format: TextureFormat, padding: u32) -> Atlas {
         let block_size = format.block_size(None).expect("Unsupported texture format");
         let buffer = vec![0u8; (max_size * max_size * block_size) as usize];
         let extent = Extent3d {
@@ -38,6 +43,13 @@ impl Atlas {
             view_formats: &[],
         });
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // color images are filtered, masks are drawn at 1:1 and sampled
+        // as is to keep subpixel text crisp
+        let sampler = if format == COLOR_FORMAT {
+            &wgpu.linear_texture_sampler
+        } else {
+            &wgpu.texture_sampler
+        };
         let bind_group = wgpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &wgpu.texture_bind_group_layout,
             entries: &[
@@ -47,27 +59,45 @@ impl Atlas {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&wgpu.texture_sampler),
+                    resource: wgpu::BindingResource::Sampler(sampler),
                 },
             ],
             label: Some("altas_texture_bind_group"),
         });
         Atlas {
-            allocator: SimpleAtlasAllocator::new(size2(max_size as i32, max_size as i32)),
+            allocator: AtlasAllocator::new(size2(max_size as i32, max_size as i32)),
             extent,
             format,
             texture,
             view,
             bind_group,
             block_size,
+            padding,
             buffer,
             dirty: true,
         }
     }
 
-    pub fn update_texture(&mut self, queue: &Queue) {
+    pub fn format(&self) -> TextureFormat {
+        self.format
+    }
+
+    /// Returns the width and height of the atlas, which is square.
+    pub fn size(&self) -> u32 {
+        self.extent.width
+    }
+
+    /// Returns the size of the atlas's pixel data in bytes. The texture
+    /// takes up the same amount of GPU memory.
+    pub fn memory_usage(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Uploads the atlas's pixel data if it changed since the last upload.
+    /// Returns true if it was uploaded.
+    pub fn update_texture(&mut self, queue: &Queue) -> bool {
         if !self.dirty || self.allocator.is_empty() {
-            return;
+            return false;
         }
         queue.write_texture(
             wgpu::ImageCopyTexture {
@@ -85,23 +115,119 @@ impl Atlas {
             self.extent,
         );
         self.dirty = false;
+        true
     }
 
-    pub fn allocate(&mut self, width: u32, height: u32, data: &[u8]) -> Option<(u32, u32)> {
-        let (x, y) = self
+    /// Copies an image of `width` by `height` pixels into the atlas,
+    /// surrounded by cleared padding. Returns the allocation and the
+    /// position of the image, excluding the padding.
+    pub fn allocate(
+        &mut self,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) -> Option<(AllocId, u32, u32)> {
+        let padded_width = width + self.padding * 2;
+        let padded_height = height + self.padding * 2;
+        let alloc = self
             .allocator
-            .allocate(size2(width as i32, height as i32))
-            .map(|rect| (rect.min.x as u32, rect.min.y as u32))?;
+            .allocate(size2(padded_width as i32, padded_height as i32))?;
+        let id = alloc.id;
+        let x = alloc.rectangle.min.x as u32;
+        let y = alloc.rectangle.min.y as u32;
+        self.clear_rect(x, y, padded_width, padded_height);
+        let x = x + self.padding;
+        let y = y + self.padding;
         let channels = self.block_size as usize;
         let data_stride = width as usize * channels;
         let buffer_stride = self.extent.width as usize * channels;
         let mut offset = y as usize * buffer_stride + x as usize * channels;
         for row in data.chunks(data_stride) {
-            let dest = self.buffer.get_mut(offset..offset + data_stride)?;
+            let Some(dest) = self.buffer.get_mut(offset..offset + data_stride) else {
+                log::error!("Image data doesn't fit a {width}x{height} allocation");
+                self.deallocate(id, x, y, width, height);
+                return None;
+            };
             dest.copy_from_slice(row);
             offset += buffer_stride;
         }
         self.dirty = true;
-        Some((x, y))
+        Some((id, x, y))
+    }
+
+    /// Frees the allocation `id` of `width` by `height` pixels at `x`, `y`,
+    /// as returned by [`Atlas::allocate`], clearing its pixels so they
+    /// don't show up in a later allocation.
+    pub fn deallocate(&mut self, id: AllocId, x: u32, y: u32, width: u32, height: u32) {
+        self.allocator.deallocate(id);
+        self.clear_rect(
+            x - self.padding,
+            y - self.padding,
+            width + self.padding * 2,
+            height + self.padding * 2,
+        );
+    }
+
+    fn clear_rect(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        let channels = self.block_size as usize;
+        let row_len = width as usize * channels;
+        let buffer_stride = self.extent.width as usize * channels;
+        let mut offset = y as usize * buffer_stride + x as usize * channels;
+        for _ in 0..height {
+            if let Some(row) = self.buffer.get_mut(offset..offset + row_len) {
+                row.fill(0);
+            }
+            offset += buffer_stride;
+        }
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::{
+        color,
+        image_cache::MASK_FORMAT,
+        wgpu_context::{DeviceOptions, WgpuContext},
+    };
+
+    fn atlas() -> Atlas {
+        let context = WgpuContext::new_headless(1, 1, 1., color::WHITE, &DeviceOptions::default());
+        Atlas::new(context.renderer(), 64, MASK_FORMAT, 1)
+    }
+
+    /// Returns the first byte of the pixel at `(x, y)`.
+    fn pixel(atlas: &Atlas, x: u32, y: u32) -> u8 {
+        atlas.buffer[((y * atlas.extent.width + x) * atlas.block_size) as usize]
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn allocations_are_separated_by_cleared_padding() {
+        let mut atlas = atlas();
+        let data = vec![255; (4 * 4 * atlas.block_size) as usize];
+        let allocations = [(); 2].map(|_| atlas.allocate(4, 4, &data).unwrap());
+        for (_, x, y) in allocations {
+            assert_eq!(pixel(&atlas, x, y), 255);
+            assert_eq!(pixel(&atlas, x + 3, y + 3), 255);
+            // the padding on every side of the image is clear
+            for i in 0..4 {
+                assert_eq!(pixel(&atlas, x - 1, y + i), 0);
+                assert_eq!(pixel(&atlas, x + 4, y + i), 0);
+                assert_eq!(pixel(&atlas, x + i, y - 1), 0);
+                assert_eq!(pixel(&atlas, x + i, y + 4), 0);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn data_past_the_atlas_frees_the_allocation() {
+        let mut atlas = atlas();
+        // more rows than the atlas has
+        let data = vec![255; (4 * 100 * atlas.block_size) as usize];
+        assert!(atlas.allocate(4, 4, &data).is_none());
+        assert!(atlas.allocator.is_empty());
     }
 }
@@ -176,31 +176,51 @@ impl Color {
     /// Creates a new color from a CSS style color definition.
     pub fn parse(s: &str) -> Option<Self> {
         let s = s.trim();
-        if s.starts_with("#") {
-            let s = &s.as_bytes()[1..];
-            let mut bytes = [0u8, 0, 0, 255];
-            match s.len() {
-                // RGB | RGBA
-                3 | 4 => {
-                    for (i, b) in s.chunks(1).enumerate() {
-                        let v = core::str::from_utf8(b).ok()?;
-                        let v = u8::from_str_radix(v, 16).ok()?;
-                        bytes[i] = v * 16 + v;
-                    }
-                    return Some(bytes.into());
+        if s.starts_with('#') {
+            return Self::from_hex(s).ok();
+        }
+        Self::from_name(s)
+    }
+
+    /// Creates a new color from a hex string of 3 (RGB), 4 (RGBA), 6
+    /// (RRGGBB) or 8 (RRGGBBAA) digits, with an optional leading `#`.
+    pub fn from_hex(s: &str) -> Result<Self, ColorParseError> {
+        let s = s.strip_prefix('#').unwrap_or(s);
+        let digits = s
+            .chars()
+            .map(|c| {
+                c.to_digit(16)
+                    .map(|d| d as u8)
+                    .ok_or(ColorParseError::InvalidDigit(c))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut bytes = [0u8, 0, 0, 255];
+        match digits.len() {
+            // RGB | RGBA
+            3 | 4 => {
+                for (i, v) in digits.iter().enumerate() {
+                    bytes[i] = v * 16 + v;
                 }
-                // RRGGBB | RRGGBBAA
-                6 | 8 => {
-                    for (i, b) in s.chunks(2).enumerate() {
-                        let v = core::str::from_utf8(b).ok()?;
-                        bytes[i] = u8::from_str_radix(v, 16).ok()?;
-                    }
-                    return Some(bytes.into());
+            }
+            // RRGGBB | RRGGBBAA
+            6 | 8 => {
+                for (i, v) in digits.chunks(2).enumerate() {
+                    bytes[i] = v[0] * 16 + v[1];
                 }
-                _ => return None,
             }
+            len => return Err(ColorParseError::InvalidLength(len)),
         }
-        Self::from_name(s)
+        Ok(bytes.into())
+    }
+
+    /// Creates a new color from a packed `0xRRGGBBAA` value.
+    pub const fn from_u32(v: u32) -> Self {
+        Self::new((v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8)
+    }
+
+    /// Formats the color as `#rrggbbaa`.
+    pub fn to_hex_string(self) -> String {
+        format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
     }
 
     /// Converts the color to an array of bytes in RGBA order.
@@ -248,6 +268,46 @@ impl Color {
         }
     }
 
+    /// Linearly interpolates between this color and `other` by `t`, which
+    /// is clamped to `0..=1`.
+    pub fn lerp(self, other: Self, t: f32) -> Self {
+        let t = t.clamp(0., 1.);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Self {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+            a: mix(self.a, other.a),
+        }
+    }
+
+    /// Returns this color with its alpha replaced by `a`.
+    pub const fn with_alpha(self, a: u8) -> Self {
+        Self { a, ..self }
+    }
+
+    /// Composites this color over `background` using straight (not
+    /// premultiplied) source-over blending.
+    pub fn over(self, background: Self) -> Self {
+        let sa = self.a as f32 / 255.;
+        let da = background.a as f32 / 255.;
+        let a = sa + da * (1. - sa);
+        if a <= 0. {
+            return Self::new(0, 0, 0, 0);
+        }
+        let mix = |s: u8, d: u8| {
+            ((s as f32 * sa + d as f32 * da * (1. - sa)) / a)
+                .round()
+                .clamp(0., 255.) as u8
+        };
+        Self {
+            r: mix(self.r, background.r),
+            g: mix(self.g, background.g),
+            b: mix(self.b, background.b),
+            a: (a * 255.).round() as u8,
+        }
+    }
+
     /// Converts the byte color into a floating point representation.
     pub fn to_rgba_f32(self) -> [f32; 4] {
         let s = 1. / 255.;
@@ -311,6 +371,28 @@ impl From<[u8; 3]> for Color {
     }
 }
 
+/// Error returned by [`Color::from_hex`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorParseError {
+    /// The string didn't have 3, 4, 6 or 8 hex digits.
+    InvalidLength(usize),
+    /// The string contained a character that isn't a hex digit.
+    InvalidDigit(char),
+}
+
+impl core::fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ColorParseError::InvalidLength(len) => {
+                write!(f, "expected 3, 4, 6 or 8 hex digits, found {}", len)
+            }
+            ColorParseError::InvalidDigit(c) => write!(f, "invalid hex digit {:?}", c),
+        }
+    }
+}
+
+impl std::error::Error for ColorParseError {}
+
 /// Alice blue (240, 248, 255, 255)
 pub const ALICE_BLUE: Color = Color::new(240, 248, 255, 255);
 /// Antique white (250, 235, 215, 255)
@@ -655,3 +737,82 @@ const LINEAR_RGB_TO_SRGB_TABLE: &[u8; 256] = &[
     246, 246, 247, 247, 248, 248, 249, 249, 250, 250, 251, 251, 251, 252, 252, 253, 253, 254, 254,
     255, 255,
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_hex_digit_counts() {
+        assert_eq!(
+            Color::from_hex("f80"),
+            Ok(Color::new(0xff, 0x88, 0x00, 0xff))
+        );
+        assert_eq!(
+            Color::from_hex("f80c"),
+            Ok(Color::new(0xff, 0x88, 0x00, 0xcc))
+        );
+        assert_eq!(
+            Color::from_hex("12ab9f"),
+            Ok(Color::new(0x12, 0xab, 0x9f, 0xff))
+        );
+        assert_eq!(
+            Color::from_hex("12ab9f40"),
+            Ok(Color::new(0x12, 0xab, 0x9f, 0x40))
+        );
+    }
+
+    #[test]
+    fn from_hex_optional_hash() {
+        assert_eq!(Color::from_hex("#12AB9F"), Color::from_hex("12ab9f"));
+        assert_eq!(Color::from_hex("#fff"), Ok(WHITE));
+    }
+
+    #[test]
+    fn from_hex_errors() {
+        assert_eq!(
+            Color::from_hex("#12ag9f"),
+            Err(ColorParseError::InvalidDigit('g'))
+        );
+        assert_eq!(
+            Color::from_hex("##fff"),
+            Err(ColorParseError::InvalidDigit('#'))
+        );
+        assert_eq!(Color::from_hex(""), Err(ColorParseError::InvalidLength(0)));
+        assert_eq!(
+            Color::from_hex("#12345"),
+            Err(ColorParseError::InvalidLength(5))
+        );
+        assert_eq!(
+            Color::from_hex("123456789"),
+            Err(ColorParseError::InvalidLength(9))
+        );
+    }
+
+    #[test]
+    fn lerp_endpoints_and_clamping() {
+        assert_eq!(BLACK.lerp(WHITE, 0.), BLACK);
+        assert_eq!(BLACK.lerp(WHITE, 1.), WHITE);
+        assert_eq!(BLACK.lerp(WHITE, 0.5), Color::new(128, 128, 128, 255));
+        assert_eq!(BLACK.lerp(WHITE, -1.), BLACK);
+        assert_eq!(BLACK.lerp(WHITE, 2.), WHITE);
+        assert_eq!(RED.lerp(TRANSPARENT, 0.5), Color::new(128, 0, 0, 128));
+    }
+
+    #[test]
+    fn opaque_over_anything() {
+        for background in [BLACK, WHITE, TRANSPARENT, BLUE.with_alpha(100)] {
+            assert_eq!(RED.over(background), RED);
+        }
+    }
+
+    #[test]
+    fn transparent_over() {
+        assert_eq!(TRANSPARENT.over(TRANSPARENT), Color::new(0, 0, 0, 0));
+        assert_eq!(
+            WHITE.with_alpha(0).over(TRANSPARENT),
+            Color::new(0, 0, 0, 0)
+        );
+        assert_eq!(TRANSPARENT.over(BLUE), BLUE);
+    }
+}
@@ -1,12 +1,20 @@
+use std::ops::Range;
+
+use crate::fonts::{Font, ShapeContext};
+
 use super::{
-    color::Color,
-    image_cache::TextureLocation,
-    types::{Rect, Vertex},
+    color::{self, Color},
+    glyph_cache::{GlyphCache, GlyphRenderOptions},
+    image_cache::{ImageCache, TextureLocation, COLOR_FORMAT},
+    types::{DrawTransform, Rect, Vertex},
+    wgpu_context::Renderer,
 };
 
 #[derive(Default)]
 struct Batch {
     atlas_index: Option<usize>,
+    clip: Option<Rect>,
+    transform: DrawTransform,
     vertices: Vec<Vertex>,
     indices: Vec<u32>,
 }
@@ -16,6 +24,8 @@ impl Batch {
         self.vertices.clear();
         self.indices.clear();
         self.atlas_index = None;
+        self.clip = None;
+        self.transform = DrawTransform::IDENTITY;
     }
 
     fn add_rect(
@@ -41,25 +51,128 @@ impl Batch {
                 pos: [x, y, depth, flags],
                 color,
                 uv: [l, t],
+                rect: [0.; 3],
+                end_color: color,
             },
             Vertex {
                 pos: [x, y + h, depth, flags],
                 color,
                 uv: [l, b],
+                rect: [0.; 3],
+                end_color: color,
             },
             Vertex {
                 pos: [x + w, y + h, depth, flags],
                 color,
                 uv: [r, b],
+                rect: [0.; 3],
+                end_color: color,
             },
             Vertex {
                 pos: [x + w, y, depth, flags],
                 color,
                 uv: [r, t],
+                rect: [0.; 3],
+                end_color: color,
+            },
+        ];
+        self.push_quad(&verts);
+    }
+
+    /// Adds a rect masked by its distance to the edge, with corners rounded
+    /// to `radius`. The quad is grown by `outset` on every side so pixels
+    /// partly covered by the edge are drawn too.
+    fn add_round_rect(&mut self, rect: Rect, depth: f32, color: Color, radius: f32, outset: f32) {
+        let hw = rect.width * 0.5;
+        let hh = rect.height * 0.5;
+        let extent = [hw, hh, radius.min(hw).min(hh)];
+        let x = rect.x - outset;
+        let y = rect.y - outset;
+        let w = rect.width + outset * 2.;
+        let h = rect.height + outset * 2.;
+        let hw = hw + outset;
+        let hh = hh + outset;
+        // flag 2 has the fragment shader mask the corners using the
+        // distance from the center passed in uv
+        let verts = [
+            Vertex {
+                pos: [x, y, depth, 2.],
+                color,
+                uv: [-hw, -hh],
+                rect: extent,
+                end_color: color,
+            },
+            Vertex {
+                pos: [x, y + h, depth, 2.],
+                color,
+                uv: [-hw, hh],
+                rect: extent,
+                end_color: color,
+            },
+            Vertex {
+                pos: [x + w, y + h, depth, 2.],
+                color,
+                uv: [hw, hh],
+                rect: extent,
+                end_color: color,
+            },
+            Vertex {
+                pos: [x + w, y, depth, 2.],
+                color,
+                uv: [hw, -hh],
+                rect: extent,
+                end_color: color,
             },
         ];
+        self.push_quad(&verts);
+    }
+
+    /// Adds a rect with the given colors at its top left, bottom left,
+    /// bottom right and top right corners, blended across the rect.
+    fn add_gradient_rect(&mut self, rect: Rect, depth: f32, colors: [Color; 4]) {
+        let x = rect.x;
+        let y = rect.y;
+        let w = rect.width;
+        let h = rect.height;
+        let corners = [[x, y], [x, y + h], [x + w, y + h], [x + w, y]];
+        let verts = [0, 1, 2, 3].map(|i| Vertex {
+            pos: [corners[i][0], corners[i][1], depth, 0.],
+            color: colors[i],
+            uv: [0., 0.],
+            rect: [0.; 3],
+            end_color: colors[i],
+        });
+        self.push_quad(&verts);
+    }
+
+    fn add_radial_gradient(
+        &mut self,
+        rect: Rect,
+        depth: f32,
+        inner_color: Color,
+        outer_color: Color,
+    ) {
+        let x = rect.x;
+        let y = rect.y;
+        let w = rect.width;
+        let h = rect.height;
+        // flag 3 has the fragment shader blend from the color to the end
+        // color by the distance from the center, with uv spanning -1..1
+        let corners = [[x, y], [x, y + h], [x + w, y + h], [x + w, y]];
+        let uvs = [[-1., -1.], [-1., 1.], [1., 1.], [1., -1.]];
+        let verts = [0, 1, 2, 3].map(|i| Vertex {
+            pos: [corners[i][0], corners[i][1], depth, 3.],
+            color: inner_color,
+            uv: uvs[i],
+            rect: [0.; 3],
+            end_color: outer_color,
+        });
+        self.push_quad(&verts);
+    }
+
+    fn push_quad(&mut self, verts: &[Vertex; 4]) {
         let base = self.vertices.len() as u32;
-        self.vertices.extend_from_slice(&verts);
+        self.vertices.extend_from_slice(verts);
         self.indices.extend_from_slice(&[
             base, //
             base + 1,
@@ -70,36 +183,156 @@ impl Batch {
         ]);
     }
 
-    fn build_display_list(&self, list: &mut DisplayList) {
+    /// Applies the affine `transform` to the positions of the last quad
+    /// added to the batch.
+    fn transform_last_quad(&mut self, transform: &Transform) {
+        let start = self.vertices.len() - 4;
+        for vertex in &mut self.vertices[start..] {
+            let [x, y, ..] = vertex.pos;
+            vertex.pos[0] = transform[0][0] * x + transform[0][1] * y + transform[0][2];
+            vertex.pos[1] = transform[1][0] * x + transform[1][1] * y + transform[1][2];
+        }
+    }
+
+    /// Reorders the quads of the batch from back to front, i.e. by
+    /// decreasing depth. Quads at the same depth keep their order.
+    fn sort_back_to_front(&mut self) {
+        let mut quads = self
+            .vertices
+            .chunks_exact(4)
+            .map(|quad| [quad[0], quad[1], quad[2], quad[3]])
+            .collect::<Vec<_>>();
+        quads.sort_by(|a, b| b[0].pos[2].total_cmp(&a[0].pos[2]));
+        self.vertices.clear();
+        self.indices.clear();
+        for quad in &quads {
+            self.push_quad(quad);
+        }
+    }
+
+    /// Returns the depth of the quad at `index`.
+    fn quad_depth(&self, index: usize) -> f32 {
+        self.vertices[index * 4].pos[2]
+    }
+
+    /// Returns the number of quads in the batch.
+    fn quad_count(&self) -> usize {
+        self.vertices.len() / 4
+    }
+
+    /// Appends the batch to `list`, changing the scissor rectangle and draw
+    /// transform from `state` if the batch's differ.
+    fn build_display_list(&self, list: &mut DisplayList, state: &mut ListState) {
+        self.build_quads(0..self.quad_count(), list, state);
+    }
+
+    /// Appends the quads in `quads` to `list` as a single draw, changing
+    /// the scissor rectangle and draw transform from `state` if the batch's
+    /// differ.
+    fn build_quads(&self, quads: Range<usize>, list: &mut DisplayList, state: &mut ListState) {
+        if self.clip != state.scissor {
+            list.commands.push(match self.clip {
+                Some(clip) => Command::SetScissor(clip),
+                None => Command::ResetScissor,
+            });
+            state.scissor = self.clip;
+        }
+        if self.transform != state.transform {
+            list.commands.push(Command::SetTransform(self.transform));
+            state.transform = self.transform;
+        }
         let first_vertex = list.vertices.len() as u32;
         let first_index = list.indices.len() as u32;
-        list.vertices.extend_from_slice(&self.vertices);
+        let vertex_offset = quads.start as u32 * 4;
+        let indices = &self.indices[quads.start * 6..quads.end * 6];
+        list.vertices
+            .extend_from_slice(&self.vertices[quads.start * 4..quads.end * 4]);
         list.indices
-            .extend(self.indices.iter().map(|i| *i + first_vertex));
+            .extend(indices.iter().map(|i| *i - vertex_offset + first_vertex));
         if let Some(atlas_index) = self.atlas_index {
             list.commands.push(Command::BindTexture(atlas_index));
         }
         list.commands.push(Command::Draw {
             start: first_index,
-            count: self.indices.len() as u32,
+            count: indices.len() as u32,
         });
     }
 }
 
+/// Scissor rectangle and draw transform in effect while building a display
+/// list.
+#[derive(Default)]
+struct ListState {
+    scissor: Option<Rect>,
+    transform: DrawTransform,
+}
+
+/// Appends the quads of `batches` to `list` from back to front across all
+/// of the batches, with one draw for each stretch of quads from the same
+/// batch. The quads of each batch must already be sorted back to front.
+fn build_sorted_display_list(batches: &[Batch], list: &mut DisplayList, state: &mut ListState) {
+    let mut quads = batches
+        .iter()
+        .enumerate()
+        .flat_map(|(b, batch)| (0..batch.quad_count()).map(move |q| (b, q)))
+        .collect::<Vec<_>>();
+    // stable, so quads at the same depth stay in batch order
+    quads.sort_by(|&(a, qa), &(b, qb)| {
+        batches[b]
+            .quad_depth(qb)
+            .total_cmp(&batches[a].quad_depth(qa))
+    });
+    let mut start = 0;
+    while start < quads.len() {
+        let (b, first) = quads[start];
+        let mut end = start + 1;
+        while end < quads.len() && quads[end] == (b, first + end - start) {
+            end += 1;
+        }
+        batches[b].build_quads(first..first + end - start, list, state);
+        start = end;
+    }
+}
+
+/// 2D affine transform as a row major 3x3 matrix. A point `(x, y)` maps to
+/// `(m[0][0] * x + m[0][1] * y + m[0][2], m[1][0] * x + m[1][1] * y + m[1][2])`;
+/// the last row is ignored.
+pub type Transform = [[f32; 3]; 3];
+
 #[derive(Clone, Copy)]
 enum BatchType {
     Opaque,
     Transparent,
     Subpixel,
+    Grayscale,
 }
 
+/// Batches primitives into a [`DisplayList`] for a frame.
+///
+/// Each frame starts with [`Compositor::begin`], followed by the draw calls,
+/// and ends with [`Compositor::end`], which returns the display list. The
+/// next frame can then begin.
 pub struct Compositor {
     empty_batches: Vec<Batch>,
     opaque_batches: Vec<Batch>,
     transparent_batches: Vec<Batch>,
     subpixel_batches: Vec<Batch>,
+    grayscale_batches: Vec<Batch>,
+    clips: Vec<Rect>,
+    transform: Option<Transform>,
+    /// Transform applied on the GPU to the rects added, see
+    /// [`Compositor::set_draw_transform`].
+    draw_transform: DrawTransform,
+    /// Number of depths handed out by [`Compositor::next_depth`].
+    depth_count: u32,
+    /// True between [`Compositor::begin`] and [`Compositor::end`].
+    in_frame: bool,
 }
 
+/// Distance between the depths handed out by [`Compositor::next_depth`],
+/// leaving room for about a million primitives per frame.
+const DEPTH_STEP: f32 = 1. / (1 << 20) as f32;
+
 impl Compositor {
     pub fn new() -> Compositor {
         Compositor {
@@ -107,15 +340,88 @@ impl Compositor {
             opaque_batches: Vec::new(),
             transparent_batches: Vec::new(),
             subpixel_batches: Vec::new(),
+            grayscale_batches: Vec::new(),
+            clips: Vec::new(),
+            transform: None,
+            draw_transform: DrawTransform::IDENTITY,
+            depth_count: 0,
+            in_frame: false,
         }
     }
 
+    /// Starts a new frame, recycling the batches of the previous one.
     pub fn begin(&mut self) {
+        debug_assert!(
+            !self.in_frame,
+            "Compositor::begin called twice without Compositor::end"
+        );
+        self.in_frame = true;
         self.empty_batches.append(&mut self.opaque_batches);
         self.empty_batches.append(&mut self.transparent_batches);
+        self.empty_batches.append(&mut self.subpixel_batches);
+        self.empty_batches.append(&mut self.grayscale_batches);
         self.empty_batches
             .iter_mut()
             .for_each(|batch| batch.clear());
+        self.clips.clear();
+        self.transform = None;
+        self.draw_transform = DrawTransform::IDENTITY;
+        self.depth_count = 0;
+    }
+
+    /// Returns a depth in front of all the depths previously returned since
+    /// [`Compositor::begin`], so that primitives drawn with it stack in
+    /// submission order.
+    ///
+    /// Depths count down from just below 1, so they are behind anything
+    /// drawn with a small explicit depth. Opaque primitives are drawn before
+    /// transparent ones, and the depth test hides the parts of a transparent
+    /// primitive behind an opaque one submitted after it. Transparent
+    /// primitives are sorted back to front by [`Compositor::end`], so they
+    /// blend with each other in submission order. Subpixel text ignores the
+    /// depth of its vertices.
+    pub fn next_depth(&mut self) -> f32 {
+        self.depth_count += 1;
+        (1. - self.depth_count as f32 * DEPTH_STEP).max(0.)
+    }
+
+    /// Sets the transform applied to the rects added after this call, e.g.
+    /// to zoom or pan, or `None` to draw them untransformed. Clip rects are
+    /// not transformed.
+    pub fn set_transform(&mut self, transform: Option<Transform>) {
+        self.transform = transform;
+    }
+
+    /// Sets the offset and scale applied to the rects added after this call
+    /// on the GPU, before the camera. Unlike [`Compositor::set_transform`]
+    /// the vertices aren't changed, so e.g. a scrolled region only needs a
+    /// new transform rather than new geometry. Clip rects are not
+    /// transformed.
+    pub fn set_draw_transform(&mut self, offset: [f32; 2], scale: f32) {
+        self.draw_transform = DrawTransform::new(offset, scale);
+    }
+
+    /// Clips everything drawn until the matching [`Compositor::pop_clip`]
+    /// to `rect`, intersected with the current clip.
+    ///
+    /// The clip is applied as a scissor rectangle, which has whole pixel
+    /// edges: the edges are rounded outwards, so at fractional scale
+    /// factors up to one extra partially covered pixel is drawn on each
+    /// side.
+    pub fn push_clip(&mut self, rect: impl Into<Rect>) {
+        let rect = rect.into();
+        let clip = match self.clips.last() {
+            // nothing is drawn inside clips that don't overlap
+            Some(current) => current.intersect(&rect).unwrap_or_default(),
+            None => rect,
+        };
+        self.clips.push(clip);
+    }
+
+    /// Restores the clip that was current before the last
+    /// [`Compositor::push_clip`].
+    pub fn pop_clip(&mut self) {
+        self.clips.pop();
     }
 
     pub fn draw_rect(&mut self, rect: impl Into<Rect>, depth: f32, color: Color) {
@@ -124,11 +430,141 @@ impl Compositor {
         } else {
             BatchType::Transparent
         };
+        let transform = self.transform;
         let batch = match self.get_batch(batch_type, None) {
             Some(batch) => batch,
             None => self.allocate_batch(batch_type),
         };
         batch.add_rect(rect.into(), depth, color, None, None);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Draws a rectangle with antialiased edges, for rects at fractional
+    /// pixel positions, which [`Compositor::draw_rect`] draws with hard
+    /// edges. Pixels are covered by how much of them is inside the rect, so
+    /// a rect on whole pixels looks the same as with `draw_rect`. Like
+    /// rounded rects it is always drawn as transparent.
+    pub fn draw_rect_aa(&mut self, rect: impl Into<Rect>, depth: f32, color: Color) {
+        let transform = self.transform;
+        let batch = match self.get_batch(BatchType::Transparent, None) {
+            Some(batch) => batch,
+            None => self.allocate_batch(BatchType::Transparent),
+        };
+        // a rounded rect with no radius, grown by a pixel for the outer
+        // half of the edge
+        batch.add_round_rect(rect.into(), depth, color, 0., 1.);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Draws a rectangle with corners rounded to `corner_radius`. The edges
+    /// are antialiased, so it is always drawn as transparent.
+    pub fn draw_round_rect(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        color: Color,
+        corner_radius: f32,
+    ) {
+        if corner_radius <= 0. {
+            self.draw_rect(rect, depth, color);
+            return;
+        }
+        let transform = self.transform;
+        let batch = match self.get_batch(BatchType::Transparent, None) {
+            Some(batch) => batch,
+            None => self.allocate_batch(BatchType::Transparent),
+        };
+        batch.add_round_rect(rect.into(), depth, color, corner_radius, 0.);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Fills `rect` with a gradient from `start_color` to `end_color` along
+    /// `angle`, in radians clockwise from pointing right. The gradient runs
+    /// between the two corners furthest along the angle, so the colors at
+    /// every point are an affine blend of the corner colors and no shader
+    /// support is needed.
+    pub fn draw_linear_gradient(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        start_color: Color,
+        end_color: Color,
+        angle: f32,
+    ) {
+        let rect = rect.into();
+        let (dy, dx) = angle.sin_cos();
+        let length = (rect.width * dx).abs() + (rect.height * dy).abs();
+        // position of each corner along the gradient, from 0 to 1
+        let t = |cx: f32, cy: f32| {
+            if length > 0. {
+                (cx * dx + cy * dy) / length + 0.5
+            } else {
+                0.
+            }
+        };
+        let hw = rect.width * 0.5;
+        let hh = rect.height * 0.5;
+        let colors = [
+            start_color.lerp(end_color, t(-hw, -hh)),
+            start_color.lerp(end_color, t(-hw, hh)),
+            start_color.lerp(end_color, t(hw, hh)),
+            start_color.lerp(end_color, t(hw, -hh)),
+        ];
+        let transform = self.transform;
+        let batch = match self.get_batch(BatchType::Transparent, None) {
+            Some(batch) => batch,
+            None => self.allocate_batch(BatchType::Transparent),
+        };
+        batch.add_gradient_rect(rect, depth, colors);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Fills `rect` with a gradient from `inner_color` at its center to
+    /// `outer_color` at the edge of the ellipse inscribed in it. The corners
+    /// outside the ellipse are `outer_color`.
+    pub fn draw_radial_gradient(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        inner_color: Color,
+        outer_color: Color,
+    ) {
+        let transform = self.transform;
+        let batch = match self.get_batch(BatchType::Transparent, None) {
+            Some(batch) => batch,
+            None => self.allocate_batch(BatchType::Transparent),
+        };
+        batch.add_radial_gradient(rect.into(), depth, inner_color, outer_color);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Draws a rect in front of everything previously drawn with an
+    /// automatic depth, see [`Compositor::next_depth`].
+    pub fn draw_rect_auto(&mut self, rect: impl Into<Rect>, color: Color) {
+        let depth = self.next_depth();
+        self.draw_rect(rect, depth, color);
+    }
+
+    /// Adds an image rect in front of everything previously drawn with an
+    /// automatic depth, see [`Compositor::next_depth`].
+    pub fn add_image_rect_auto(
+        &mut self,
+        rect: impl Into<Rect>,
+        color: Color,
+        texture_location: TextureLocation,
+    ) {
+        let depth = self.next_depth();
+        self.add_image_rect(rect, depth, color, texture_location);
     }
 
     pub fn add_image_rect(
@@ -139,6 +575,7 @@ impl Compositor {
         texture_location: TextureLocation,
     ) {
         let atlas_index = Some(texture_location.atlas_index);
+        let transform = self.transform;
         let batch = match self.get_batch(BatchType::Transparent, atlas_index) {
             Some(batch) => batch,
             None => self.allocate_batch(BatchType::Transparent),
@@ -150,6 +587,30 @@ impl Compositor {
             texture_location.max.1,
         ];
         batch.add_rect(rect.into(), depth, color, Some(&coords), atlas_index);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Draws the whole of the atlas `atlas_index` into `rect`, in front of
+    /// everything else, to debug image packing and glyph caching. Coverage
+    /// masks are drawn in black.
+    pub fn draw_atlas_debug(
+        &mut self,
+        image_cache: &ImageCache,
+        atlas_index: usize,
+        rect: impl Into<Rect>,
+    ) {
+        let Some(location) = image_cache.get_atlas_location(atlas_index) else {
+            log::error!("Missing atlas at index {}", atlas_index);
+            return;
+        };
+        let color = if location.format == COLOR_FORMAT {
+            color::WHITE
+        } else {
+            color::BLACK
+        };
+        self.add_image_rect(rect, 0., color, location);
     }
 
     pub fn add_subpixel_rect(
@@ -160,6 +621,7 @@ impl Compositor {
         texture_location: TextureLocation,
     ) {
         let atlas_index = Some(texture_location.atlas_index);
+        let transform = self.transform;
         let batch = match self.get_batch(BatchType::Subpixel, atlas_index) {
             Some(batch) => batch,
             None => self.allocate_batch(BatchType::Subpixel),
@@ -171,6 +633,99 @@ impl Compositor {
             texture_location.max.1,
         ];
         batch.add_rect(rect.into(), depth, color, Some(&coords), atlas_index);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Adds a glyph with a grayscale coverage mask, which is tinted with
+    /// `color`.
+    pub fn add_grayscale_rect(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        color: Color,
+        texture_location: TextureLocation,
+    ) {
+        let atlas_index = Some(texture_location.atlas_index);
+        let transform = self.transform;
+        let batch = match self.get_batch(BatchType::Grayscale, atlas_index) {
+            Some(batch) => batch,
+            None => self.allocate_batch(BatchType::Grayscale),
+        };
+        let coords = [
+            texture_location.min.0,
+            texture_location.min.1,
+            texture_location.max.0,
+            texture_location.max.1,
+        ];
+        batch.add_rect(rect.into(), depth, color, Some(&coords), atlas_index);
+        if let Some(transform) = &transform {
+            batch.transform_last_quad(transform);
+        }
+    }
+
+    /// Shapes `text` as a single line with `font` and draws it with its top
+    /// left corner at `origin`, without going through a document. Meant for
+    /// labels and overlays like fps counters. The glyphs get an automatic
+    /// depth, see [`Compositor::next_depth`]. Returns the advance width of
+    /// the line.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_text(
+        &mut self,
+        text: &str,
+        origin: (f32, f32),
+        size: f32,
+        color: Color,
+        font: &Font,
+        glyph_cache: &mut GlyphCache,
+        image_cache: &mut ImageCache,
+        wgpu: &Renderer,
+    ) -> f32 {
+        let mut shaper = ShapeContext::new(font, size);
+        shaper.add_str(text);
+        let Some(clusters) = shaper.shape() else {
+            log::error!("Failed to shape {:?}", text);
+            return 0.;
+        };
+        let depth = self.next_depth();
+        let options = GlyphRenderOptions::default();
+        let bias = options.subpixel_bias;
+        let baseline = origin.1 + font.metrics().scale(size).ascent.round();
+        let mut session = glyph_cache.session(
+            wgpu,
+            image_cache,
+            font.fontref(),
+            size,
+            font.coords(),
+            options,
+        );
+        let mut pen = origin.0;
+        for glyph in clusters.iter().flatten() {
+            let x = pen + glyph.x;
+            let y = baseline - glyph.y;
+            pen += glyph.advance;
+            let Some(entry) = session.get(glyph.id, x, y) else {
+                continue;
+            };
+            let Some(tex_loc) = session.get_texture_location(entry.image_id) else {
+                continue;
+            };
+            let rect = [
+                (x + bias.0).floor() + entry.left as f32,
+                (y + bias.1).floor() - entry.top as f32,
+                entry.width as f32,
+                entry.height as f32,
+            ];
+            if entry.is_bitmap {
+                self.add_image_rect(rect, depth, color::WHITE, tex_loc);
+            } else if entry.is_subpixel {
+                self.add_subpixel_rect(rect, depth, color, tex_loc);
+            } else {
+                self.add_grayscale_rect(rect, depth, color, tex_loc);
+            }
+        }
+        pen - origin.0
     }
 
     fn get_batch(
@@ -178,8 +733,12 @@ impl Compositor {
         batch_type: BatchType,
         atlas_index: Option<usize>,
     ) -> Option<&mut Batch> {
+        let clip = self.clips.last().copied();
+        let transform = self.draw_transform;
         let check_fn = |batch: &&mut Batch| -> bool {
-            if atlas_index.is_some() && batch.atlas_index.is_some() {
+            if batch.clip != clip || batch.transform != transform {
+                false
+            } else if atlas_index.is_some() && batch.atlas_index.is_some() {
                 atlas_index == batch.atlas_index
             } else {
                 true
@@ -188,16 +747,19 @@ impl Compositor {
         match batch_type {
             BatchType::Transparent => self.transparent_batches.iter_mut().find(check_fn),
             BatchType::Subpixel => self.subpixel_batches.iter_mut().find(check_fn),
+            BatchType::Grayscale => self.grayscale_batches.iter_mut().find(check_fn),
             BatchType::Opaque => self.opaque_batches.iter_mut().find(check_fn),
         }
     }
 
     fn allocate_batch(&mut self, batch_type: BatchType) -> &mut Batch {
-        let batch = if let Some(batch) = self.empty_batches.pop() {
+        let mut batch = if let Some(batch) = self.empty_batches.pop() {
             batch
         } else {
             Batch::default()
         };
+        batch.clip = self.clips.last().copied();
+        batch.transform = self.draw_transform;
         match batch_type {
             BatchType::Transparent => {
                 self.transparent_batches.push(batch);
@@ -207,6 +769,10 @@ impl Compositor {
                 self.subpixel_batches.push(batch);
                 self.subpixel_batches.last_mut().unwrap()
             }
+            BatchType::Grayscale => {
+                self.grayscale_batches.push(batch);
+                self.grayscale_batches.last_mut().unwrap()
+            }
             BatchType::Opaque => {
                 self.opaque_batches.push(batch);
                 self.opaque_batches.last_mut().unwrap()
@@ -214,35 +780,56 @@ impl Compositor {
         }
     }
 
-    pub fn build_display_list(&self) -> DisplayList {
+    /// Ends the frame started by [`Compositor::begin`], returning the
+    /// display list of everything drawn since. Transparent and subpixel
+    /// primitives are drawn back to front across batches, so they blend
+    /// over everything behind them.
+    pub fn end(&mut self) -> DisplayList {
+        debug_assert!(
+            self.in_frame,
+            "Compositor::end called without Compositor::begin"
+        );
+        self.in_frame = false;
+        for batch in self
+            .transparent_batches
+            .iter_mut()
+            .chain(self.subpixel_batches.iter_mut())
+        {
+            batch.sort_back_to_front();
+        }
+        self.build_display_list()
+    }
+
+    fn build_display_list(&self) -> DisplayList {
         let mut list = DisplayList::new();
+        let mut state = ListState::default();
         if !self.opaque_batches.is_empty() {
             list.commands.push(Command::BindPipeline(Pipeline::Opaque));
             for batch in &self.opaque_batches {
                 if batch.vertices.is_empty() {
                     continue;
                 }
-                batch.build_display_list(&mut list);
+                batch.build_display_list(&mut list, &mut state);
             }
         }
         if !self.transparent_batches.is_empty() {
             list.commands
                 .push(Command::BindPipeline(Pipeline::Transparent));
-            for batch in &self.transparent_batches {
-                if batch.vertices.is_empty() {
-                    continue;
-                }
-                batch.build_display_list(&mut list);
-            }
+            build_sorted_display_list(&self.transparent_batches, &mut list, &mut state);
         }
         if !self.subpixel_batches.is_empty() {
             list.commands
                 .push(Command::BindPipeline(Pipeline::Subpixel));
-            for batch in &self.subpixel_batches {
+            build_sorted_display_list(&self.subpixel_batches, &mut list, &mut state);
+        }
+        if !self.grayscale_batches.is_empty() {
+            list.commands
+                .push(Command::BindPipeline(Pipeline::Grayscale));
+            for batch in &self.grayscale_batches {
                 if batch.vertices.is_empty() {
                     continue;
                 }
-                batch.build_display_list(&mut list);
+                batch.build_display_list(&mut list, &mut state);
             }
         }
         list
@@ -295,6 +882,12 @@ pub enum Command {
     BindPipeline(Pipeline),
     /// Draw the specified range of indexed triangles.
     Draw { start: u32, count: u32 },
+    /// Restrict drawing to the specified rectangle.
+    SetScissor(Rect),
+    /// Remove the scissor rectangle.
+    ResetScissor,
+    /// Offset and scale the positions of the following draws.
+    SetTransform(DrawTransform),
 }
 
 /// Pipelines used by a display list.
@@ -303,4 +896,102 @@ pub enum Pipeline {
     Opaque,
     Transparent,
     Subpixel,
+    Grayscale,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::image_cache::MASK_FORMAT;
+
+    fn texture_location() -> TextureLocation {
+        TextureLocation {
+            atlas_index: 0,
+            format: MASK_FORMAT,
+            min: (0., 0.),
+            max: (0.5, 0.5),
+        }
+    }
+
+    #[test]
+    fn begin_recycles_subpixel_batches() {
+        let mut compositor = Compositor::new();
+        compositor.begin();
+        compositor.add_subpixel_rect([0., 0., 10., 10.], 0.5, color::BLACK, texture_location());
+        assert_eq!(compositor.subpixel_batches.len(), 1);
+        compositor.end();
+        compositor.begin();
+        assert!(compositor.subpixel_batches.is_empty());
+        assert_eq!(compositor.empty_batches.len(), 1);
+        assert!(compositor.empty_batches[0].vertices.is_empty());
+    }
+
+    #[test]
+    fn translated_rect_vertices() {
+        let mut compositor = Compositor::new();
+        compositor.begin();
+        compositor.set_transform(Some([[1., 0., 10.], [0., 1., 20.], [0., 0., 1.]]));
+        compositor.draw_rect([0., 0., 5., 5.], 0.5, color::BLACK);
+        let positions = compositor.opaque_batches[0]
+            .vertices
+            .iter()
+            .map(|vertex| [vertex.pos[0], vertex.pos[1]])
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [[10., 20.], [10., 25.], [15., 25.], [15., 20.]]);
+        compositor.set_transform(None);
+        compositor.draw_rect([0., 0., 5., 5.], 0.5, color::BLACK);
+        assert_eq!(compositor.opaque_batches[0].vertices[4].pos[..2], [0., 0.]);
+    }
+
+    #[test]
+    fn round_rect_vertices() {
+        let mut compositor = Compositor::new();
+        compositor.begin();
+        compositor.draw_round_rect([0., 0., 20., 10.], 0.5, color::BLACK, 4.);
+        assert!(compositor.opaque_batches.is_empty());
+        // one quad, with the corners masked in the fragment shader
+        let vertices = &compositor.transparent_batches[0].vertices;
+        assert_eq!(vertices.len(), 4);
+        assert!(vertices.iter().all(|vertex| vertex.rect == [10., 5., 4.]));
+    }
+
+    #[test]
+    fn zero_radius_round_rect_is_a_rect() {
+        let mut rect = Compositor::new();
+        rect.begin();
+        rect.draw_rect([1., 2., 20., 10.], 0.5, color::BLACK);
+        let mut round_rect = Compositor::new();
+        round_rect.begin();
+        round_rect.draw_round_rect([1., 2., 20., 10.], 0.5, color::BLACK, 0.);
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&round_rect.opaque_batches[0].vertices),
+            bytemuck::cast_slice::<_, u8>(&rect.opaque_batches[0].vertices)
+        );
+    }
+
+    #[test]
+    fn transparent_rects_are_drawn_back_to_front() {
+        let front = color::RED.with_alpha(128);
+        let back = color::BLUE.with_alpha(128);
+        let mut compositor = Compositor::new();
+        compositor.begin();
+        // the front rect is submitted first, in a batch of its own
+        compositor.push_clip([0., 0., 20., 20.]);
+        compositor.draw_rect([0., 0., 10., 10.], 0.2, front);
+        compositor.pop_clip();
+        compositor.draw_rect([5., 5., 10., 10.], 0.8, back);
+        let display_list = compositor.end();
+        let colors = display_list
+            .vertices()
+            .iter()
+            .map(|vertex| vertex.color)
+            .collect::<Vec<_>>();
+        assert_eq!(colors, [back, back, back, back, front, front, front, front]);
+        let draws = display_list
+            .commands()
+            .iter()
+            .filter(|command| matches!(command, Command::Draw { .. }))
+            .count();
+        assert_eq!(draws, 2);
+    }
 }
@@ -1,18 +1,22 @@
 #![allow(clippy::too_many_arguments)]
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+};
 
 use swash::{
     scale::{
         image::{Content, Image as GlyphImage},
         Render, ScaleContext, Scaler, Source, StrikeWith,
     },
-    zeno::{Format, Vector},
+    zeno::{Angle, Format, Transform, Vector},
     CacheKey as FontCacheKey, FontRef, GlyphId,
 };
+use wgpu::TextureFormat;
 
 use super::{
-    image_cache::{ImageCache, TextureLocation},
-    wgpu_context::WgpuContext,
+    image_cache::{ImageCache, TextureLocation, COLOR_FORMAT, MASK_FORMAT},
+    wgpu_context::Renderer,
 };
 
 const IS_MACOS: bool = cfg!(target_os = "macos");
@@ -30,13 +34,166 @@ struct GlyphKey {
     id: GlyphId,
     offset: [SubpixelOffset; 2],
     size: u16,
+    /// Hash of the [`GlyphRenderOptions`] the glyph was rendered with.
+    options: u64,
+}
+
+/// Options controlling how glyphs are rasterized, to tune text rendering
+/// for a platform or panel.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GlyphRenderOptions {
+    /// Weights of the subpixel filter, applied to the red, green and blue
+    /// channels.
+    pub subpixel_filter: [f32; 3],
+    /// Amount to embolden outlines by, in pixels.
+    pub embolden: f32,
+    /// Whether outlines are hinted.
+    pub hint: bool,
+    /// Offset added to glyph positions before snapping them to pixels.
+    pub subpixel_bias: (f32, f32),
+    /// Whether outlines are rendered as subpixel masks rather than
+    /// grayscale coverage. Grayscale is needed for transformed text and
+    /// text over transparent backgrounds.
+    pub subpixel: bool,
+    /// Extra amount to embolden outlines by, in pixels, to fake a bold
+    /// face.
+    pub synthetic_bold: f32,
+    /// Angle to slant outlines by, in degrees, to fake an italic face.
+    pub synthetic_skew: f32,
+    /// Whether glyphs are also rendered at subpixel offsets vertically.
+    /// Horizontal text rarely benefits from it and it multiplies the
+    /// number of cached glyphs, so by default glyphs snap to whole pixels
+    /// vertically. Worth enabling for rotated text.
+    pub vertical_subpixel: bool,
+}
+
+impl Default for GlyphRenderOptions {
+    fn default() -> Self {
+        GlyphRenderOptions {
+            subpixel_filter: [0.3, 0., -0.3],
+            embolden: if IS_MACOS { 0.25 } else { 0. },
+            hint: !IS_MACOS,
+            subpixel_bias: (0.125, 0.),
+            subpixel: true,
+            synthetic_bold: 0.,
+            synthetic_skew: 0.,
+            vertical_subpixel: false,
+        }
+    }
+}
+
+impl GlyphRenderOptions {
+    /// Returns a hash of the options that affect rasterization.
+    fn key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for weight in self.subpixel_filter {
+            weight.to_bits().hash(&mut hasher);
+        }
+        self.embolden.to_bits().hash(&mut hasher);
+        self.hint.hash(&mut hasher);
+        self.subpixel.hash(&mut hasher);
+        self.synthetic_bold.to_bits().hash(&mut hasher);
+        self.synthetic_skew.to_bits().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the subpixel offset a glyph at `(x, y)` is rendered at, which
+    /// is zero vertically unless [`GlyphRenderOptions::vertical_subpixel`]
+    /// is set.
+    fn subpixel_offset(&self, x: f32, y: f32) -> [SubpixelOffset; 2] {
+        let y = if self.vertical_subpixel {
+            SubpixelOffset::quantize(y)
+        } else {
+            SubpixelOffset::Zero
+        };
+        [SubpixelOffset::quantize(x), y]
+    }
+
+    /// Returns the transform slanting outlines by
+    /// [`GlyphRenderOptions::synthetic_skew`], if any.
+    fn transform(&self) -> Option<Transform> {
+        if self.synthetic_skew == 0. {
+            return None;
+        }
+        Some(Transform::skew(
+            Angle::from_degrees(self.synthetic_skew),
+            Angle::ZERO,
+        ))
+    }
+}
+
+/// Rendered glyphs, evicting the least recently used ones past capacity.
+#[derive(Default)]
+struct GlyphMap {
+    glyphs: HashMap<GlyphKey, (GlyphEntry, u64)>,
+    /// Keys of the glyphs by the tick they were last used at, oldest first.
+    lru: BTreeMap<u64, GlyphKey>,
+    tick: u64,
+    capacity: Option<usize>,
+}
+
+impl GlyphMap {
+    fn get(&mut self, key: &GlyphKey) -> Option<GlyphEntry> {
+        let (entry, last_used) = self.glyphs.get_mut(key)?;
+        self.lru.remove(&*last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.lru.insert(self.tick, *key);
+        Some(*entry)
+    }
+
+    /// Inserts a glyph, returning the entries evicted to make room for it.
+    fn insert(&mut self, key: GlyphKey, entry: GlyphEntry) -> Vec<GlyphEntry> {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.glyphs.insert(key, (entry, self.tick)) {
+            self.lru.remove(&last_used);
+        }
+        self.lru.insert(self.tick, key);
+        let mut evicted = Vec::new();
+        if let Some(capacity) = self.capacity {
+            while self.glyphs.len() > capacity {
+                match self.evict_oldest() {
+                    Some(entry) => evicted.push(entry),
+                    None => break,
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Removes the glyphs for which `f` returns false, returning their
+    /// entries.
+    fn retain(&mut self, mut f: impl FnMut(&GlyphKey) -> bool) -> Vec<GlyphEntry> {
+        let mut removed = Vec::new();
+        self.glyphs.retain(|key, (entry, _)| {
+            let keep = f(key);
+            if !keep {
+                removed.push(*entry);
+            }
+            keep
+        });
+        let glyphs = &self.glyphs;
+        self.lru.retain(|_, key| glyphs.contains_key(key));
+        removed
+    }
+
+    fn clear(&mut self) {
+        self.glyphs.clear();
+        self.lru.clear();
+    }
+
+    /// Removes the least recently used glyph.
+    fn evict_oldest(&mut self) -> Option<GlyphEntry> {
+        let (_, key) = self.lru.pop_first()?;
+        self.glyphs.remove(&key).map(|(entry, _)| entry)
+    }
 }
 
 #[derive(Default)]
 pub struct GlyphCache {
     scale_context: ScaleContext,
     img: GlyphImage,
-    glyphs: HashMap<GlyphKey, GlyphEntry>,
+    glyphs: GlyphMap,
 }
 
 impl GlyphCache {
@@ -44,20 +201,49 @@ impl GlyphCache {
         GlyphCache::default()
     }
 
+    /// Creates a cache holding at most `capacity` glyphs, evicting the least
+    /// recently used glyphs past that.
+    pub fn with_capacity(capacity: usize) -> GlyphCache {
+        let mut cache = GlyphCache::default();
+        cache.glyphs.capacity = Some(capacity);
+        cache
+    }
+
+    /// Drops the glyphs rendered at a size other than `sizes`, e.g. after
+    /// the scale factor changes, and frees their images.
+    pub fn retain_sizes(&mut self, image_cache: &mut ImageCache, sizes: &[f32]) {
+        let sizes = sizes
+            .iter()
+            .map(|&size| quantize_size(size))
+            .collect::<Vec<_>>();
+        for entry in self.glyphs.retain(|key| sizes.contains(&key.size)) {
+            image_cache.free(entry.image_id);
+        }
+    }
+
+    /// Drops all rendered glyphs, e.g. when the fonts change, so they're
+    /// rendered again on next use. Their images aren't freed, so this
+    /// should go together with [`ImageCache::clear`]; use
+    /// [`GlyphCache::retain_sizes`] with no sizes to free them instead.
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+    }
+
     pub fn session<'a>(
         &'a mut self,
-        wgpu: &'a WgpuContext,
+        wgpu: &'a Renderer,
         image_cache: &'a mut ImageCache,
         fontref: FontRef<'a>,
         size: f32,
         coords: &[i16],
+        options: GlyphRenderOptions,
     ) -> GlyphCacheSession<'a> {
-        let quant_size = (size * 32.) as u16;
+        let quant_size = quantize_size(size);
         let fontkey = fontref.key;
         let scaler = self
             .scale_context
             .builder(fontref)
-            .hint(!IS_MACOS)
+            .hint(options.hint)
             .size(size)
             .normalized_coords(coords)
             .build();
@@ -69,18 +255,22 @@ impl GlyphCache {
             quant_size,
             glyphs: &mut self.glyphs,
             fontkey,
+            options,
+            options_key: options.key(),
         }
     }
 }
 
 pub struct GlyphCacheSession<'a> {
-    wgpu: &'a WgpuContext,
+    wgpu: &'a Renderer,
     image_cache: &'a mut ImageCache,
     scaler: Scaler<'a>,
     img: &'a mut GlyphImage,
     quant_size: u16,
-    glyphs: &'a mut HashMap<GlyphKey, GlyphEntry>,
+    glyphs: &'a mut GlyphMap,
     fontkey: FontCacheKey,
+    options: GlyphRenderOptions,
+    options_key: u64,
 }
 
 impl<'a> GlyphCacheSession<'a> {
@@ -91,22 +281,28 @@ impl<'a> GlyphCacheSession<'a> {
     }
 
     pub fn get(&mut self, id: GlyphId, x: f32, y: f32) -> Option<GlyphEntry> {
-        let subpx = [SubpixelOffset::quantize(x), SubpixelOffset::quantize(y)];
+        let subpx = self.options.subpixel_offset(x, y);
         let key = GlyphKey {
             id,
             fontkey: self.fontkey,
             offset: subpx,
             size: self.quant_size,
+            options: self.options_key,
         };
         if let Some(entry) = self.glyphs.get(&key) {
-            return Some(*entry);
+            return Some(entry);
         }
         self.img.clear();
-        let embolden = if IS_MACOS { 0.25 } else { 0. };
+        let format = if self.options.subpixel {
+            Format::CustomSubpixel(self.options.subpixel_filter)
+        } else {
+            Format::Alpha
+        };
         if Render::new(SOURCES)
-            .format(Format::CustomSubpixel([0.3, 0., -0.3]))
+            .format(format)
             .offset(Vector::new(subpx[0].to_f32(), subpx[1].to_f32()))
-            .embolden(embolden)
+            .embolden(self.options.embolden + self.options.synthetic_bold)
+            .transform(self.options.transform())
             .render_into(&mut self.scaler, id, self.img)
         {
             let p = self.img.placement;
@@ -118,33 +314,132 @@ impl<'a> GlyphCacheSession<'a> {
                 return None;
             }
             let is_bitmap = self.img.content == Content::Color;
+            let is_subpixel = self.img.content == Content::SubpixelMask;
             //dbg!(self.img.content);
-            // let mut rgba8 = image::RgbaImage::new(width, height);
-            // for y in 0..height {
-            //     for x in 0..width {
-            //         let slice = &self.img.data[(y * width * 4 + (x * 4)) as usize..];
-            //         let pixel = image::Rgba([slice[0], slice[1], slice[2], 255]);
-            //         rgba8.put_pixel(x, y, pixel);
-            //     }
-            // }
-            //rgba8.save(format!("{id}_{x}_{y}.bmp")).unwrap();
-            let image_id = self
-                .image_cache
-                .allocate(self.wgpu, width, height, &self.img.data)?;
+            let image_id = if self.img.content == Content::Mask {
+                // the atlases are rgba, so spread the coverage over all channels
+                let rgba = self
+                    .img
+                    .data
+                    .iter()
+                    .flat_map(|&a| [a, a, a, a])
+                    .collect::<Vec<_>>();
+                allocate_evicting(
+                    self.wgpu,
+                    self.image_cache,
+                    self.glyphs,
+                    width,
+                    height,
+                    &rgba,
+                    MASK_FORMAT,
+                )?
+            } else {
+                let format = if is_bitmap { COLOR_FORMAT } else { MASK_FORMAT };
+                allocate_evicting(
+                    self.wgpu,
+                    self.image_cache,
+                    self.glyphs,
+                    width,
+                    height,
+                    &self.img.data,
+                    format,
+                )?
+            };
             let entry = GlyphEntry {
                 left,
                 top,
                 width,
                 height,
                 is_bitmap,
+                is_subpixel,
                 image_id,
             };
-            self.glyphs.insert(key, entry);
+            for evicted in self.glyphs.insert(key, entry) {
+                self.image_cache.free(evicted.image_id);
+            }
             Some(entry)
         } else {
             None
         }
     }
+
+    /// Renders the glyph `id` the way [`GlyphCacheSession::get`] would, at a
+    /// zero subpixel offset, and writes it to `path` as a PNG. The glyph
+    /// isn't added to the cache. Returns false if the glyph has no image.
+    #[cfg(feature = "debug-glyphs")]
+    pub fn dump_glyph(
+        &mut self,
+        id: GlyphId,
+        path: impl AsRef<std::path::Path>,
+    ) -> image::ImageResult<bool> {
+        let mut img = GlyphImage::new();
+        let format = if self.options.subpixel {
+            Format::CustomSubpixel(self.options.subpixel_filter)
+        } else {
+            Format::Alpha
+        };
+        if !Render::new(SOURCES)
+            .format(format)
+            .embolden(self.options.embolden + self.options.synthetic_bold)
+            .transform(self.options.transform())
+            .render_into(&mut self.scaler, id, &mut img)
+        {
+            return Ok(false);
+        }
+        let width = img.placement.width;
+        let height = img.placement.height;
+        if width == 0 || height == 0 {
+            return Ok(false);
+        }
+        let rgba = match img.content {
+            // coverage as black on white, so it can be viewed anywhere
+            Content::Mask => img
+                .data
+                .iter()
+                .flat_map(|&a| [255 - a, 255 - a, 255 - a, 255])
+                .collect::<Vec<_>>(),
+            Content::SubpixelMask => img
+                .data
+                .chunks(4)
+                .flat_map(|px| [255 - px[0], 255 - px[1], 255 - px[2], 255])
+                .collect(),
+            Content::Color => img.data,
+        };
+        image::save_buffer_with_format(
+            path,
+            &rgba,
+            width,
+            height,
+            image::ColorType::Rgba8,
+            image::ImageFormat::Png,
+        )?;
+        Ok(true)
+    }
+}
+
+/// Adds a glyph image to `image_cache`, evicting the least recently used
+/// glyphs and retrying while the allocation fails, until it succeeds or no
+/// glyphs are left to evict.
+fn allocate_evicting(
+    wgpu: &Renderer,
+    image_cache: &mut ImageCache,
+    glyphs: &mut GlyphMap,
+    width: u32,
+    height: u32,
+    data: &[u8],
+    format: TextureFormat,
+) -> Option<usize> {
+    loop {
+        if let Some(image_id) = image_cache.allocate(wgpu, width, height, data, format) {
+            return Some(image_id);
+        }
+        let evicted = glyphs.evict_oldest()?;
+        image_cache.free(evicted.image_id);
+    }
+}
+
+fn quantize_size(size: f32) -> u16 {
+    (size * 32.) as u16
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -154,6 +449,8 @@ pub struct GlyphEntry {
     pub width: u32,
     pub height: u32,
     pub is_bitmap: bool,
+    /// False for grayscale coverage masks.
+    pub is_subpixel: bool,
     pub image_id: usize,
 }
 
@@ -196,3 +493,130 @@ impl SubpixelOffset {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fonts::FontSource,
+        gfx::{
+            color,
+            wgpu_context::{DeviceOptions, WgpuContext},
+        },
+    };
+
+    fn key(fontkey: FontCacheKey, id: GlyphId) -> GlyphKey {
+        GlyphKey {
+            fontkey,
+            id,
+            offset: [SubpixelOffset::Zero; 2],
+            size: 16,
+            options: 0,
+        }
+    }
+
+    fn entry(image_id: usize) -> GlyphEntry {
+        GlyphEntry {
+            left: 0,
+            top: 0,
+            width: 1,
+            height: 1,
+            is_bitmap: false,
+            is_subpixel: true,
+            image_id,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let fontkey = FontCacheKey::new();
+        let mut glyphs = GlyphMap {
+            capacity: Some(2),
+            ..Default::default()
+        };
+        assert!(glyphs.insert(key(fontkey, 1), entry(1)).is_empty());
+        assert!(glyphs.insert(key(fontkey, 2), entry(2)).is_empty());
+        // 1 is now used more recently than 2
+        assert!(glyphs.get(&key(fontkey, 1)).is_some());
+        let evicted = glyphs.insert(key(fontkey, 3), entry(3));
+        assert_eq!(evicted.iter().map(|e| e.image_id).collect::<Vec<_>>(), [2]);
+        assert!(glyphs.get(&key(fontkey, 2)).is_none());
+        assert!(glyphs.get(&key(fontkey, 1)).is_some());
+        assert!(glyphs.get(&key(fontkey, 3)).is_some());
+    }
+
+    #[test]
+    fn vertical_offsets_share_an_entry_unless_enabled() {
+        let fontkey = FontCacheKey::new();
+        let key_at = |options: &GlyphRenderOptions, y: f32| GlyphKey {
+            offset: options.subpixel_offset(0.5, y),
+            ..key(fontkey, 1)
+        };
+        let mut options = GlyphRenderOptions::default();
+        assert!(!options.vertical_subpixel);
+        let mut glyphs = GlyphMap::default();
+        glyphs.insert(key_at(&options, 10.25), entry(1));
+        let shared = glyphs.get(&key_at(&options, 10.5));
+        assert_eq!(shared.map(|e| e.image_id), Some(1));
+
+        options.vertical_subpixel = true;
+        let mut glyphs = GlyphMap::default();
+        glyphs.insert(key_at(&options, 10.25), entry(1));
+        assert!(glyphs.get(&key_at(&options, 10.5)).is_none());
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn session_renders_a_glyph() {
+        let context = WgpuContext::new_headless(1, 1, 1., color::WHITE, &DeviceOptions::default());
+        let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+        let mut glyph_cache = GlyphCache::new();
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSans.ttf");
+        let fontref = source.get_fontref(id);
+        let glyph_id = fontref.charmap().map('A');
+        let mut session = glyph_cache.session(
+            context.renderer(),
+            &mut image_cache,
+            fontref,
+            16.,
+            &[],
+            GlyphRenderOptions::default(),
+        );
+        let entry = session.get(glyph_id, 0., 0.).unwrap();
+        assert!(entry.width > 0 && entry.height > 0);
+        assert!(session.get_texture_location(entry.image_id).is_some());
+        // the second lookup comes from the cache
+        assert_eq!(
+            session.get(glyph_id, 0., 0.).unwrap().image_id,
+            entry.image_id
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn cleared_glyphs_are_rendered_again() {
+        let context = WgpuContext::new_headless(1, 1, 1., color::WHITE, &DeviceOptions::default());
+        let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+        let mut glyph_cache = GlyphCache::new();
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSans.ttf");
+        let fontref = source.get_fontref(id);
+        let glyph_id = fontref.charmap().map('A');
+        let mut render = |glyph_cache: &mut GlyphCache| {
+            let mut session = glyph_cache.session(
+                context.renderer(),
+                &mut image_cache,
+                fontref,
+                16.,
+                &[],
+                GlyphRenderOptions::default(),
+            );
+            session.get(glyph_id, 0., 0.).unwrap().image_id
+        };
+        let first = render(&mut glyph_cache);
+        glyph_cache.clear();
+        // the old image is still allocated, so the glyph gets a new one
+        assert_ne!(render(&mut glyph_cache), first);
+    }
+}
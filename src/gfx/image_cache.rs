@@ -1,22 +1,57 @@
+use guillotiere::AllocId;
 use wgpu::{BindGroup, TextureFormat};
 
-use super::{atlas::Atlas, wgpu_context::WgpuContext};
+use super::{atlas::Atlas, wgpu_context::Renderer};
+
+/// Format of atlases holding color images, e.g. emoji, which are sRGB
+/// encoded and linearized when sampled.
+pub const COLOR_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+/// Format of atlases holding glyph coverage masks, which are linear.
+pub const MASK_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+/// Transparent pixels around each image in an atlas, see
+/// [`ImageCache::set_padding`].
+pub const DEFAULT_PADDING: u32 = 1;
 
 pub struct ImageCache {
     atlases: Vec<Atlas>,
-    entries: Vec<TextureLocation>,
+    /// Images by id, `None` for ids that have been freed.
+    entries: Vec<Option<Entry>>,
+    /// Freed ids available for reuse.
+    free_ids: Vec<usize>,
     max_texture_size: u32,
+    /// Width and height of new atlases, no larger than `max_texture_size`.
+    atlas_size: u32,
+    /// Transparent pixels around each image in new atlases.
+    padding: u32,
 }
 
 impl ImageCache {
     pub fn new(max_texture_size: u32) -> ImageCache {
+        ImageCache::with_atlas_size(max_texture_size, max_texture_size)
+    }
+
+    /// Creates a cache whose atlases are `atlas_size` pixels square, e.g.
+    /// to save memory on constrained GPUs. Images larger than that get an
+    /// atlas of their own, up to `max_texture_size`.
+    pub fn with_atlas_size(max_texture_size: u32, atlas_size: u32) -> ImageCache {
         ImageCache {
             atlases: Vec::new(),
             entries: Vec::new(),
+            free_ids: Vec::new(),
             max_texture_size,
+            atlas_size: atlas_size.min(max_texture_size),
+            padding: DEFAULT_PADDING,
         }
     }
 
+    /// Sets the number of transparent pixels left around each image, which
+    /// keeps neighboring images from bleeding into each other when sampled
+    /// with filtering or at fractional positions. Only affects atlases
+    /// created afterwards, so it's best set before adding any images.
+    pub fn set_padding(&mut self, padding: u32) {
+        self.padding = padding;
+    }
+
     pub fn atlas_iter_mut(&mut self) -> impl Iterator<Item = &mut Atlas> {
         self.atlases.iter_mut()
     }
@@ -25,18 +60,59 @@ impl ImageCache {
         Some(&self.atlases.get(index)?.bind_group)
     }
 
+    /// Adds an sRGB encoded RGBA image with 8 bits per channel to the cache,
+    /// sharing the atlases used for color glyphs. Returns an id for
+    /// [`ImageCache::get_image_location`], or `None` if `rgba` isn't
+    /// `width * height * 4` bytes long or the image doesn't fit in an atlas.
+    pub fn load_rgba(
+        &mut self,
+        wgpu: &Renderer,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Option<usize> {
+        let expected = width as usize * height as usize * 4;
+        if rgba.len() != expected {
+            log::error!(
+                "Image data for {}x{} image is {} bytes, expected {}",
+                width,
+                height,
+                rgba.len(),
+                expected
+            );
+            return None;
+        }
+        self.allocate(wgpu, width, height, rgba, COLOR_FORMAT)
+    }
+
+    /// Adds an image to an atlas of `format`, so that color images and
+    /// coverage masks are sampled differently. `data` must be in `format`.
     pub fn allocate(
         &mut self,
-        wgpu: &WgpuContext,
+        wgpu: &Renderer,
         width: u32,
         height: u32,
         data: &[u8],
+        format: TextureFormat,
     ) -> Option<usize> {
+        if width > self.max_texture_size || height > self.max_texture_size {
+            log::error!(
+                "Image of size {}x{} is larger than the maximum texture size {}",
+                width,
+                height,
+                self.max_texture_size
+            );
+            return None;
+        }
         let entry = 'outer: {
             for (atlas_index, atlas) in self.atlases.iter_mut().enumerate() {
-                if let Some((x, y)) = atlas.allocate(width, height, data) {
+                if atlas.format() != format {
+                    continue;
+                }
+                if let Some((alloc_id, x, y)) = atlas.allocate(width, height, data) {
                     break 'outer Some(Entry {
                         atlas_index,
+                        alloc_id,
                         x,
                         y,
                         width,
@@ -45,12 +121,17 @@ impl ImageCache {
                 }
             }
             let atlas_index = self.atlases.len();
-            let atlas = Atlas::new(wgpu, self.max_texture_size, TextureFormat::Rgba8Unorm);
+            let padded_size = width.max(height) + self.padding * 2;
+            let size = self.atlas_size.max(padded_size).min(self.max_texture_size);
+            // images as large as the maximum texture size go without padding
+            let padding = self.padding.min((size - width.max(height)) / 2);
+            let atlas = Atlas::new(wgpu, size, format, padding);
             self.atlases.push(atlas);
             let atlas = self.atlases.last_mut().unwrap();
-            if let Some((x, y)) = atlas.allocate(width, height, data) {
+            if let Some((alloc_id, x, y)) = atlas.allocate(width, height, data) {
                 Some(Entry {
                     atlas_index,
+                    alloc_id,
                     x,
                     y,
                     width,
@@ -61,27 +142,84 @@ impl ImageCache {
                 None
             }
         }?;
-        let id = self.entries.len();
-        let s = 1. / self.max_texture_size as f32;
-        let location = TextureLocation {
+        match self.free_ids.pop() {
+            Some(id) => {
+                self.entries[id] = Some(entry);
+                Some(id)
+            }
+            None => {
+                self.entries.push(Some(entry));
+                Some(self.entries.len() - 1)
+            }
+        }
+    }
+
+    /// Frees the atlas space of the image `image_id`. The id may be returned
+    /// again by a later allocation.
+    pub fn free(&mut self, image_id: usize) {
+        let Some(entry) = self.entries.get_mut(image_id).and_then(Option::take) else {
+            return;
+        };
+        self.atlases[entry.atlas_index].deallocate(
+            entry.alloc_id,
+            entry.x,
+            entry.y,
+            entry.width,
+            entry.height,
+        );
+        self.free_ids.push(image_id);
+    }
+
+    /// Drops all images and their atlases, releasing the texture memory.
+    /// Any image ids and [`TextureLocation`]s handed out before are invalid
+    /// afterwards, including those held by a [`super::glyph_cache::GlyphCache`],
+    /// which should be cleared at the same time.
+    pub fn clear(&mut self) {
+        self.atlases.clear();
+        self.entries.clear();
+        self.free_ids.clear();
+    }
+
+    /// Returns the number of atlases, which are indexed from zero.
+    pub fn atlas_count(&self) -> usize {
+        self.atlases.len()
+    }
+
+    /// Returns the number of bytes of texture data held by the atlases.
+    pub fn memory_usage(&self) -> usize {
+        self.atlases.iter().map(|atlas| atlas.memory_usage()).sum()
+    }
+
+    /// Returns a location covering the whole of the atlas `atlas_index`,
+    /// for looking at how images are packed.
+    pub fn get_atlas_location(&self, atlas_index: usize) -> Option<TextureLocation> {
+        let atlas = self.atlases.get(atlas_index)?;
+        Some(TextureLocation {
+            atlas_index,
+            format: atlas.format(),
+            min: (0., 0.),
+            max: (1., 1.),
+        })
+    }
+
+    pub fn get_image_location(&self, image_id: usize) -> Option<TextureLocation> {
+        let entry = self.entries.get(image_id)?.as_ref()?;
+        let s = 1. / self.atlases[entry.atlas_index].size() as f32;
+        Some(TextureLocation {
             atlas_index: entry.atlas_index,
+            format: self.atlases[entry.atlas_index].format(),
             min: (entry.x as f32 * s, entry.y as f32 * s),
             max: (
                 (entry.x + entry.width) as f32 * s,
                 (entry.y + entry.height) as f32 * s,
             ),
-        };
-        self.entries.push(location);
-        Some(id)
-    }
-
-    pub fn get_image_location(&self, image_id: usize) -> Option<TextureLocation> {
-        self.entries.get(image_id).copied()
+        })
     }
 }
 
 struct Entry {
     atlas_index: usize,
+    alloc_id: AllocId,
     x: u32,
     y: u32,
     width: u32,
@@ -91,6 +229,8 @@ struct Entry {
 #[derive(Clone, Copy, Debug)]
 pub struct TextureLocation {
     pub atlas_index: usize,
+    /// Format of the atlas texture.
+    pub format: TextureFormat,
     pub min: (f32, f32),
     pub max: (f32, f32),
 }
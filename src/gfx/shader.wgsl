@@ -4,17 +4,50 @@ struct CameraUniform {
 @group(0) @binding(0)
 var<uniform> camera: CameraUniform;
 
+// offset and scale applied to positions before the camera, changed between
+// draws by Command::SetTransform. declared as a push constant or as a
+// uniform with a dynamic offset, depending on what the device supports
+struct DrawTransform {
+    offset: vec2<f32>,
+    scale: f32,
+};
+//!DRAW_TRANSFORM
+
+fn transform_pos(pos: vec2<f32>) -> vec2<f32> {
+    return pos * draw_transform.scale + draw_transform.offset;
+}
+
+// vertex colors are srgb encoded and written as they are, which assumes a
+// non-srgb target. an srgb target encodes what it's given, so colors are
+// linearized first, after the subpixel gamma correction, which is tuned
+// for encoded colors. masks are coverage and never converted, and color
+// images are linearized by sampling their srgb texture. declares
+// output_color(color: vec4<f32>) -> vec4<f32>
+//!OUTPUT_COLOR
+
+fn srgb_to_linear(color: vec4<f32>) -> vec4<f32> {
+    let c = color.rgb;
+    let low = c / 12.92;
+    let high = pow((c + vec3<f32>(0.055)) / 1.055, vec3<f32>(2.4));
+    return vec4<f32>(select(high, low, c <= vec3<f32>(0.04045)), color.a);
+}
+
 struct VertexInput {
     @location(0) pos: vec4<f32>,
     @location(1) color: vec4<f32>,
     @location(2) uv: vec2<f32>,
+    @location(3) rect: vec3<f32>,
+    @location(4) end_color: vec4<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec4<f32>,
     @location(1) uv: vec2<f32>,
+    // 0: solid color, 1: textured, 2: rounded rect, 3: radial gradient
     @location(2) use_tex: f32,
+    @location(3) rect: vec3<f32>,
+    @location(4) end_color: vec4<f32>,
 };
 
 @vertex
@@ -25,7 +58,9 @@ fn vs_main(
     out.color = model.color;
     out.uv = model.uv;
     out.use_tex = model.pos.w;
-    out.clip_position = camera.view_proj * vec4<f32>(model.pos.xyz, 1.0);
+    out.rect = model.rect;
+    out.end_color = model.end_color;
+    out.clip_position = camera.view_proj * vec4<f32>(transform_pos(model.pos.xy), model.pos.z, 1.0);
     return out;
 }
 
@@ -37,7 +72,7 @@ fn subpixel_r_vs_main(
     out.color = model.color;
     out.uv = model.uv;
     out.use_tex = model.pos.w;
-    out.clip_position = camera.view_proj * vec4<f32>(model.pos.xy, 0.3, 1.0);
+    out.clip_position = camera.view_proj * vec4<f32>(transform_pos(model.pos.xy), 0.3, 1.0);
     return out;
 }
 
@@ -49,7 +84,7 @@ fn subpixel_g_vs_main(
     out.color = model.color;
     out.uv = model.uv;
     out.use_tex = model.pos.w;
-    out.clip_position = camera.view_proj * vec4<f32>(model.pos.xy, 0.2, 1.0);
+    out.clip_position = camera.view_proj * vec4<f32>(transform_pos(model.pos.xy), 0.2, 1.0);
     return out;
 }
 
@@ -61,7 +96,7 @@ fn subpixel_b_vs_main(
     out.color = model.color;
     out.uv = model.uv;
     out.use_tex = model.pos.w;
-    out.clip_position = camera.view_proj * vec4<f32>(model.pos.xy, 0.1, 1.0);
+    out.clip_position = camera.view_proj * vec4<f32>(transform_pos(model.pos.xy), 0.1, 1.0);
     return out;
 }
 
@@ -72,21 +107,38 @@ var s_diffuse: sampler;
 
 @fragment
 fn transparent_fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    var res = in.color;
+    var res = output_color(in.color);
     let sample = textureSample(t_diffuse, s_diffuse, in.uv);
-    if in.use_tex > 0.0 {
+    if in.use_tex > 2.5 {
+        res = mix(res, output_color(in.end_color), clamp(length(in.uv), 0.0, 1.0));
+    } else if in.use_tex > 1.5 {
+        let d = rounded_rect_sdf(in.uv, in.rect.xy, in.rect.z);
+        res.a *= clamp(0.5 - d, 0.0, 1.0);
+    } else if in.use_tex > 0.5 {
         res *= sample;
     }
     return res;
 }
 
+// signed distance from p, relative to the center, to the edge of a rounded rect
+fn rounded_rect_sdf(p: vec2<f32>, half_size: vec2<f32>, radius: f32) -> f32 {
+    let q = abs(p) - half_size + vec2<f32>(radius);
+    return length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0) - radius;
+}
+
+@fragment
+fn grayscale_fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let coverage = textureSample(t_diffuse, s_diffuse, in.uv).a;
+    return vec4<f32>(output_color(in.color).rgb, in.color.a * coverage);
+}
+
 @fragment
 fn subpixel_r_fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 	let color = in.color;
     let mask = textureSample(t_diffuse, s_diffuse, in.uv);
 	let alpha = gamma_correct_subpx(color, mask);
 	let a = alpha.r;
-	let rgb = color.rgb; // * alpha.rgb;
+	let rgb = output_color(color).rgb; // * alpha.rgb;
 	return vec4<f32>(rgb.r, 0.0, 0.0, a);
 }
 
@@ -96,7 +148,7 @@ fn subpixel_g_fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let mask = textureSample(t_diffuse, s_diffuse, in.uv);
 	let alpha = gamma_correct_subpx(color, mask);
 	let a = alpha.g;
-	let rgb = color.rgb; // * alpha.rgb;
+	let rgb = output_color(color).rgb; // * alpha.rgb;
 	return vec4<f32>(0.0, rgb.g, 0.0, a);
 }
 
@@ -106,7 +158,7 @@ fn subpixel_b_fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let mask = textureSample(t_diffuse, s_diffuse, in.uv);
 	let alpha = gamma_correct_subpx(color, mask);
 	let a = alpha.b;
-	let rgb = color.rgb; // * alpha.rgb;
+	let rgb = output_color(color).rgb; // * alpha.rgb;
 	return vec4<f32>(0.0, 0.0, rgb.b, a);
 }
 
@@ -6,6 +6,11 @@ pub struct Vertex {
     pub pos: [f32; 4],
     pub color: Color,
     pub uv: [f32; 2],
+    /// Half width, half height and corner radius of a rounded rectangle,
+    /// with `uv` holding the offset from its center.
+    pub rect: [f32; 3],
+    /// Outer color of a radial gradient.
+    pub end_color: Color,
 }
 
 impl Vertex {
@@ -30,6 +35,23 @@ impl Vertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x2,
                 },
+                wgpu::VertexAttribute {
+                    offset: (std::mem::size_of::<[f32; 4]>()
+                        + std::mem::size_of::<[u8; 4]>()
+                        + std::mem::size_of::<[f32; 2]>())
+                        as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: (std::mem::size_of::<[f32; 4]>()
+                        + std::mem::size_of::<[u8; 4]>()
+                        + std::mem::size_of::<[f32; 2]>()
+                        + std::mem::size_of::<[f32; 3]>())
+                        as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Unorm8x4,
+                },
             ],
         }
     }
@@ -52,23 +74,69 @@ impl CameraUniform {
                 [0., 0., 0., 1.],
             ],
         };
-        this.update(width, height, scale_factor);
+        this.update(width, height, scale_factor, (0., 0.), 1.);
         this
     }
 
-    pub fn update(&mut self, width: u32, height: u32, scale_factor: f32) {
+    /// Rebuilds the projection so that `scroll_offset` (in pixels) lands at
+    /// the top left corner of the view, and everything is scaled by `zoom`
+    /// around that corner.
+    pub fn update(
+        &mut self,
+        width: u32,
+        height: u32,
+        scale_factor: f32,
+        scroll_offset: (f32, f32),
+        zoom: f32,
+    ) {
+        let sx = 2. * zoom / width as f32;
+        let sy = -2. * zoom / height as f32;
         // NOTES: z is 0.0->1.0, so 0.0 is infront of 1.0
         self.view_proj = [
-            [2. / width as f32, 0., 0., 0.],
-            [0., -2. / height as f32, 0., 0.],
+            [sx, 0., 0., 0.],
+            [0., sy, 0., 0.],
             [0., 0., 1., 0.],
-            [-1., 1., 0., 1.],
+            [
+                -1. - scroll_offset.0 * sx,
+                1. - scroll_offset.1 * sy,
+                0.,
+                1.,
+            ],
         ]
     }
 }
 
+/// Offset and scale applied to vertex positions on the GPU before the
+/// camera, as set by [`super::compositor::Command::SetTransform`].
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DrawTransform {
+    pub offset: [f32; 2],
+    pub scale: f32,
+    /// Pads the struct to the 16 bytes the shader expects.
+    _padding: f32,
+}
+
+impl DrawTransform {
+    pub const IDENTITY: DrawTransform = DrawTransform::new([0., 0.], 1.);
+
+    pub const fn new(offset: [f32; 2], scale: f32) -> DrawTransform {
+        DrawTransform {
+            offset,
+            scale,
+            _padding: 0.,
+        }
+    }
+}
+
+impl Default for DrawTransform {
+    fn default() -> Self {
+        DrawTransform::IDENTITY
+    }
+}
+
 /// Rectangle with floating point coordinates.
-#[derive(Copy, Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
 pub struct Rect {
     pub x: f32,
     pub y: f32,
@@ -86,6 +154,49 @@ impl Rect {
             height,
         }
     }
+
+    /// Returns the area covered by both rectangles, or `None` if they don't
+    /// overlap. Rectangles that only share an edge don't overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right > x && bottom > y {
+            Some(Rect::new(x, y, right - x, bottom - y))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the point lies inside the rectangle. The left and top
+    /// edges are inclusive, the right and bottom edges exclusive.
+    pub fn contains_point(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
+    /// Returns the smallest rectangle containing both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Shrinks the rectangle by `dx` on the left and right and `dy` on the
+    /// top and bottom. Negative values grow it. The size never goes below
+    /// zero.
+    pub fn inset(&self, dx: f32, dy: f32) -> Rect {
+        let width = (self.width - dx * 2.).max(0.);
+        let height = (self.height - dy * 2.).max(0.);
+        Rect::new(
+            self.x + (self.width - width) / 2.,
+            self.y + (self.height - height) / 2.,
+            width,
+            height,
+        )
+    }
 }
 
 impl From<[f32; 4]> for Rect {
@@ -93,3 +204,101 @@ impl From<[f32; 4]> for Rect {
         Self::new(v[0], v[1], v[2], v[3])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Projects the point `(x, y)` in pixels to normalized device
+    /// coordinates.
+    fn project(camera: &CameraUniform, x: f32, y: f32) -> (f32, f32) {
+        let m = camera.view_proj;
+        (
+            m[0][0] * x + m[1][0] * y + m[3][0],
+            m[0][1] * x + m[1][1] * y + m[3][1],
+        )
+    }
+
+    #[test]
+    fn scrolling_a_line_moves_points_up_a_line() {
+        let (width, height) = (800, 600);
+        let line_height = 20.;
+        for zoom in [1., 2.] {
+            let mut camera = CameraUniform::new(width, height, 1.);
+            camera.update(width, height, 1., (0., 0.), zoom);
+            let before = project(&camera, 100., 300.);
+            camera.update(width, height, 1., (0., line_height), zoom);
+            let after = project(&camera, 100., 300.);
+            assert_eq!(after.0, before.0);
+            // NDC y points up and spans 2 over the height
+            let shift = 2. * line_height * zoom / height as f32;
+            assert!((after.1 - before.1 - shift).abs() < 1e-6, "{zoom}");
+        }
+    }
+
+    #[test]
+    fn intersection_of_overlapping_rects() {
+        let a = Rect::new(0., 0., 10., 10.);
+        let b = Rect::new(5., 2., 10., 4.);
+        assert_eq!(a.intersect(&b), Some(Rect::new(5., 2., 5., 4.)));
+    }
+
+    #[test]
+    fn intersection_of_disjoint_rects() {
+        let a = Rect::new(0., 0., 10., 10.);
+        let b = Rect::new(20., 30., 5., 5.);
+        assert_eq!(a.intersect(&b), None);
+    }
+
+    #[test]
+    fn rects_touching_at_an_edge_dont_intersect() {
+        let a = Rect::new(0., 0., 10., 10.);
+        let b = Rect::new(10., 0., 10., 10.);
+        assert_eq!(a.intersect(&b), None);
+        assert!(!a.contains_point(10., 5.));
+        assert!(b.contains_point(10., 5.));
+    }
+
+    #[test]
+    fn zero_size_rects() {
+        let empty = Rect::new(5., 5., 0., 0.);
+        let a = Rect::new(0., 0., 10., 10.);
+        assert_eq!(a.intersect(&empty), None);
+        assert!(!empty.contains_point(5., 5.));
+        assert_eq!(
+            empty.union(&Rect::new(0., 0., 2., 2.)),
+            Rect::new(0., 0., 5., 5.)
+        );
+    }
+
+    #[test]
+    fn union_covers_both() {
+        let a = Rect::new(0., 0., 10., 10.);
+        let b = Rect::new(20., -5., 5., 5.);
+        assert_eq!(a.union(&b), Rect::new(0., -5., 25., 15.));
+        assert_eq!(a.union(&a), a);
+    }
+
+    #[test]
+    fn contains_point_edges() {
+        let a = Rect::new(0., 0., 10., 10.);
+        assert!(a.contains_point(0., 0.));
+        assert!(a.contains_point(9.5, 9.5));
+        assert!(!a.contains_point(10., 10.));
+        assert!(!a.contains_point(-0.5, 5.));
+    }
+
+    #[test]
+    fn inset() {
+        let a = Rect::new(0., 0., 10., 10.);
+        assert_eq!(a.inset(2., 1.), Rect::new(2., 1., 6., 8.));
+        assert_eq!(a.inset(-1., -1.), Rect::new(-1., -1., 12., 12.));
+    }
+
+    #[test]
+    fn inset_larger_than_rect() {
+        let a = Rect::new(0., 0., 10., 4.);
+        assert_eq!(a.inset(3., 3.), Rect::new(3., 2., 4., 0.));
+        assert_eq!(a.inset(20., 20.), Rect::new(5., 2., 0., 0.));
+    }
+}
@@ -1,3 +1,5 @@
+use std::{num::NonZeroU64, ops::Deref, sync::Arc, time::Instant};
+
 use wgpu::util::DeviceExt;
 use winit::window::Window;
 
@@ -5,90 +7,182 @@ use super::{
     color::Color,
     compositor::{Command, DisplayList, Pipeline},
     image_cache::ImageCache,
-    types::{CameraUniform, Vertex},
+    types::{CameraUniform, DrawTransform, Rect, Vertex},
 };
 
-pub struct WgpuContext {
+/// GPU resources shared by every window drawn with the same device: the
+/// device itself, the shader and the pipelines. The image and glyph caches
+/// only need a renderer, so they can be shared between windows too.
+///
+/// Every [`WgpuContext`] has one, which it dereferences to. Pass
+/// [`WgpuContext::renderer`] to [`WgpuContext::with_renderer`] to draw to
+/// another window with it.
+pub struct Renderer {
     pub instance: wgpu::Instance,
     pub adapter: wgpu::Adapter,
-    pub surface: wgpu::Surface,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
-    pub config: wgpu::SurfaceConfiguration,
+    /// Format of the targets the pipelines draw to.
+    pub format: wgpu::TextureFormat,
     pub shader: wgpu::ShaderModule,
     pub texture_bind_group_layout: wgpu::BindGroupLayout,
+    /// Sampler for glyph masks, which are drawn at 1:1 and stay crisp.
     pub texture_sampler: wgpu::Sampler,
+    /// Sampler for color images, e.g. emoji, which are smoother filtered
+    /// when scaled or zoomed.
+    pub linear_texture_sampler: wgpu::Sampler,
     pub camera_bind_group_layout: wgpu::BindGroupLayout,
-    pub depth_texture: wgpu::Texture,
-    pub depth_view: wgpu::TextureView,
     pub render_pipeline_layout: wgpu::PipelineLayout,
     pub opaque_render_pipeline: wgpu::RenderPipeline,
     pub transparent_render_pipeline: wgpu::RenderPipeline,
     pub subpixel_r_render_pipeline: wgpu::RenderPipeline,
     pub subpixel_g_render_pipeline: wgpu::RenderPipeline,
     pub subpixel_b_render_pipeline: wgpu::RenderPipeline,
+    pub grayscale_render_pipeline: wgpu::RenderPipeline,
+    /// True if draw transforms are passed as push constants, otherwise
+    /// they're read from a uniform buffer at a dynamic offset.
+    push_constants: bool,
+    /// Distance between transforms in the uniform buffer, which has to
+    /// meet the device's offset alignment.
+    transform_stride: u64,
+}
+
+/// Draws to a window, or to a texture when headless, with a [`Renderer`]
+/// that may be shared with other contexts.
+pub struct WgpuContext {
+    renderer: Arc<Renderer>,
+    /// Surface of the window rendered to, `None` for a headless context.
+    pub surface: Option<wgpu::Surface>,
+    /// Texture rendered to by a headless context.
+    pub target_texture: Option<wgpu::Texture>,
+    pub config: wgpu::SurfaceConfiguration,
+    pub depth_texture: wgpu::Texture,
+    pub depth_view: wgpu::TextureView,
 
     pub camera_uniform: CameraUniform,
     pub camera_buffer: wgpu::Buffer,
     pub camera_bind_group: wgpu::BindGroup,
+    pub scale_factor: f32,
+    pub scroll_offset: (f32, f32),
+    pub zoom: f32,
 
     pub vertex_buffer: Option<wgpu::Buffer>,
     pub index_buffer: Option<wgpu::Buffer>,
+    /// sRGB encoded, like the vertex colors, and linearized when clearing
+    /// an sRGB target.
     pub clear_color: wgpu::Color,
+    /// False until the surface is configured, which is put off while the
+    /// window has no area, e.g. when it's minimized.
+    surface_configured: bool,
+    /// Statistics of the last frame rendered.
+    frame_stats: FrameStats,
+    /// Queries timing frames on the GPU, when enabled.
+    gpu_timer: Option<GpuTimer>,
+    /// Distinct transforms of the frame being drawn, starting with the
+    /// identity. In the uniform fallback these are the slots of
+    /// `transform_buffer`.
+    transforms: Vec<DrawTransform>,
+    /// Holds `transforms` at the renderer's transform stride apart, `None`
+    /// when push constants are used.
+    transform_buffer: Option<wgpu::Buffer>,
+    /// Area of the target repainted by the frame being drawn, in pixels,
+    /// or `None` if all of it is.
+    repaint_rect: Option<Rect>,
 }
 
-impl WgpuContext {
-    pub fn new(window: &Window, clear_color: Color) -> WgpuContext {
-        let scale_factor = window.scale_factor() as f32;
-        let size = dbg!(window.inner_size());
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            dx12_shader_compiler: Default::default(),
-        });
-        let surface =
-            unsafe { instance.create_surface(&window) }.expect("failed to create surface");
+/// Adapter, features and limits requested when creating a [`WgpuContext`].
+///
+/// Draw transforms are passed as push constants when
+/// [`wgpu::Features::PUSH_CONSTANTS`] is enabled and
+/// `limits.max_push_constant_size` is at least [`DRAW_TRANSFORM_SIZE`],
+/// otherwise through a uniform buffer.
+#[derive(Clone, Debug)]
+pub struct DeviceOptions {
+    /// Features to enable. Features the adapter doesn't support are left
+    /// out, with a warning.
+    pub features: wgpu::Features,
+    /// Limits to request. If the adapter can't meet them the default limits
+    /// are used, with a warning.
+    pub limits: wgpu::Limits,
+    /// Whether to prefer an integrated or a discrete GPU when there's more
+    /// than one adapter.
+    pub power_preference: wgpu::PowerPreference,
+    /// Backends adapters are looked for on.
+    pub backends: wgpu::Backends,
+    /// Only use a software adapter, e.g. to get the same output on every
+    /// machine.
+    pub force_fallback_adapter: bool,
+}
 
-        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        DeviceOptions {
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::default(),
             power_preference: wgpu::PowerPreference::default(),
-            compatible_surface: Some(&surface),
+            backends: wgpu::Backends::all(),
             force_fallback_adapter: false,
-        }))
-        .expect("failed to fetch adapter");
+        }
+    }
+}
 
-        let (device, queue) = pollster::block_on(adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                limits: wgpu::Limits::default(),
-                label: None,
-            },
-            None,
-        ))
-        .expect("failed to fetch device");
+/// Statistics of a rendered frame, for performance work.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct FrameStats {
+    pub vertex_count: usize,
+    pub index_count: usize,
+    /// Number of draw calls, counting each subpixel draw once per channel.
+    pub draw_count: usize,
+    /// Number of atlases uploaded to the GPU for the frame.
+    pub atlas_uploads: usize,
+    /// Time spent recording and submitting the frame on the CPU.
+    pub cpu_ms: f32,
+    /// Time the GPU spent drawing the frame, if GPU timing is enabled.
+    pub gpu_ms: Option<f32>,
+}
 
-        let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .find(|f| !f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
-        dbg!(surface_format);
+/// Timestamp queries written before and after the render pass.
+struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+}
 
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface_format,
-            width: size.width,
-            height: size.height,
-            present_mode: surface_caps.present_modes[0],
-            alpha_mode: surface_caps.alpha_modes[0],
-            view_formats: vec![],
-        };
-        surface.configure(&device, &config);
+/// Size of the push constants holding a [`DrawTransform`].
+pub const DRAW_TRANSFORM_SIZE: u32 = std::mem::size_of::<DrawTransform>() as u32;
+
+/// Size of the two timestamps written by a [`GpuTimer`].
+const TIMESTAMPS_SIZE: u64 = 2 * std::mem::size_of::<u64>() as u64;
 
-        // pipeline setup!
+impl Renderer {
+    /// Creates the shader and pipelines for drawing to targets of `format`.
+    fn new(
+        instance: wgpu::Instance,
+        adapter: wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        format: wgpu::TextureFormat,
+    ) -> Renderer {
+        let push_constants = device.features().contains(wgpu::Features::PUSH_CONSTANTS)
+            && device.limits().max_push_constant_size >= DRAW_TRANSFORM_SIZE;
+        let draw_transform = if push_constants {
+            "var<push_constant> draw_transform: DrawTransform;"
+        } else {
+            "@group(0) @binding(1)\nvar<uniform> draw_transform: DrawTransform;"
+        };
+        let output_color = if format.is_srgb() {
+            "fn output_color(color: vec4<f32>) -> vec4<f32> {\n    return srgb_to_linear(color);\n}"
+        } else {
+            "fn output_color(color: vec4<f32>) -> vec4<f32> {\n    return color;\n}"
+        };
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+            source: wgpu::ShaderSource::Wgsl(
+                include_str!("shader.wgsl")
+                    .replace("//!DRAW_TRANSFORM", draw_transform)
+                    .replace("//!OUTPUT_COLOR", output_color)
+                    .into(),
+            ),
         });
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -122,29 +216,59 @@ impl WgpuContext {
             mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
         });
+        let linear_texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
 
+        let camera_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let transform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: true,
+                min_binding_size: NonZeroU64::new(DRAW_TRANSFORM_SIZE as u64),
+            },
+            count: None,
+        };
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
+                entries: if push_constants {
+                    &[camera_entry][..]
+                } else {
+                    &[camera_entry, transform_entry][..]
+                },
                 label: Some("camera_bind_group_layout"),
             });
 
-        let (depth_texture, depth_view) = create_depth_texture(&device, size.width, size.height);
-
+        let push_constant_ranges = if push_constants {
+            vec![wgpu::PushConstantRange {
+                stages: wgpu::ShaderStages::VERTEX,
+                range: 0..DRAW_TRANSFORM_SIZE,
+            }]
+        } else {
+            Vec::new()
+        };
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[&camera_bind_group_layout, &texture_bind_group_layout],
-                push_constant_ranges: &[],
+                push_constant_ranges: &push_constant_ranges,
             });
 
         let primitive_state = wgpu::PrimitiveState {
@@ -181,7 +305,7 @@ impl WgpuContext {
                     module: &shader,
                     entry_point: "transparent_fs_main",
                     targets: &[Some(wgpu::ColorTargetState {
-                        format: config.format,
+                        format,
                         blend: Some(wgpu::BlendState::REPLACE), // TODO: is this right?
                         write_mask: wgpu::ColorWrites::ALL,
                     })],
@@ -204,7 +328,7 @@ impl WgpuContext {
                     module: &shader,
                     entry_point: "transparent_fs_main",
                     targets: &[Some(wgpu::ColorTargetState {
-                        format: config.format,
+                        format,
                         blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                         write_mask: wgpu::ColorWrites::ALL,
                     })],
@@ -227,7 +351,7 @@ impl WgpuContext {
                     module: &shader,
                     entry_point: "subpixel_r_fs_main",
                     targets: &[Some(wgpu::ColorTargetState {
-                        format: config.format,
+                        format,
                         blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                         write_mask: wgpu::ColorWrites::RED | wgpu::ColorWrites::ALPHA,
                     })],
@@ -250,7 +374,7 @@ impl WgpuContext {
                     module: &shader,
                     entry_point: "subpixel_g_fs_main",
                     targets: &[Some(wgpu::ColorTargetState {
-                        format: config.format,
+                        format,
                         blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                         write_mask: wgpu::ColorWrites::GREEN | wgpu::ColorWrites::ALPHA,
                     })],
@@ -273,93 +397,504 @@ impl WgpuContext {
                     module: &shader,
                     entry_point: "subpixel_b_fs_main",
                     targets: &[Some(wgpu::ColorTargetState {
-                        format: config.format,
+                        format,
                         blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                         write_mask: wgpu::ColorWrites::BLUE | wgpu::ColorWrites::ALPHA,
                     })],
                 }),
                 primitive: primitive_state,
+                depth_stencil: Some(depth_stencil_state.clone()),
+                multisample: multisample_state,
+                multiview: None,
+            });
+        let grayscale_render_pipeline =
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Grayscale Render Pipeline"),
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[Vertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "grayscale_fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: primitive_state,
                 depth_stencil: Some(depth_stencil_state),
                 multisample: multisample_state,
                 multiview: None,
             });
 
-        // data setup!
-
-        let camera_uniform = CameraUniform::new(size.width, size.height, scale_factor);
-
-        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Camera Buffer"),
-            contents: bytemuck::cast_slice(&[camera_uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-
-        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
-            label: Some("camera_bind_group"),
-        });
+        // uniforms bound at a dynamic offset have to be aligned
+        let transform_stride = (device.limits().min_uniform_buffer_offset_alignment as u64)
+            .max(DRAW_TRANSFORM_SIZE as u64);
 
-        WgpuContext {
+        Renderer {
             instance,
             adapter,
-            surface,
             device,
             queue,
+            format,
             shader,
-            config,
             texture_bind_group_layout,
             texture_sampler,
+            linear_texture_sampler,
             camera_bind_group_layout,
-            depth_texture,
-            depth_view,
             render_pipeline_layout,
             opaque_render_pipeline,
             transparent_render_pipeline,
             subpixel_r_render_pipeline,
             subpixel_g_render_pipeline,
             subpixel_b_render_pipeline,
+            grayscale_render_pipeline,
+            push_constants,
+            transform_stride,
+        }
+    }
+}
+
+impl Deref for WgpuContext {
+    type Target = Renderer;
+
+    fn deref(&self) -> &Renderer {
+        &self.renderer
+    }
+}
+
+impl WgpuContext {
+    /// Creates a context rendering to `window`. `present_mode` falls back to
+    /// [`wgpu::PresentMode::Fifo`] if the surface doesn't support it.
+    ///
+    /// `surface_format` is used if the surface supports it, otherwise the
+    /// first non-sRGB format is picked. Colors come out the same either way,
+    /// but with an sRGB format they're blended in linear space.
+    ///
+    /// If the window has no area the surface isn't configured until the
+    /// first [`WgpuContext::resize`] to a real size, and nothing is rendered
+    /// before that.
+    pub fn new(
+        window: &Window,
+        clear_color: Color,
+        present_mode: wgpu::PresentMode,
+        surface_format: Option<wgpu::TextureFormat>,
+        options: &DeviceOptions,
+    ) -> WgpuContext {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: options.backends,
+            dx12_shader_compiler: Default::default(),
+        });
+        let surface =
+            unsafe { instance.create_surface(&window) }.expect("failed to create surface");
+
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: options.power_preference,
+            compatible_surface: Some(&surface),
+            force_fallback_adapter: options.force_fallback_adapter,
+        }))
+        .expect("failed to fetch adapter");
+        log_adapter(&adapter);
+
+        let (device, queue) = request_device(&adapter, options);
+
+        let surface_caps = surface.get_capabilities(&adapter);
+        let format = match surface_format {
+            Some(format) if surface_caps.formats.contains(&format) => format,
+            requested => {
+                if let Some(format) = requested {
+                    log::warn!("Surface format {:?} isn't supported", format);
+                }
+                surface_caps
+                    .formats
+                    .iter()
+                    .copied()
+                    .find(|f| !f.is_srgb())
+                    .unwrap_or(surface_caps.formats[0])
+            }
+        };
+        log::info!("Using surface format {:?}", format);
+
+        let renderer = Renderer::new(instance, adapter, device, queue, format);
+        WgpuContext::for_window(
+            Arc::new(renderer),
+            window,
+            surface,
+            clear_color,
+            present_mode,
+        )
+    }
+
+    /// Creates a context rendering to another window with the renderer of
+    /// an existing context, see [`WgpuContext::renderer`]. The window's
+    /// surface has to support the renderer's format, which surfaces on the
+    /// same adapter usually do.
+    pub fn with_renderer(
+        renderer: Arc<Renderer>,
+        window: &Window,
+        clear_color: Color,
+        present_mode: wgpu::PresentMode,
+    ) -> WgpuContext {
+        let surface =
+            unsafe { renderer.instance.create_surface(&window) }.expect("failed to create surface");
+        let surface_caps = surface.get_capabilities(&renderer.adapter);
+        if !surface_caps.formats.contains(&renderer.format) {
+            log::error!(
+                "Surface doesn't support the renderer's format {:?}",
+                renderer.format
+            );
+        }
+        WgpuContext::for_window(renderer, window, surface, clear_color, present_mode)
+    }
+
+    fn for_window(
+        renderer: Arc<Renderer>,
+        window: &Window,
+        surface: wgpu::Surface,
+        clear_color: Color,
+        present_mode: wgpu::PresentMode,
+    ) -> WgpuContext {
+        let scale_factor = window.scale_factor() as f32;
+        let size = window.inner_size();
+        let surface_caps = surface.get_capabilities(&renderer.adapter);
+        // configuring a surface with no area fails, so the depth texture is
+        // created at 1x1 and the surface left for the first resize
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: renderer.format,
+            width: size.width.max(1),
+            height: size.height.max(1),
+            present_mode: supported_present_mode(&surface_caps, present_mode),
+            alpha_mode: surface_caps.alpha_modes[0],
+            view_formats: vec![],
+        };
+
+        let mut context = WgpuContext::from_renderer(
+            renderer,
+            Some(surface),
+            None,
+            config,
+            scale_factor,
+            clear_color,
+        );
+        if size.width > 0 && size.height > 0 {
+            context.configure_surface();
+        }
+        context
+    }
+
+    /// Creates a context without a window that renders into an owned
+    /// texture of `width` by `height` pixels, at least 1x1. Use
+    /// [`WgpuContext::render_to_buffer`] to draw and read back the pixels.
+    pub fn new_headless(
+        width: u32,
+        height: u32,
+        scale_factor: f32,
+        clear_color: Color,
+        options: &DeviceOptions,
+    ) -> WgpuContext {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: options.backends,
+            dx12_shader_compiler: Default::default(),
+        });
+
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: options.power_preference,
+            compatible_surface: None,
+            force_fallback_adapter: options.force_fallback_adapter,
+        }))
+        .expect("failed to fetch adapter");
+        log_adapter(&adapter);
+
+        let (device, queue) = request_device(&adapter, options);
+
+        let renderer = Renderer::new(instance, adapter, device, queue, TARGET_TEXTURE_FORMAT);
+        WgpuContext::with_renderer_headless(
+            Arc::new(renderer),
+            width,
+            height,
+            scale_factor,
+            clear_color,
+        )
+    }
+
+    /// Creates a headless context like [`WgpuContext::new_headless`] with
+    /// the renderer of an existing context. The texture has the renderer's
+    /// format, but is still read back as RGBA.
+    pub fn with_renderer_headless(
+        renderer: Arc<Renderer>,
+        width: u32,
+        height: u32,
+        scale_factor: f32,
+        clear_color: Color,
+    ) -> WgpuContext {
+        let width = width.max(1);
+        let height = height.max(1);
+        // not used to configure a surface, but keeps track of the target
+        // size and format
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: renderer.format,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+        };
+        let target_texture =
+            create_target_texture(&renderer.device, width, height, renderer.format);
+
+        WgpuContext::from_renderer(
+            renderer,
+            None,
+            Some(target_texture),
+            config,
+            scale_factor,
+            clear_color,
+        )
+    }
+
+    fn from_renderer(
+        renderer: Arc<Renderer>,
+        surface: Option<wgpu::Surface>,
+        target_texture: Option<wgpu::Texture>,
+        config: wgpu::SurfaceConfiguration,
+        scale_factor: f32,
+        clear_color: Color,
+    ) -> WgpuContext {
+        let device = &renderer.device;
+        let (depth_texture, depth_view) = create_depth_texture(device, config.width, config.height);
+
+        let camera_uniform = CameraUniform::new(config.width, config.height, scale_factor);
+
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Camera Buffer"),
+            contents: bytemuck::cast_slice(&[camera_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let transform_buffer = (!renderer.push_constants).then(|| {
+            create_transform_buffer(device, renderer.transform_stride * INITIAL_TRANSFORMS)
+        });
+
+        let camera_bind_group = create_camera_bind_group(
+            device,
+            &renderer.camera_bind_group_layout,
+            &camera_buffer,
+            transform_buffer.as_ref(),
+        );
+
+        WgpuContext {
+            renderer,
+            surface,
+            target_texture,
+            config,
+            depth_texture,
+            depth_view,
             camera_uniform,
             camera_buffer,
             camera_bind_group,
+            scale_factor,
+            scroll_offset: (0., 0.),
+            zoom: 1.,
             vertex_buffer: None,
             index_buffer: None,
             clear_color: clear_color.into(),
+            surface_configured: false,
+            frame_stats: FrameStats::default(),
+            gpu_timer: None,
+            transforms: vec![DrawTransform::IDENTITY],
+            transform_buffer,
+            repaint_rect: None,
+        }
+    }
+
+    /// Returns the renderer of the context, to share with contexts for
+    /// other windows.
+    pub fn renderer(&self) -> &Arc<Renderer> {
+        &self.renderer
+    }
+
+    /// Returns the name, backend and type of the adapter in use.
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
+    }
+
+    /// Returns the statistics of the last frame rendered.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+
+    /// Enables timing frames on the GPU, reported in
+    /// [`FrameStats::gpu_ms`]. This waits for the GPU to finish each frame,
+    /// so it's only meant for profiling. Returns false if the device doesn't
+    /// support timestamp queries, which have to be requested with
+    /// [`DeviceOptions::features`].
+    pub fn set_gpu_timing(&mut self, enabled: bool) -> bool {
+        if !enabled {
+            self.gpu_timer = None;
+            return true;
+        }
+        if !self
+            .device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+        {
+            return false;
+        }
+        if self.gpu_timer.is_none() {
+            let query_set = self.device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("Frame Timestamps"),
+                ty: wgpu::QueryType::Timestamp,
+                count: 2,
+            });
+            let resolve_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Timestamp Resolve Buffer"),
+                size: TIMESTAMPS_SIZE,
+                usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+            let readback_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Timestamp Readback Buffer"),
+                size: TIMESTAMPS_SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            });
+            self.gpu_timer = Some(GpuTimer {
+                query_set,
+                resolve_buffer,
+                readback_buffer,
+            });
         }
+        true
+    }
+
+    /// Waits for the last submitted frame and returns the time between its
+    /// timestamps, if GPU timing is enabled.
+    fn read_gpu_time(&self) -> Option<f32> {
+        let timer = self.gpu_timer.as_ref()?;
+        let slice = timer.readback_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            if let Err(e) = result {
+                log::error!("Failed to map timestamp buffer: {e}");
+            }
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        let ticks = {
+            let data = slice.get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&data);
+            timestamps[1].saturating_sub(timestamps[0])
+        };
+        timer.readback_buffer.unmap();
+        let period = self.queue.get_timestamp_period();
+        Some(ticks as f32 * period / 1_000_000.)
     }
 
+    /// Resizes the render target. Sizes with no area are ignored.
     pub fn resize(&mut self, width: u32, height: u32, scale_factor: f32) {
+        if width == 0 || height == 0 {
+            return;
+        }
         self.config.width = width;
         self.config.height = height;
-        self.surface.configure(&self.device, &self.config);
-        self.camera_uniform.update(width, height, scale_factor);
+        self.configure_surface();
+        self.scale_factor = scale_factor;
+        self.update_camera();
+        (self.depth_texture, self.depth_view) = create_depth_texture(&self.device, width, height);
+        if self.target_texture.is_some() {
+            let texture = create_target_texture(&self.device, width, height, self.config.format);
+            self.target_texture = Some(texture);
+        }
+    }
+
+    /// Scrolls the view so that the pixel at `(x, y)` is drawn at the top
+    /// left corner of the surface.
+    pub fn set_scroll(&mut self, x: f32, y: f32) {
+        self.scroll_offset = (x, y);
+        self.update_camera();
+    }
+
+    /// Scales everything drawn by `zoom`, anchored at the top left corner of
+    /// the surface.
+    pub fn set_zoom(&mut self, zoom: f32) {
+        self.zoom = zoom;
+        self.update_camera();
+    }
+
+    fn update_camera(&mut self) {
+        self.camera_uniform.update(
+            self.config.width,
+            self.config.height,
+            self.scale_factor,
+            self.scroll_offset,
+            self.zoom,
+        );
         self.queue.write_buffer(
             &self.camera_buffer,
             0,
             bytemuck::cast_slice(&[self.camera_uniform]),
         );
-        (self.depth_texture, self.depth_view) = create_depth_texture(&self.device, width, height);
+    }
+
+    /// Switches the present mode, e.g. to [`wgpu::PresentMode::Mailbox`]
+    /// for lower latency while scrolling. Falls back to
+    /// [`wgpu::PresentMode::Fifo`] if the surface doesn't support it.
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        let Some(surface) = &self.surface else {
+            return;
+        };
+        let surface_caps = surface.get_capabilities(&self.adapter);
+        let present_mode = supported_present_mode(&surface_caps, present_mode);
+        if present_mode != self.config.present_mode {
+            self.config.present_mode = present_mode;
+            if self.surface_configured {
+                self.configure_surface();
+            }
+        }
+    }
+
+    fn configure_surface(&mut self) {
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+            self.surface_configured = true;
+        }
     }
 
     pub fn set_clear_color(&mut self, color: Color) {
         self.clear_color = color.into();
     }
 
+    /// Renders `display_list` to the window. With `dirty_rects`, in window
+    /// pixels, only the union of the rects is cleared and drawn to, and the
+    /// rest of the frame is loaded from the surface. That is only correct
+    /// if the surface keeps its contents between frames, which most
+    /// swapchains don't guarantee, so pass `None` to repaint everything
+    /// unless it's known to.
     pub fn render(
         &mut self,
         image_cache: &mut ImageCache,
         display_list: &DisplayList,
+        dirty_rects: Option<&[Rect]>,
     ) -> Result<(), ()> {
-        let output = match self.surface.get_current_texture() {
+        let start = Instant::now();
+        let Some(surface) = &self.surface else {
+            log::error!("Can't render to a surface from a headless context");
+            return Err(());
+        };
+        if !self.surface_configured {
+            return Ok(());
+        }
+        let output = match surface.get_current_texture() {
             Ok(output) => output,
             Err(e) => {
                 log::error!("Failed to get current texture: {e}");
                 match e {
                     wgpu::SurfaceError::Lost => {
-                        self.surface.configure(&self.device, &self.config);
+                        self.configure_surface();
                     }
                     wgpu::SurfaceError::OutOfMemory => {
                         return Err(());
@@ -370,10 +905,179 @@ impl WgpuContext {
             }
         };
 
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Encoder"),
+            });
+        let repaint_rect = dirty_rects.map(union_rect);
+        if !self.draw(&mut encoder, &view, image_cache, display_list, repaint_rect) {
+            return Ok(());
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+        self.frame_stats.cpu_ms = start.elapsed().as_secs_f32() * 1000.;
+        self.frame_stats.gpu_ms = self.read_gpu_time();
+        output.present();
+        Ok(())
+    }
+
+    /// Renders `display_list` into the texture of a headless context like
+    /// [`WgpuContext::render`], without reading it back. The texture keeps
+    /// its contents between frames, so repainting only `dirty_rects` is
+    /// always correct here. Returns false if drawing failed.
+    ///
+    /// Panics if the context isn't headless.
+    pub fn render_to_texture(
+        &mut self,
+        image_cache: &mut ImageCache,
+        display_list: &DisplayList,
+        dirty_rects: Option<&[Rect]>,
+    ) -> bool {
+        let start = Instant::now();
+        let view = self
+            .target_texture
+            .as_ref()
+            .expect("render_to_texture requires a headless context")
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Offscreen Render Encoder"),
+            });
+        let repaint_rect = dirty_rects.map(union_rect);
+        if !self.draw(&mut encoder, &view, image_cache, display_list, repaint_rect) {
+            return false;
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+        self.frame_stats.cpu_ms = start.elapsed().as_secs_f32() * 1000.;
+        self.frame_stats.gpu_ms = self.read_gpu_time();
+        true
+    }
+
+    /// Renders `display_list` into the texture of a headless context and
+    /// returns its pixels as tightly packed RGBA rows, or `None` if drawing
+    /// failed, e.g. because a texture atlas was missing.
+    ///
+    /// Panics if the context isn't headless.
+    pub fn render_to_buffer(
+        &mut self,
+        image_cache: &mut ImageCache,
+        display_list: &DisplayList,
+    ) -> Option<Vec<u8>> {
+        let start = Instant::now();
+        let view = self
+            .target_texture
+            .as_ref()
+            .expect("render_to_buffer requires a headless context")
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Offscreen Render Encoder"),
+            });
+        if !self.draw(&mut encoder, &view, image_cache, display_list, None) {
+            return None;
+        }
+
+        let width = self.config.width;
+        let height = self.config.height;
+        // rows of a texture copy have to be aligned
+        let unpadded_bytes_per_row = width * 4;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.next_multiple_of(align);
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: self.target_texture.as_ref().unwrap(),
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+        self.frame_stats.cpu_ms = start.elapsed().as_secs_f32() * 1000.;
+        self.frame_stats.gpu_ms = self.read_gpu_time();
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            if let Err(e) = result {
+                log::error!("Failed to map readback buffer: {e}");
+            }
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+        if matches!(
+            self.config.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            // e.g. with the renderer of a window
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        Some(pixels)
+    }
+
+    /// Records the draw commands of `display_list` into `encoder`, rendering
+    /// to `view`. With a `repaint_rect` everything is scissored to it, and
+    /// it's cleared with a quad rather than clearing the whole target.
+    /// Returns false if a texture atlas was missing.
+    fn draw(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        image_cache: &mut ImageCache,
+        display_list: &DisplayList,
+        repaint_rect: Option<Rect>,
+    ) -> bool {
+        self.repaint_rect = repaint_rect;
         // update texture buffers for atlases
+        let mut atlas_uploads = 0;
         for atlas in image_cache.atlas_iter_mut() {
-            atlas.update_texture(&self.queue);
+            if atlas.update_texture(&self.queue) {
+                atlas_uploads += 1;
+            }
         }
+        self.frame_stats = FrameStats {
+            vertex_count: display_list.vertices().len(),
+            index_count: display_list.indices().len(),
+            draw_count: draw_count(display_list.commands()),
+            atlas_uploads,
+            cpu_ms: 0.,
+            gpu_ms: None,
+        };
+        if let Some(timer) = &self.gpu_timer {
+            encoder.write_timestamp(&timer.query_set, 0);
+        }
+        self.update_transforms(display_list.commands());
 
         // TODO: don't recreate each time unless necessary!
         let vertex_data = display_list.vertices();
@@ -394,23 +1098,35 @@ impl WgpuContext {
                 usage: wgpu::BufferUsages::INDEX,
             });
         self.index_buffer = Some(index_buffer);
+        let clear_buffer = repaint_rect.map(|rect| {
+            let vertices = self.clear_quad(rect);
+            self.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Clear Vertex Buffer"),
+                    contents: bytemuck::cast_slice(&vertices),
+                    usage: wgpu::BufferUsages::VERTEX,
+                })
+        });
+        // like the shader, the clear color has to be linear for an sRGB
+        // target
+        let clear_color = if self.config.format.is_srgb() {
+            linear_color(self.clear_color)
+        } else {
+            self.clear_color
+        };
+        let color_load = match repaint_rect {
+            Some(_) => wgpu::LoadOp::Load,
+            None => wgpu::LoadOp::Clear(clear_color),
+        };
 
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(self.clear_color),
+                        load: color_load,
                         store: true,
                     },
                 })],
@@ -423,14 +1139,30 @@ impl WgpuContext {
                     stencil_ops: None,
                 }),
             });
-            // bind the camera bind group
-            render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+            // bind the camera bind group, with the identity transform
+            let mut state = DrawState {
+                atlas_index: 0,
+                scissor: None,
+                transform: DrawTransform::IDENTITY,
+            };
+            if self.push_constants {
+                render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+            }
+            self.set_transform(&mut render_pass, state.transform);
             // we need to bind something to the texture bind group even if we don't use it
             let Some(atlas_bind_group) = image_cache.get_bind_group(0) else {
                 log::error!("Missing atlas at index 0");
-                return Ok(());
+                return false;
             };
             render_pass.set_bind_group(1, atlas_bind_group, &[]);
+            if let Some(clear_buffer) = &clear_buffer {
+                // the color attachment is loaded, so clear just the
+                // repainted area
+                self.set_scissor(&mut render_pass, None);
+                render_pass.set_pipeline(&self.opaque_render_pipeline);
+                render_pass.set_vertex_buffer(0, clear_buffer.slice(..));
+                render_pass.draw(0..6, 0..1);
+            }
             if let (Some(vertex_buffer), Some(index_buffer)) =
                 (&self.vertex_buffer, &self.index_buffer)
             {
@@ -438,52 +1170,413 @@ impl WgpuContext {
                 render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
             }
             //println!("....... >");
-            let mut current_pipeline = Pipeline::Opaque;
-            for command in display_list.commands() {
-                //println!("{:?}", command);
-                match *command {
-                    Command::BindTexture(atlas_index) => {
-                        let Some(atlas_bind_group) = image_cache.get_bind_group(atlas_index) else {
-                            log::error!("Missing atlas at index {}", atlas_index);
-                            return Ok(());
-                        };
-                        render_pass.set_bind_group(1, atlas_bind_group, &[]);
-                    }
-                    Command::Draw { start, count } => {
-                        if matches!(current_pipeline, Pipeline::Subpixel) {
-                            render_pass.set_pipeline(&self.subpixel_r_render_pipeline);
-                            render_pass.draw_indexed(start..(start + count), 0, 0..1);
-                            render_pass.set_pipeline(&self.subpixel_g_render_pipeline);
-                            render_pass.draw_indexed(start..(start + count), 0, 0..1);
-                            render_pass.set_pipeline(&self.subpixel_b_render_pipeline);
-                            render_pass.draw_indexed(start..(start + count), 0, 0..1);
-                        } else {
-                            render_pass.draw_indexed(start..(start + count), 0, 0..1);
-                        }
-                    }
+            let commands = display_list.commands();
+            let mut i = 0;
+            while i < commands.len() {
+                //println!("{:?}", commands[i]);
+                match commands[i] {
                     Command::BindPipeline(pipeline) => match pipeline {
                         Pipeline::Opaque => {
-                            current_pipeline = Pipeline::Opaque;
                             render_pass.set_pipeline(&self.opaque_render_pipeline);
                         }
                         Pipeline::Transparent => {
-                            current_pipeline = Pipeline::Transparent;
                             render_pass.set_pipeline(&self.transparent_render_pipeline);
                         }
                         Pipeline::Subpixel => {
-                            current_pipeline = Pipeline::Subpixel;
+                            // run all the commands up to the next pipeline
+                            // change once per channel, rather than switching
+                            // pipelines for every draw. this is the same order
+                            // the glyphs within a single draw are rendered in.
+                            let end = commands[i + 1..]
+                                .iter()
+                                .position(|command| matches!(command, Command::BindPipeline(_)))
+                                .map_or(commands.len(), |n| i + 1 + n);
+                            let group = &commands[i + 1..end];
+                            // restored before replaying the group for the
+                            // next channel
+                            let start_state = state;
+                            for (pass, pipeline) in [
+                                &self.subpixel_r_render_pipeline,
+                                &self.subpixel_g_render_pipeline,
+                                &self.subpixel_b_render_pipeline,
+                            ]
+                            .into_iter()
+                            .enumerate()
+                            {
+                                if pass > 0 {
+                                    state = start_state;
+                                    let Some(atlas_bind_group) =
+                                        image_cache.get_bind_group(state.atlas_index)
+                                    else {
+                                        log::error!("Missing atlas at index {}", state.atlas_index);
+                                        return false;
+                                    };
+                                    render_pass.set_bind_group(1, atlas_bind_group, &[]);
+                                    self.set_scissor(&mut render_pass, state.scissor);
+                                    self.set_transform(&mut render_pass, state.transform);
+                                }
+                                render_pass.set_pipeline(pipeline);
+                                for command in group {
+                                    if !self.execute(
+                                        &mut render_pass,
+                                        image_cache,
+                                        command,
+                                        &mut state,
+                                    ) {
+                                        return false;
+                                    }
+                                }
+                            }
+                            i = end;
+                            continue;
+                        }
+                        Pipeline::Grayscale => {
+                            render_pass.set_pipeline(&self.grayscale_render_pipeline);
                         }
                     },
+                    ref command => {
+                        if !self.execute(&mut render_pass, image_cache, command, &mut state) {
+                            return false;
+                        }
+                    }
                 }
+                i += 1;
             }
         }
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
-        Ok(())
+        if let Some(timer) = &self.gpu_timer {
+            encoder.write_timestamp(&timer.query_set, 1);
+            encoder.resolve_query_set(&timer.query_set, 0..2, &timer.resolve_buffer, 0);
+            encoder.copy_buffer_to_buffer(
+                &timer.resolve_buffer,
+                0,
+                &timer.readback_buffer,
+                0,
+                TIMESTAMPS_SIZE,
+            );
+        }
+        true
+    }
+
+    /// Runs a command other than [`Command::BindPipeline`], tracking the
+    /// bound atlas, scissor rectangle and transform in `state`. Returns false
+    /// if the atlas to bind is missing.
+    fn execute<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        image_cache: &'a ImageCache,
+        command: &Command,
+        state: &mut DrawState,
+    ) -> bool {
+        match *command {
+            Command::BindTexture(index) => {
+                let Some(atlas_bind_group) = image_cache.get_bind_group(index) else {
+                    log::error!("Missing atlas at index {}", index);
+                    return false;
+                };
+                render_pass.set_bind_group(1, atlas_bind_group, &[]);
+                state.atlas_index = index;
+            }
+            Command::Draw { start, count } => {
+                render_pass.draw_indexed(start..(start + count), 0, 0..1);
+            }
+            Command::SetScissor(rect) => {
+                state.scissor = Some(rect);
+                self.set_scissor(render_pass, state.scissor);
+            }
+            Command::ResetScissor => {
+                state.scissor = None;
+                self.set_scissor(render_pass, state.scissor);
+            }
+            Command::SetTransform(transform) => {
+                state.transform = transform;
+                self.set_transform(render_pass, transform);
+            }
+            Command::BindPipeline(_) => {}
+        }
+        true
+    }
+
+    /// Collects the distinct transforms set by `commands` and, without push
+    /// constants, writes them to the transform buffer, growing it if needed.
+    fn update_transforms(&mut self, commands: &[Command]) {
+        self.transforms.clear();
+        self.transforms.push(DrawTransform::IDENTITY);
+        for command in commands {
+            if let Command::SetTransform(transform) = command {
+                if !self.transforms.contains(transform) {
+                    self.transforms.push(*transform);
+                }
+            }
+        }
+        let Some(buffer) = &self.transform_buffer else {
+            return;
+        };
+        let size = self.transforms.len() as u64 * self.transform_stride;
+        if buffer.size() < size {
+            let buffer = create_transform_buffer(&self.device, size.next_power_of_two());
+            self.camera_bind_group = create_camera_bind_group(
+                &self.device,
+                &self.camera_bind_group_layout,
+                &self.camera_buffer,
+                Some(&buffer),
+            );
+            self.transform_buffer = Some(buffer);
+        }
+        let mut data = vec![0u8; size as usize];
+        for (slot, transform) in data
+            .chunks_exact_mut(self.transform_stride as usize)
+            .zip(&self.transforms)
+        {
+            slot[..DRAW_TRANSFORM_SIZE as usize].copy_from_slice(bytemuck::bytes_of(transform));
+        }
+        if let Some(buffer) = &self.transform_buffer {
+            self.queue.write_buffer(buffer, 0, &data);
+        }
+    }
+
+    /// Applies `transform` to the following draws, either as push constants
+    /// or by rebinding the camera bind group at the transform's slot.
+    fn set_transform<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        transform: DrawTransform,
+    ) {
+        if self.push_constants {
+            render_pass.set_push_constants(
+                wgpu::ShaderStages::VERTEX,
+                0,
+                bytemuck::bytes_of(&transform),
+            );
+        } else {
+            // every transform in the display list was collected by
+            // update_transforms
+            let slot = self
+                .transforms
+                .iter()
+                .position(|t| *t == transform)
+                .unwrap_or(0);
+            let offset = (slot as u64 * self.transform_stride) as u32;
+            render_pass.set_bind_group(0, &self.camera_bind_group, &[offset]);
+        }
+    }
+
+    /// Restricts drawing to `rect`, or to the whole target if `None`, within
+    /// the area being repainted.
+    fn set_scissor(&self, render_pass: &mut wgpu::RenderPass, rect: Option<Rect>) {
+        let target = Rect::new(0., 0., self.config.width as f32, self.config.height as f32);
+        let target = match self.repaint_rect {
+            Some(repaint_rect) => target.intersect(&repaint_rect).unwrap_or_default(),
+            None => target,
+        };
+        // clips are in document space, so follow the camera
+        let rect = match rect {
+            Some(rect) => target
+                .intersect(&Rect::new(
+                    (rect.x - self.scroll_offset.0) * self.zoom,
+                    (rect.y - self.scroll_offset.1) * self.zoom,
+                    rect.width * self.zoom,
+                    rect.height * self.zoom,
+                ))
+                .unwrap_or_default(),
+            None => target,
+        };
+        let (x, y, width, height) = scissor_rect(rect, self.config.width, self.config.height);
+        render_pass.set_scissor_rect(x, y, width, height);
+    }
+
+    /// Returns the two triangles of a quad filling `rect`, in window pixels,
+    /// with the clear color, behind anything drawn with a depth below 1.
+    fn clear_quad(&self, rect: Rect) -> [Vertex; 6] {
+        let c = self.clear_color;
+        let to_u8 = |v: f64| (v * 255.).round().clamp(0., 255.) as u8;
+        let color = Color::new(to_u8(c.r), to_u8(c.g), to_u8(c.b), to_u8(c.a));
+        // the camera maps document space to the window
+        let left = rect.x / self.zoom + self.scroll_offset.0;
+        let top = rect.y / self.zoom + self.scroll_offset.1;
+        let right = left + rect.width / self.zoom;
+        let bottom = top + rect.height / self.zoom;
+        let vertex = |x: f32, y: f32| Vertex {
+            pos: [x, y, CLEAR_DEPTH, 0.],
+            color,
+            uv: [0., 0.],
+            rect: [0., 0., 0.],
+            end_color: color,
+        };
+        [
+            vertex(left, top),
+            vertex(left, bottom),
+            vertex(right, bottom),
+            vertex(left, top),
+            vertex(right, bottom),
+            vertex(right, top),
+        ]
     }
 }
 
+/// Render pass state tracked while executing commands.
+#[derive(Copy, Clone)]
+struct DrawState {
+    atlas_index: usize,
+    scissor: Option<Rect>,
+    transform: DrawTransform,
+}
+
+/// Returns the smallest rect containing all of `rects`, empty if there
+/// are none.
+fn union_rect(rects: &[Rect]) -> Rect {
+    rects
+        .iter()
+        .copied()
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or_default()
+}
+
+/// Returns the number of draw calls `commands` make, with the draws after
+/// [`Pipeline::Subpixel`] made once per channel.
+fn draw_count(commands: &[Command]) -> usize {
+    let mut per_draw = 1;
+    let mut count = 0;
+    for command in commands {
+        match command {
+            Command::BindPipeline(Pipeline::Subpixel) => per_draw = 3,
+            Command::BindPipeline(_) => per_draw = 1,
+            Command::Draw { .. } => count += per_draw,
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Returns `requested` if the surface supports it, otherwise `Fifo`, which
+/// is always supported.
+fn supported_present_mode(
+    surface_caps: &wgpu::SurfaceCapabilities,
+    requested: wgpu::PresentMode,
+) -> wgpu::PresentMode {
+    if surface_caps.present_modes.contains(&requested) {
+        requested
+    } else {
+        wgpu::PresentMode::Fifo
+    }
+}
+
+/// Converts an sRGB encoded color to linear.
+fn linear_color(color: wgpu::Color) -> wgpu::Color {
+    let linear = |c: f64| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    wgpu::Color {
+        r: linear(color.r),
+        g: linear(color.g),
+        b: linear(color.b),
+        a: color.a,
+    }
+}
+
+/// Depth of the quad clearing the repainted area, just in front of the
+/// depth buffer's clear value so it passes the depth test.
+const CLEAR_DEPTH: f32 = 1. - f32::EPSILON;
+
+/// Converts `rect` to a scissor rectangle of whole pixels within the render
+/// target, rounding its edges outwards.
+fn scissor_rect(rect: Rect, target_width: u32, target_height: u32) -> (u32, u32, u32, u32) {
+    let left = (rect.x.floor().max(0.) as u32).min(target_width);
+    let top = (rect.y.floor().max(0.) as u32).min(target_height);
+    let right = ((rect.x + rect.width).ceil().max(0.) as u32).clamp(left, target_width);
+    let bottom = ((rect.y + rect.height).ceil().max(0.) as u32).clamp(top, target_height);
+    (left, top, right - left, bottom - top)
+}
+
+/// Logs the adapter picked by [`DeviceOptions::power_preference`] and
+/// [`DeviceOptions::backends`].
+fn log_adapter(adapter: &wgpu::Adapter) {
+    let info = adapter.get_info();
+    log::info!(
+        "Using adapter {:?} ({:?}, {:?})",
+        info.name,
+        info.backend,
+        info.device_type
+    );
+}
+
+/// Requests a device with the features and limits of `options` that the
+/// adapter supports.
+fn request_device(adapter: &wgpu::Adapter, options: &DeviceOptions) -> (wgpu::Device, wgpu::Queue) {
+    let features = options.features & adapter.features();
+    if features != options.features {
+        log::warn!(
+            "Adapter doesn't support features {:?}",
+            options.features - features
+        );
+    }
+    let limits = if options.limits.check_limits(&adapter.limits()) {
+        options.limits.clone()
+    } else {
+        log::warn!("Adapter doesn't support the requested limits, using the defaults");
+        wgpu::Limits::default()
+    };
+    pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features,
+            limits,
+            label: None,
+        },
+        None,
+    ))
+    .expect("failed to fetch device")
+}
+
+/// Number of transforms the transform buffer initially has room for.
+const INITIAL_TRANSFORMS: u64 = 16;
+
+fn create_transform_buffer(device: &wgpu::Device, size: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Transform Buffer"),
+        size,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Creates the bind group of the camera and, without push constants, the
+/// transform buffer.
+fn create_camera_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    camera_buffer: &wgpu::Buffer,
+    transform_buffer: Option<&wgpu::Buffer>,
+) -> wgpu::BindGroup {
+    let camera_entry = wgpu::BindGroupEntry {
+        binding: 0,
+        resource: camera_buffer.as_entire_binding(),
+    };
+    let entries = match transform_buffer {
+        Some(buffer) => vec![
+            camera_entry,
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(DRAW_TRANSFORM_SIZE as u64),
+                }),
+            },
+        ],
+        None => vec![camera_entry],
+    };
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &entries,
+        label: Some("camera_bind_group"),
+    })
+}
+
 const DEPTH_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+const TARGET_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
 
 fn create_depth_texture(
     device: &wgpu::Device,
@@ -507,3 +1600,25 @@ fn create_depth_texture(
     let depth_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
     (depth_texture, depth_view)
 }
+
+fn create_target_texture(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("target_texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
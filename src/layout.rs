@@ -1,17 +1,162 @@
 use std::ops::Range;
 
-use swash::{Metrics, shape::cluster::Glyph};
+use swash::{GlyphId, Metrics, shape::{cluster::Glyph, Direction}};
 
-//use super::fonts::FontCacheKey;
+use crate::{fonts::{Font, FontId}, gfx::{color::Color, types::Rect}};
 
-#[derive(Debug)]
+/// A shaped cluster within a run.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    /// Source byte range of the cluster.
+    pub range: Range<usize>,
+    /// Range of the cluster's glyphs in [`Run::glyphs`].
+    pub glyphs: Range<usize>,
+    pub is_whitespace: bool,
+    pub is_tab: bool,
+    /// True if a word may be hyphenated after the cluster.
+    pub hyphen_after: bool,
+    /// First char of a cluster no font has glyphs for, drawn as a box, see
+    /// [`Layout::missing_glyph_rects`].
+    pub missing: Option<char>,
+}
+
+#[derive(Clone, Debug)]
 pub struct Run {
-    pub font_index: usize,
+    /// Font the run was shaped with, looked up through the
+    /// [`crate::fonts::FontSource`] that loaded it.
+    pub font_id: FontId,
     pub glyphs: Vec<Glyph>,
+    pub clusters: Vec<Cluster>,
     pub size: f32,
     pub metrics: Metrics,
     pub range: Range<usize>,
     pub coords: Vec<i16>,
+    /// Resolved direction of the run, from its bidi embedding level.
+    pub direction: Direction,
+    /// Bidi embedding level of the run.
+    pub level: u8,
+    /// Color the run's glyphs are drawn with.
+    pub color: Color,
+    /// Emboldening and slant to fake a face missing from the fonts.
+    pub synthesis: Synthesis,
+    /// Lines drawn under or through the run.
+    pub decoration: Decoration,
+    /// Glyph added after a cluster the line is hyphenated at, `None` if
+    /// the run has no hyphenation points or its font has no hyphen.
+    pub hyphen: Option<Glyph>,
+    /// Glyph ending a line truncated after one of the run's clusters,
+    /// `None` if the run's font has no ellipsis.
+    pub ellipsis: Option<Glyph>,
+    /// Extra space after each cluster other than tabs, in pixels, negative
+    /// to tighten the text. It's included in the advance of the last glyph
+    /// of each cluster, except for the cluster ending a line.
+    pub letter_spacing: f32,
+}
+
+/// Emboldening and slant applied when rasterizing a run, for styles the
+/// run's font doesn't have a face for.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct Synthesis {
+    /// Amount to embolden outlines by, in pixels.
+    pub embolden: f32,
+    /// Angle to slant glyphs by, in degrees.
+    pub skew: f32,
+}
+
+/// Lines drawn under or through a run, see [`Layout::decoration_rects`].
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct Decoration {
+    pub underline: bool,
+    pub strikethrough: bool,
+    /// Color of the lines, or `None` for the run's color.
+    pub color: Option<Color>,
+}
+
+impl Run {
+    /// Returns the advance of the cluster at `index`.
+    pub fn cluster_advance(&self, index: usize) -> f32 {
+        self.glyphs[self.clusters[index].glyphs.clone()]
+            .iter()
+            .map(|g| g.advance)
+            .sum()
+    }
+
+    /// Returns the total advance of the run.
+    pub fn advance(&self) -> f32 {
+        self.glyphs.iter().map(|g| g.advance).sum()
+    }
+
+    /// Creates a new run containing only the given range of clusters.
+    fn slice(&self, clusters: Range<usize>) -> Run {
+        let first = &self.clusters[clusters.start];
+        let last = &self.clusters[clusters.end - 1];
+        let glyph_start = first.glyphs.start;
+        let glyph_end = last.glyphs.end;
+        Run {
+            font_id: self.font_id,
+            glyphs: self.glyphs[glyph_start..glyph_end].to_vec(),
+            clusters: self.clusters[clusters.clone()]
+                .iter()
+                .map(|c| Cluster {
+                    range: c.range.clone(),
+                    glyphs: (c.glyphs.start - glyph_start)..(c.glyphs.end - glyph_start),
+                    is_whitespace: c.is_whitespace,
+                    is_tab: c.is_tab,
+                    hyphen_after: c.hyphen_after,
+                    missing: c.missing,
+                })
+                .collect(),
+            size: self.size,
+            metrics: self.metrics,
+            range: first.range.start..last.range.end,
+            coords: self.coords.clone(),
+            direction: self.direction,
+            level: self.level,
+            color: self.color,
+            synthesis: self.synthesis,
+            decoration: self.decoration,
+            hyphen: self.hyphen,
+            ellipsis: self.ellipsis,
+            letter_spacing: self.letter_spacing,
+        }
+    }
+
+    /// Appends the run's hyphen to its last cluster, for a line hyphenated
+    /// after it.
+    fn push_hyphen(&mut self) {
+        if let Some(hyphen) = self.hyphen {
+            self.push_to_last_cluster(hyphen);
+        }
+    }
+
+    /// Appends the run's ellipsis to its last cluster, for a line truncated
+    /// after it.
+    fn push_ellipsis(&mut self) {
+        if let Some(ellipsis) = self.ellipsis {
+            self.push_to_last_cluster(ellipsis);
+        }
+    }
+
+    fn push_to_last_cluster(&mut self, glyph: Glyph) {
+        let Some(last) = self.clusters.last_mut() else {
+            return;
+        };
+        self.glyphs.push(glyph);
+        last.glyphs.end = self.glyphs.len();
+    }
+
+    /// Puts the clusters of a right-to-left run, and their glyphs, into
+    /// visual order.
+    fn reverse(&mut self) {
+        let mut glyphs = Vec::with_capacity(self.glyphs.len());
+        self.clusters.reverse();
+        for cluster in &mut self.clusters {
+            let start = glyphs.len();
+            glyphs.extend_from_slice(&self.glyphs[cluster.glyphs.clone()]);
+            cluster.glyphs = start..glyphs.len();
+        }
+        self.glyphs = glyphs;
+    }
 }
 
 #[derive(Default, Debug)]
@@ -22,17 +167,387 @@ pub struct Line {
     pub leading: f32,
     pub above: f32,
     pub below: f32,
+    /// Horizontal offset of the start of the line, from its indent and
+    /// alignment.
+    pub x_offset: f32,
+    /// Indent of the line, see [`Layout::set_indent`]. Included in
+    /// `x_offset`.
+    pub indent: f32,
+    /// Byte offset of the line break ending the line, or `None` if the
+    /// line was wrapped or ends the document.
+    pub hard_break: Option<usize>,
+    /// True if clusters were dropped from the end of the line to fit the
+    /// width set with [`Layout::set_ellipsis`].
+    pub truncated: bool,
 }
 
 impl Line {
-    fn reset(&mut self) {
-        self.runs.clear();
+    /// Returns the total advance of the line.
+    pub fn advance(&self) -> f32 {
+        self.runs.iter().map(|run| run.advance()).sum()
+    }
+
+    /// Returns markers for the whitespace clusters of the line and for its
+    /// line break, with `x` relative to the start of the line. Markers on a
+    /// line without runs use `default_size`.
+    pub fn whitespace_markers(&self, default_size: f32) -> Vec<WhitespaceMarker> {
+        let mut markers = Vec::new();
+        let mut x = 0.;
+        let mut size = default_size;
+        for run in &self.runs {
+            size = run.size;
+            for (i, cluster) in run.clusters.iter().enumerate() {
+                let advance = run.cluster_advance(i);
+                if cluster.is_whitespace {
+                    markers.push(WhitespaceMarker {
+                        kind: if cluster.is_tab {
+                            WhitespaceKind::Tab
+                        } else {
+                            WhitespaceKind::Space
+                        },
+                        x,
+                        advance,
+                        size,
+                    });
+                }
+                x += advance;
+            }
+        }
+        if self.hard_break.is_some() {
+            markers.push(WhitespaceMarker {
+                kind: WhitespaceKind::LineBreak,
+                x,
+                advance: 0.,
+                size,
+            });
+        }
+        markers
+    }
+
+    /// Sets the vertical metrics of the line from the largest ascent,
+    /// descent and leading of `metrics`.
+    pub fn set_metrics(&mut self, metrics: impl IntoIterator<Item = Metrics>) {
+        self.ascent = 0.;
+        self.descent = 0.;
+        self.leading = 0.;
+        for metrics in metrics {
+            self.ascent = self.ascent.max(metrics.ascent);
+            self.descent = self.descent.max(metrics.descent);
+            self.leading = self.leading.max(metrics.leading);
+        }
+        // metrics are rounded so every line is a whole number of pixels
+        // tall, keeping baselines pixel aligned without drift over many
+        // lines. leading is rounded to an even value so it splits evenly
+        // between above and below.
+        self.ascent = self.ascent.round();
+        self.descent = self.descent.round();
+        self.leading = (self.leading * 0.5).round() * 2.;
+        self.below = (self.descent + self.leading * 0.5).round();
+        // baseline = y + above
+        self.above = (self.ascent + self.leading * 0.5).round();
+    }
+
+    /// Grows or shrinks the line to `height` pixels, rounded, by adding the
+    /// same amount above and below, so the baseline stays centered in the
+    /// extra space. Call after [`Line::set_metrics`].
+    fn set_height(&mut self, height: f32) {
+        let extra = height.round() - (self.above + self.below);
+        let half = (extra * 0.5).round();
+        self.above += half;
+        self.below += extra - half;
+    }
+
+    /// Returns the advance of the whitespace clusters at the end of the line.
+    fn trailing_whitespace(&self) -> f32 {
+        let mut advance = 0.;
+        for run in self.runs.iter().rev() {
+            for (i, cluster) in run.clusters.iter().enumerate().rev() {
+                if !cluster.is_whitespace {
+                    return advance;
+                }
+                advance += run.cluster_advance(i);
+            }
+        }
+        advance
+    }
+
+    /// Removes the letter spacing of the last cluster of the line, in
+    /// logical order, so that spacing only goes between clusters. Has to
+    /// happen before a hyphen is appended to that cluster.
+    fn trim_letter_spacing(&mut self) {
+        let Some(run) = self.runs.last_mut() else {
+            return;
+        };
+        let Some(cluster) = run.clusters.last() else {
+            return;
+        };
+        if run.letter_spacing != 0. && !cluster.is_tab && !cluster.glyphs.is_empty() {
+            run.glyphs[cluster.glyphs.end - 1].advance -= run.letter_spacing;
+        }
+    }
+
+    /// Drops clusters from the end of the line, in logical order, until it
+    /// fits `max_width` with the ellipsis of the last remaining run after
+    /// them. At least one cluster is kept, even if it doesn't fit.
+    fn truncate(&mut self, max_width: f32) {
+        if self.advance() <= max_width {
+            return;
+        }
+        let clusters = cluster_indices(&self.runs);
+        let mut keep = 1;
+        let mut x = 0.;
+        for (i, &(r, c)) in clusters.iter().enumerate() {
+            x += self.runs[r].cluster_advance(c);
+            let ellipsis = self.runs[r].ellipsis.map_or(0., |g| g.advance);
+            if x + ellipsis > max_width {
+                break;
+            }
+            keep = i + 1;
+        }
+        let Some(&(last_run, last_cluster)) = clusters.get(keep - 1) else {
+            return;
+        };
+        self.runs.truncate(last_run + 1);
+        if last_cluster + 1 < self.runs[last_run].clusters.len() {
+            self.runs[last_run] = self.runs[last_run].slice(0..last_cluster + 1);
+        }
+        self.runs[last_run].push_ellipsis();
+        self.truncated = true;
+    }
+
+    /// Widens the whitespace clusters of the line, other than trailing
+    /// whitespace, so that the line fills `width`.
+    fn justify(&mut self, width: f32) {
+        let extra = width - (self.advance() - self.trailing_whitespace());
+        let clusters = cluster_indices(&self.runs);
+        let Some(end) = clusters
+            .iter()
+            .rposition(|&(r, c)| !self.runs[r].clusters[c].is_whitespace)
+        else {
+            return;
+        };
+        let spaces = clusters[..end]
+            .iter()
+            .copied()
+            .filter(|&(r, c)| {
+                let cluster = &self.runs[r].clusters[c];
+                cluster.is_whitespace && !cluster.glyphs.is_empty()
+            })
+            .collect::<Vec<_>>();
+        if spaces.is_empty() || extra <= 0. {
+            return;
+        }
+        let per_space = extra / spaces.len() as f32;
+        for (r, c) in spaces {
+            let run = &mut self.runs[r];
+            let last = run.clusters[c].glyphs.end - 1;
+            run.glyphs[last].advance += per_space;
+        }
+    }
+
+    /// Puts the runs of the line, and the clusters within right-to-left
+    /// runs, into visual order (rule L2 of the bidi algorithm).
+    fn reorder(&mut self) {
+        let Some(max) = self.runs.iter().map(|run| run.level).max() else {
+            return;
+        };
+        let Some(min_odd) = self.runs.iter().map(|run| run.level).filter(|l| l % 2 == 1).min()
+        else {
+            // all left-to-right
+            return;
+        };
+        // from the highest level down to the lowest odd level, reverse every
+        // sequence of runs at that level or higher
+        for level in (min_odd..=max).rev() {
+            let mut i = 0;
+            while i < self.runs.len() {
+                if self.runs[i].level < level {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                while i < self.runs.len() && self.runs[i].level >= level {
+                    i += 1;
+                }
+                self.runs[start..i].reverse();
+            }
+        }
+        for run in &mut self.runs {
+            if run.direction == Direction::RightToLeft {
+                run.reverse();
+            }
+        }
+    }
+}
+
+/// Kind of whitespace marked by a [`WhitespaceMarker`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WhitespaceKind {
+    Space,
+    Tab,
+    LineBreak,
+}
+
+impl WhitespaceKind {
+    /// Returns the character drawn to show the whitespace.
+    pub fn marker(self) -> char {
+        match self {
+            WhitespaceKind::Space => '·',
+            WhitespaceKind::Tab => '→',
+            WhitespaceKind::LineBreak => '¶',
+        }
+    }
+}
+
+/// Position of a whitespace marker drawn when showing whitespace. Markers
+/// are drawn over the whitespace and don't take up any space themselves.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WhitespaceMarker {
+    pub kind: WhitespaceKind,
+    /// Offset of the start of the whitespace from the start of the line.
+    pub x: f32,
+    /// Advance of the whitespace, which the marker is centered in.
+    pub advance: f32,
+    /// Font size of the whitespace.
+    pub size: f32,
+}
+
+/// Glyph of a laid out document with its position, as yielded by
+/// [`Layout::positioned_glyphs`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PositionedGlyph<'a> {
+    /// Font the glyph's run was shaped with.
+    pub font_id: FontId,
+    pub id: GlyphId,
+    /// Position of the glyph's origin on the baseline, including its offset
+    /// from shaping.
+    pub x: f32,
+    pub y: f32,
+    pub advance: f32,
+    /// Font size of the glyph's run.
+    pub size: f32,
+    /// Normalized variation coordinates of the glyph's run.
+    pub coords: &'a [i16],
+    pub color: Color,
+    pub synthesis: Synthesis,
+}
+
+/// Direction text flows in, see [`Layout::set_writing_mode`].
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum WritingMode {
+    /// Glyphs advance to the right and lines stack downwards.
+    #[default]
+    HorizontalTb,
+    /// Glyphs advance downwards and lines stack to the left, as in vertical
+    /// CJK text. Lines still store their advance and alignment offset in
+    /// [`Line::advance`] and [`Line::x_offset`], along the vertical.
+    VerticalRl,
+}
+
+/// Height of lines, see [`Layout::set_line_height`].
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub enum LineHeight {
+    /// The ascent, descent and leading of the line's fonts.
+    #[default]
+    Normal,
+    /// A multiple of the normal height.
+    Multiple(f32),
+    /// A fixed height in pixels, whatever the fonts.
+    Absolute(f32),
+}
+
+impl LineHeight {
+    /// Returns the height of a line whose normal height is `normal`.
+    fn resolve(self, normal: f32) -> f32 {
+        match self {
+            LineHeight::Normal => normal,
+            LineHeight::Multiple(factor) => normal * factor,
+            LineHeight::Absolute(height) => height,
+        }
+    }
+}
+
+/// Horizontal alignment of lines within the available width.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum Alignment {
+    #[default]
+    Left,
+    Right,
+    Center,
+    /// Stretches whitespace so lines fill the width. The last line of a
+    /// paragraph is left aligned.
+    Justify,
+}
+
+/// Result of [`Layout::hit_test`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HitTestResult {
+    /// Index of the visual line in [`Layout::lines`].
+    pub line: usize,
+    /// Index of the run within the line.
+    pub run: usize,
+    /// Index of the first glyph of the hit cluster within the run.
+    pub glyph: usize,
+    /// Byte offset of the start of the hit cluster in the document.
+    ///
+    /// Points past the end of a line ending in a line break land before the
+    /// break: `offset` is then the offset of the break, `is_leading` is
+    /// true, and `run` and `glyph` point just past the end of the line.
+    pub offset: usize,
+    /// True if the point landed on the leading half of the cluster, which
+    /// is the right half in right-to-left runs.
+    pub is_leading: bool,
+}
+
+/// Logical line of runs as pushed by the parser, before wrapping.
+#[derive(Default, Debug)]
+struct Paragraph {
+    runs: Vec<Run>,
+    /// Source byte range of the paragraph, including its line break.
+    range: Range<usize>,
+    /// Byte offset of the paragraph's line break, if it has one.
+    hard_break: Option<usize>,
+    /// Metrics of lines without runs, e.g. empty lines, so they still have
+    /// a height.
+    metrics: Metrics,
+}
+
+impl Paragraph {
+    /// Moves the source ranges of the paragraph by `delta` bytes.
+    fn shift(&mut self, delta: isize) {
+        let shift = |range: &mut Range<usize>| {
+            range.start = (range.start as isize + delta) as usize;
+            range.end = (range.end as isize + delta) as usize;
+        };
+        shift(&mut self.range);
+        if let Some(offset) = &mut self.hard_break {
+            *offset = (*offset as isize + delta) as usize;
+        }
+        for run in &mut self.runs {
+            shift(&mut run.range);
+            for cluster in &mut run.clusters {
+                shift(&mut cluster.range);
+            }
+        }
     }
 }
 
 #[derive(Default)]
 pub struct Layout {
+    /// Visual lines, valid after [`Layout::finish`]. Runs, and the clusters
+    /// within them, are in visual order from left to right.
     pub lines: Vec<Line>,
+    paragraphs: Vec<Paragraph>,
+    wrap_width: Option<f32>,
+    alignment: Alignment,
+    writing_mode: WritingMode,
+    hyphenation: bool,
+    /// Width lines are truncated at, see [`Layout::set_ellipsis`].
+    ellipsis_width: Option<f32>,
+    line_height: LineHeight,
+    /// Indents of the first line of each paragraph and of the lines
+    /// wrapped after it, see [`Layout::set_indent`].
+    indent: (f32, f32),
 }
 
 impl Layout {
@@ -41,52 +556,924 @@ impl Layout {
     }
 
     pub fn reset(&mut self) {
-        for line in &mut self.lines {
-            line.reset();
-        }
+        self.paragraphs.clear();
+        self.lines.clear();
+    }
+
+    /// Sets the width in pixels at which lines are wrapped, or `None` to
+    /// disable wrapping. Takes effect on the next call to [`Layout::finish`].
+    pub fn set_wrap_width(&mut self, wrap_width: Option<f32>) {
+        self.wrap_width = wrap_width;
+    }
+
+    /// Sets the direction text flows in. Runs have to be shaped for the
+    /// writing mode, vertically for [`WritingMode::VerticalRl`], which
+    /// [`crate::document::Document::set_writing_mode`] takes care of. Only
+    /// [`Layout::positioned_glyphs`] follows vertical lines so far; hit
+    /// testing, cursors and decorations assume horizontal lines.
+    pub fn set_writing_mode(&mut self, writing_mode: WritingMode) {
+        self.writing_mode = writing_mode;
     }
 
+    pub fn writing_mode(&self) -> WritingMode {
+        self.writing_mode
+    }
+
+    /// Enables breaking words at hyphenation points, with a hyphen at the
+    /// end of the line, when wrapping would otherwise split a word between
+    /// any two clusters. The points are found when parsing, so this needs
+    /// the text to be parsed again, which
+    /// [`crate::document::Document::set_hyphenation`] takes care of.
+    pub fn set_hyphenation(&mut self, hyphenation: bool) {
+        self.hyphenation = hyphenation;
+    }
+
+    pub fn hyphenation(&self) -> bool {
+        self.hyphenation
+    }
+
+    /// Truncates lines wider than `max_width` pixels at a cluster boundary,
+    /// ending them with an ellipsis in the font of the last cluster kept,
+    /// or `None` to show lines in full. Lines are wrapped first, so this is
+    /// mostly useful for single line labels with wrapping disabled. Takes
+    /// effect on the next call to [`Layout::finish`], after which
+    /// [`Layout::is_truncated`] tells whether any text was cut off.
+    pub fn set_ellipsis(&mut self, max_width: Option<f32>) {
+        self.ellipsis_width = max_width;
+    }
+
+    /// Returns true if any line was truncated by the last call to
+    /// [`Layout::finish`].
+    pub fn is_truncated(&self) -> bool {
+        self.lines.iter().any(|line| line.truncated)
+    }
+
+    /// Sets the height of lines, e.g. [`LineHeight::Multiple`] of 1.5 for
+    /// more space between lines. The space is split evenly above and below
+    /// the text. Takes effect on the next call to [`Layout::finish`].
+    pub fn set_line_height(&mut self, line_height: LineHeight) {
+        self.line_height = line_height;
+    }
+
+    /// Indents the first line of each paragraph by `first_line` pixels and
+    /// the lines it wraps onto by `hanging` pixels. Indented lines wrap,
+    /// align and justify within what's left of the width. Takes effect on
+    /// the next call to [`Layout::finish`].
+    pub fn set_indent(&mut self, first_line: f32, hanging: f32) {
+        self.indent = (first_line, hanging);
+    }
+
+    /// Sets the horizontal alignment of lines. Lines are aligned within the
+    /// wrap width, or within the widest line if wrapping is disabled.
+    pub fn set_alignment(&mut self, alignment: Alignment) {
+        self.alignment = alignment;
+    }
+
+    /// Adds a run to the paragraph `line_no`. Glyphs and clusters are in
+    /// logical order; `level` is the bidi embedding level of the run.
+    /// `synthesis` fakes a bold or italic face `font` doesn't have.
+    /// `letter_spacing` must already be added to the glyph advances, see
+    /// [`Run::letter_spacing`].
+    #[allow(clippy::too_many_arguments)]
     pub fn push_run(
         &mut self,
         line_no: usize,
-        font_index: usize,
+        font: &Font,
         range: Range<usize>,
         glyphs: Vec<Glyph>,
+        clusters: Vec<Cluster>,
         size: f32,
-        metrics: Metrics,
+        level: u8,
+        color: Color,
+        synthesis: Synthesis,
+        decoration: Decoration,
+        letter_spacing: f32,
     ) {
-        println!("RUN: {} {} {:?} {:?}", line_no, font_index, range, glyphs);
-        while self.lines.len() <= line_no {
-            self.lines.push(Line::default());
+        while self.paragraphs.len() <= line_no {
+            self.paragraphs.push(Paragraph::default());
         }
-        let line = &mut self.lines[line_no];
-        line.runs.push(Run {
-            font_index,
+        let hyphen = if clusters.iter().any(|cluster| cluster.hyphen_after) {
+            char_glyph(font, size, '-')
+        } else {
+            None
+        };
+        let ellipsis = char_glyph(font, size, '…');
+        let paragraph = &mut self.paragraphs[line_no];
+        paragraph.runs.push(Run {
+            font_id: font.id(),
             glyphs,
+            clusters,
             size,
-            metrics: metrics.scale(size),
+            metrics: font.metrics().scale(size),
             range,
-            coords: Vec::new(),
+            coords: font.coords().to_vec(),
+            direction: if level % 2 == 1 {
+                Direction::RightToLeft
+            } else {
+                Direction::LeftToRight
+            },
+            level,
+            color,
+            synthesis,
+            decoration,
+            hyphen,
+            ellipsis,
+            letter_spacing,
         });
     }
 
+    /// Records the source byte range of the paragraph `line_no`, once all of
+    /// its runs have been pushed, and the byte offset of the line break
+    /// ending it. `metrics` are those of the primary font at the text size,
+    /// used for lines without any runs so a cursor can be shown on them.
+    pub fn end_paragraph(
+        &mut self,
+        line_no: usize,
+        range: Range<usize>,
+        hard_break: Option<usize>,
+        metrics: Metrics,
+    ) {
+        while self.paragraphs.len() <= line_no {
+            self.paragraphs.push(Paragraph::default());
+        }
+        let paragraph = &mut self.paragraphs[line_no];
+        paragraph.range = range;
+        paragraph.hard_break = hard_break;
+        paragraph.metrics = metrics;
+    }
+
+    /// Returns the number of paragraphs (logical lines) in the layout.
+    pub fn paragraph_count(&self) -> usize {
+        self.paragraphs.len()
+    }
+
+    /// Returns the source byte range of the paragraph `line_no`.
+    pub fn paragraph_range(&self, line_no: usize) -> Option<Range<usize>> {
+        Some(self.paragraphs.get(line_no)?.range.clone())
+    }
+
+    /// Returns the runs of the paragraph `line_no`, in logical order.
+    pub fn paragraph_runs(&self, line_no: usize) -> Option<&[Run]> {
+        Some(&self.paragraphs.get(line_no)?.runs)
+    }
+
+    /// Returns the indices of the paragraphs whose source range touches
+    /// `range`, including paragraphs that merely share an endpoint.
+    pub fn paragraphs_touching(&self, range: Range<usize>) -> Range<usize> {
+        let start = self
+            .paragraphs
+            .partition_point(|p| p.range.end < range.start);
+        let end = self
+            .paragraphs
+            .partition_point(|p| p.range.start <= range.end);
+        start..end.max(start)
+    }
+
+    /// Removes the paragraphs at `lines` and moves the source ranges of the
+    /// paragraphs after them by `delta` bytes. Returns the source range
+    /// covered by the removed paragraphs.
+    pub fn remove_paragraphs(&mut self, lines: Range<usize>, delta: isize) -> Option<Range<usize>> {
+        let removed = self
+            .paragraphs
+            .drain(lines.clone())
+            .map(|p| p.range)
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end));
+        for paragraph in &mut self.paragraphs[lines.start..] {
+            paragraph.shift(delta);
+        }
+        removed
+    }
+
+    /// Returns the source byte range of the cluster containing the byte
+    /// `offset`, if it has been parsed.
+    pub fn cluster_range(&self, offset: usize) -> Option<Range<usize>> {
+        let index = self.paragraphs.partition_point(|p| p.range.end <= offset);
+        self.paragraphs
+            .get(index)?
+            .runs
+            .iter()
+            .flat_map(|run| &run.clusters)
+            .find(|cluster| cluster.range.contains(&offset))
+            .map(|cluster| cluster.range.clone())
+    }
+
+    /// Inserts the paragraphs parsed into `other` at index `line_no`.
+    pub fn insert_paragraphs(&mut self, line_no: usize, other: Layout) {
+        self.paragraphs.splice(line_no..line_no, other.paragraphs);
+    }
+
     pub fn finish(&mut self) {
-        for line in &mut self.lines {
-            line.ascent = 0.;
-            line.descent = 0.;
-            line.leading = 0.;
+        self.lines.clear();
+        // index of the last visual line of each paragraph
+        let mut paragraph_ends = Vec::with_capacity(self.paragraphs.len());
+        let (first_indent, hanging_indent) = self.indent;
+        for paragraph in &self.paragraphs {
+            let first = self.lines.len();
+            match self.wrap_width {
+                Some(width) => wrap(
+                    &paragraph.runs,
+                    width - first_indent,
+                    width - hanging_indent,
+                    &mut self.lines,
+                ),
+                None => {
+                    let mut line = Line {
+                        runs: paragraph.runs.clone(),
+                        ..Default::default()
+                    };
+                    line.trim_letter_spacing();
+                    self.lines.push(line);
+                }
+            }
+            for (i, line) in self.lines[first..].iter_mut().enumerate() {
+                line.indent = if i == 0 { first_indent } else { hanging_indent };
+            }
+            if let Some(line) = self.lines.last_mut() {
+                line.hard_break = paragraph.hard_break;
+            }
+            paragraph_ends.push(self.lines.len() - 1);
+        }
+        if let Some(max_width) = self.ellipsis_width {
+            for line in &mut self.lines {
+                line.truncate(max_width - line.indent);
+            }
+        }
+        let width = self.wrap_width.unwrap_or_else(|| {
+            self.lines
+                .iter()
+                .map(|line| line.indent + line.advance() - line.trailing_whitespace())
+                .fold(0., f32::max)
+        });
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            let used = line.advance() - line.trailing_whitespace();
+            let available = width - line.indent;
+            line.x_offset = line.indent
+                + match self.alignment {
+                    Alignment::Left => 0.,
+                    Alignment::Right => (available - used).max(0.),
+                    Alignment::Center => ((available - used) * 0.5).max(0.),
+                    Alignment::Justify => {
+                        if paragraph_ends.binary_search(&i).is_err() {
+                            line.justify(available);
+                        }
+                        0.
+                    }
+                };
+            line.reorder();
+        }
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            let mut metrics = line.runs.iter().map(|run| run.metrics).collect::<Vec<_>>();
+            if metrics.is_empty() {
+                let paragraph = paragraph_ends.partition_point(|&end| end < i);
+                metrics.push(self.paragraphs[paragraph].metrics);
+            }
+            if vertical {
+                // columns extend to either side of the vertical baseline,
+                // so "above" is the right of the column
+                for metrics in &mut metrics {
+                    *metrics = Metrics {
+                        ascent: metrics.vertical_ascent,
+                        descent: metrics.vertical_descent,
+                        leading: metrics.vertical_leading,
+                        ..*metrics
+                    };
+                }
+            }
+            line.set_metrics(metrics);
+            if self.line_height != LineHeight::Normal {
+                let height = self.line_height.resolve(line.above + line.below);
+                line.set_height(height);
+            }
+        }
+    }
+
+    /// Returns the width and height of the laid out text, e.g. for scrollbar
+    /// extents: the width of the widest line, including its indent and
+    /// alignment offset, and the sum of the line heights. Lines are
+    /// measured along the horizontal axis, so in [`WritingMode::VerticalRl`]
+    /// the width is the height of the tallest column and the height the
+    /// width of all columns. Only valid after [`Layout::finish`].
+    pub fn total_size(&self) -> (f32, f32) {
+        let width = self
+            .lines
+            .iter()
+            .map(|line| line.x_offset + line.advance())
+            .fold(0f32, f32::max);
+        let height = self.lines.iter().map(|line| line.above + line.below).sum::<f32>();
+        (width, height)
+    }
+
+    /// Maps a point, relative to the top left of the layout, to the cluster
+    /// under it. Points outside the layout are clamped to the nearest line
+    /// and cluster. Only valid after [`Layout::finish`].
+    pub fn hit_test(&self, x: f32, y: f32) -> Option<HitTestResult> {
+        let mut top = 0.;
+        let mut line_index = self.lines.len().checked_sub(1)?;
+        for (i, line) in self.lines.iter().enumerate() {
+            top += line.above + line.below;
+            if y < top {
+                line_index = i;
+                break;
+            }
+        }
+        let line = &self.lines[line_index];
+        let mut px = line.x_offset;
+        let mut last = None;
+        for (r, run) in line.runs.iter().enumerate() {
+            for (c, cluster) in run.clusters.iter().enumerate() {
+                let advance = run.cluster_advance(c);
+                let result = HitTestResult {
+                    line: line_index,
+                    run: r,
+                    glyph: cluster.glyphs.start,
+                    offset: cluster.range.start,
+                    is_leading: (x < px + advance * 0.5)
+                        == (run.direction == Direction::LeftToRight),
+                };
+                if x < px + advance {
+                    return Some(result);
+                }
+                px += advance;
+                last = Some(result);
+            }
+        }
+        match line.hard_break {
+            Some(offset) => Some(HitTestResult {
+                line: line_index,
+                run: line.runs.len().saturating_sub(1),
+                glyph: line.runs.last().map_or(0, |run| run.glyphs.len()),
+                offset,
+                is_leading: true,
+            }),
+            None => last,
+        }
+    }
+
+    /// Returns the byte offset of the cursor position nearest to `hit`, the
+    /// start or the end of the hit cluster.
+    pub fn hit_offset(&self, hit: &HitTestResult) -> usize {
+        if hit.is_leading {
+            return hit.offset;
+        }
+        self.lines[hit.line].runs[hit.run]
+            .clusters
+            .iter()
+            .find(|cluster| cluster.glyphs.start == hit.glyph)
+            .map_or(hit.offset, |cluster| cluster.range.end)
+    }
+
+    /// Returns the rectangles covering the text between the byte offsets
+    /// `start` and `end`, one per visual line, relative to the top left of
+    /// the layout. Only valid after [`Layout::finish`].
+    pub fn selection_rects(&self, start: usize, end: usize) -> Vec<Rect> {
+        let mut rects = Vec::new();
+        if start >= end {
+            return rects;
+        }
+        let mut top = 0.;
+        for line in &self.lines {
+            let height = line.above + line.below;
+            let mut px = line.x_offset;
+            let mut span: Option<(f32, f32)> = None;
+            for run in &line.runs {
+                for (c, cluster) in run.clusters.iter().enumerate() {
+                    let advance = run.cluster_advance(c);
+                    if cluster.range.start < end && cluster.range.end > start {
+                        // with mixed directions the selected clusters may not
+                        // be contiguous, so cover all of them
+                        span = Some(match span {
+                            Some((left, right)) => (left.min(px), right.max(px + advance)),
+                            None => (px, px + advance),
+                        });
+                    }
+                    px += advance;
+                }
+            }
+            if let Some((left, right)) = span {
+                rects.push(Rect::new(left, top, right - left, height));
+            }
+            top += height;
+        }
+        rects
+    }
+
+    /// Returns the caret rectangle for the cursor at the byte offset
+    /// `offset`, relative to the top left of the layout. Offsets inside a
+    /// cluster snap to its start, and offsets at a line break or past the
+    /// end of the text land at the end of the line. Only valid after
+    /// [`Layout::finish`].
+    pub fn cursor_rect(&self, offset: usize) -> Option<Rect> {
+        let caret = |line: &Line, top: f32, x: f32| {
+            Rect::new(
+                x,
+                top + line.above - line.ascent,
+                CURSOR_WIDTH,
+                line.ascent + line.descent,
+            )
+        };
+        let mut top = 0.;
+        for line in &self.lines {
+            let mut px = line.x_offset;
+            for run in &line.runs {
+                for (c, cluster) in run.clusters.iter().enumerate() {
+                    let advance = run.cluster_advance(c);
+                    if cluster.range.contains(&offset) {
+                        let x = match run.direction {
+                            Direction::LeftToRight => px,
+                            Direction::RightToLeft => px + advance,
+                        };
+                        return Some(caret(line, top, x));
+                    }
+                    px += advance;
+                }
+            }
+            // offsets in the line ending, e.g. a \r before the \n
+            if line.hard_break.map_or(false, |at| offset <= at) {
+                return Some(caret(line, top, px));
+            }
+            top += line.above + line.below;
+        }
+        // past the end of the text
+        let line = self.lines.last()?;
+        let top = top - (line.above + line.below);
+        Some(caret(line, top, line.x_offset + line.advance()))
+    }
+
+    /// Returns the glyphs of the layout in visual order, positioned with the
+    /// top left of the layout at `origin`. In [`WritingMode::VerticalRl`]
+    /// the first line is the rightmost column. Only valid after
+    /// [`Layout::finish`].
+    pub fn positioned_glyphs(&self, origin: (f32, f32)) -> impl Iterator<Item = PositionedGlyph<'_>> + '_ {
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        let extent = self.lines.iter().map(|line| line.above + line.below).sum::<f32>();
+        // offset of each line across the direction glyphs advance in
+        let starts = self.lines.iter().scan(0., |start, line| {
+            let line_start = *start;
+            *start += line.above + line.below;
+            Some(line_start)
+        });
+        self.lines.iter().zip(starts).flat_map(move |(line, start)| {
+            // the pen starts on the baseline, at the start of the line, and
+            // moves along `step`
+            let (pen, step) = if vertical {
+                let baseline = origin.0 + extent - start - line.above;
+                ((baseline, origin.1 + line.x_offset), (0., 1.))
+            } else {
+                let baseline = origin.1 + start + line.above;
+                ((origin.0 + line.x_offset, baseline), (1., 0.))
+            };
+            let run_starts = line.runs.iter().scan(0., |offset, run| {
+                let run_start = *offset;
+                *offset += run.advance();
+                Some(run_start)
+            });
+            line.runs.iter().zip(run_starts).flat_map(move |(run, run_start)| {
+                run.glyphs.iter().scan(run_start, move |offset, g| {
+                    let glyph = PositionedGlyph {
+                        font_id: run.font_id,
+                        id: g.id,
+                        x: pen.0 + step.0 * *offset + g.x,
+                        y: pen.1 + step.1 * *offset - g.y,
+                        advance: g.advance,
+                        size: run.size,
+                        coords: &run.coords,
+                        color: run.color,
+                        synthesis: run.synthesis,
+                    };
+                    *offset += g.advance;
+                    Some(glyph)
+                })
+            })
+        })
+    }
+
+    /// Returns the underlines and strikethroughs of the layout as rects with
+    /// their colors, with the top left of the layout at `origin`. Lines are
+    /// placed using the metrics of each run's font, and the lines of
+    /// adjacent runs with the same decoration and color are joined into one
+    /// rect, at the position of the first. Only valid after
+    /// [`Layout::finish`].
+    pub fn decoration_rects(&self, origin: (f32, f32)) -> Vec<(Rect, Color)> {
+        let mut rects: Vec<(Rect, Color)> = Vec::new();
+        let mut top = origin.1;
+        for line in &self.lines {
+            let baseline = top + line.above;
+            let mut x = origin.0 + line.x_offset;
+            // index into rects of the underline and strikethrough of the
+            // previous run, to extend when the next run matches
+            let mut prev: [Option<usize>; 2] = [None, None];
+            for run in &line.runs {
+                let advance = run.advance();
+                let color = run.decoration.color.unwrap_or(run.color);
+                let stroke = run.metrics.stroke_size.round().max(MIN_STROKE_SIZE);
+                let kinds = [
+                    (run.decoration.underline, run.metrics.underline_offset),
+                    (run.decoration.strikethrough, run.metrics.strikeout_offset),
+                ];
+                for (prev, (enabled, offset)) in prev.iter_mut().zip(kinds) {
+                    if !enabled {
+                        *prev = None;
+                        continue;
+                    }
+                    match *prev {
+                        Some(i) if rects[i].1 == color => rects[i].0.width += advance,
+                        _ => {
+                            // offsets are from the baseline to the top of
+                            // the stroke, with y pointing up
+                            let y = (baseline - offset).round();
+                            rects.push((Rect::new(x, y, advance, stroke), color));
+                            *prev = Some(rects.len() - 1);
+                        }
+                    }
+                }
+                x += advance;
+            }
+            top += line.above + line.below;
+        }
+        rects
+    }
+
+    /// Returns the boxes drawn in place of clusters no font has glyphs for,
+    /// with the first char of each cluster and the run's color, with the
+    /// top left of the layout at `origin`. Boxes span the ascent and
+    /// descent of the run's font, inset a little from the cluster's
+    /// advance. Only valid after [`Layout::finish`].
+    pub fn missing_glyph_rects(&self, origin: (f32, f32)) -> Vec<(Rect, char, Color)> {
+        let mut rects = Vec::new();
+        let mut top = origin.1;
+        for line in &self.lines {
+            let baseline = top + line.above;
+            let mut x = origin.0 + line.x_offset;
             for run in &line.runs {
-                line.ascent = line.ascent.max(run.metrics.ascent);
-                line.descent = line.descent.max(run.metrics.descent);
-                line.leading = line.leading.max(run.metrics.leading);
-            }
-            // eh???
-            line.ascent = line.ascent.round();
-            line.descent = line.descent.round();
-            line.leading = (line.leading * 0.5).round() * 2.;
-            line.below = (line.descent + line.leading * 0.5).round();
-            // baseline = y + above
-            line.above = (line.ascent + line.leading * 0.5).round();
+                for (c, cluster) in run.clusters.iter().enumerate() {
+                    let advance = run.cluster_advance(c);
+                    if let Some(ch) = cluster.missing {
+                        let ascent = run.metrics.ascent.round();
+                        let descent = run.metrics.descent.round();
+                        let rect = Rect::new(x, baseline - ascent, advance, ascent + descent);
+                        rects.push((rect.inset(advance * 0.1, run.size * 0.05), ch, run.color));
+                    }
+                    x += advance;
+                }
+            }
+            top += line.above + line.below;
+        }
+        rects
+    }
+
+    /// Returns the line index, run index and bounding box of each run,
+    /// relative to the top left of the layout, e.g. to fill run
+    /// backgrounds. Boxes span the full height of their line and the boxes
+    /// of adjacent runs share an edge, so backgrounds tile without gaps.
+    /// Only valid after [`Layout::finish`].
+    pub fn run_bounds(&self) -> impl Iterator<Item = (usize, usize, Rect)> + '_ {
+        let tops = self.lines.iter().scan(0., |top, line| {
+            let line_top = *top;
+            *top += line.above + line.below;
+            Some(line_top)
+        });
+        self.lines
+            .iter()
+            .zip(tops)
+            .enumerate()
+            .flat_map(|(line_index, (line, top))| {
+                let height = line.above + line.below;
+                line.runs
+                    .iter()
+                    .enumerate()
+                    .scan(line.x_offset, move |x, (run_index, run)| {
+                        let advance = run.advance();
+                        let rect = Rect::new(*x, top, advance, height);
+                        *x += advance;
+                        Some((line_index, run_index, rect))
+                    })
+            })
+    }
+}
+
+/// Thinnest decoration line drawn by [`Layout::decoration_rects`].
+const MIN_STROKE_SIZE: f32 = 1.;
+
+/// Width of the caret returned by [`Layout::cursor_rect`].
+const CURSOR_WIDTH: f32 = 2.;
+
+/// Breaks a paragraph's runs into visual lines no wider than `width`, or
+/// `first_width` for the first line.
+///
+/// Breaks are placed after whitespace clusters where possible, falling back
+/// to breaking between clusters when a single word doesn't fit. Trailing
+/// whitespace is allowed to hang past the edge.
+fn wrap(runs: &[Run], first_width: f32, width: f32, lines: &mut Vec<Line>) {
+    let clusters = cluster_indices(runs);
+    let advances = clusters
+        .iter()
+        .map(|&(r, c)| runs[r].cluster_advance(c))
+        .collect::<Vec<_>>();
+    // advance of the hyphen added when breaking after each cluster, if it
+    // can be hyphenated after
+    let hyphens = clusters
+        .iter()
+        .map(|&(r, c)| {
+            let hyphen = runs[r].hyphen.filter(|_| runs[r].clusters[c].hyphen_after)?;
+            Some(hyphen.advance)
+        })
+        .collect::<Vec<_>>();
+
+    // break positions, with whether the line ends in a hyphen
+    let mut breaks = Vec::new();
+    let mut line_start = 0;
+    let mut last_break = None;
+    let mut last_hyphen = None;
+    let mut x = 0.;
+    let mut line_width = first_width;
+    for (i, &(r, c)) in clusters.iter().enumerate() {
+        let is_whitespace = runs[r].clusters[c].is_whitespace;
+        if !is_whitespace && x + advances[i] > line_width && i > line_start {
+            let at = match (last_break, last_hyphen) {
+                (_, Some(h)) if h > line_start && last_break.map_or(true, |b| h > b) => (h, true),
+                (Some(at), _) if at > line_start => (at, false),
+                _ => (i, false),
+            };
+            breaks.push(at);
+            line_start = at.0;
+            line_width = width;
+            x = advances[at.0..i].iter().sum::<f32>();
+        }
+        x += advances[i];
+        if is_whitespace {
+            last_break = Some(i + 1);
+        }
+        // the hyphen has to fit on the line too
+        if let Some(hyphen) = hyphens[i] {
+            if x + hyphen <= line_width {
+                last_hyphen = Some(i + 1);
+            }
+        }
+    }
+    breaks.push((clusters.len(), false));
+
+    let mut start = 0;
+    for (end, hyphenated) in breaks {
+        let mut line = Line::default();
+        for (r, run) in runs.iter().enumerate() {
+            let in_run = clusters[start..end]
+                .iter()
+                .filter(|&&(cr, _)| cr == r)
+                .map(|&(_, c)| c);
+            let (Some(first), Some(last)) = (in_run.clone().min(), in_run.max()) else {
+                continue;
+            };
+            line.runs.push(run.slice(first..last + 1));
+        }
+        // before the hyphen goes on, so the spacing comes off the cluster
+        line.trim_letter_spacing();
+        if hyphenated {
+            if let Some(run) = line.runs.last_mut() {
+                run.push_hyphen();
+            }
+        }
+        lines.push(line);
+        start = end;
+    }
+}
+
+/// Returns the glyph of `font` at `size` for `ch`, if it has one.
+fn char_glyph(font: &Font, size: f32, ch: char) -> Option<Glyph> {
+    let fontref = font.fontref();
+    let id = fontref.charmap().map(ch);
+    if id == 0 {
+        return None;
+    }
+    Some(Glyph {
+        id,
+        advance: fontref.glyph_metrics(font.coords()).scale(size).advance_width(id),
+        ..Default::default()
+    })
+}
+
+/// Returns the (run index, cluster index) of every cluster in `runs`.
+fn cluster_indices(runs: &[Run]) -> Vec<(usize, usize)> {
+    runs.iter()
+        .enumerate()
+        .flat_map(|(r, run)| (0..run.clusters.len()).map(move |c| (r, c)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fonts::FontSource;
+
+    const ADVANCE: f32 = 10.;
+
+    fn font_source() -> (FontSource, FontId) {
+        let mut source = FontSource::new();
+        let id = source.load_fixture("DejaVuSans.ttf");
+        (source, id)
+    }
+
+    /// Pushes `text` as a run with one glyph of [`ADVANCE`] per char,
+    /// starting at byte `start` of paragraph `line_no`. With `hyphenate`,
+    /// words may be hyphenated after any char.
+    fn push_text(layout: &mut Layout, font: &Font, line_no: usize, start: usize, text: &str, letter_spacing: f32, hyphenate: bool) {
+        let mut glyphs = Vec::new();
+        let mut clusters = Vec::new();
+        for (i, ch) in text.char_indices() {
+            clusters.push(Cluster {
+                range: start + i..start + i + ch.len_utf8(),
+                glyphs: glyphs.len()..glyphs.len() + 1,
+                is_whitespace: ch.is_whitespace(),
+                is_tab: ch == '\t',
+                hyphen_after: hyphenate && !ch.is_whitespace(),
+                missing: None,
+            });
+            glyphs.push(Glyph {
+                id: 1,
+                advance: ADVANCE + letter_spacing,
+                ..Default::default()
+            });
+        }
+        let range = start..start + text.len();
+        layout.push_run(
+            line_no,
+            font,
+            range,
+            glyphs,
+            clusters,
+            16.,
+            0,
+            Color::default(),
+            Synthesis::default(),
+            Decoration::default(),
+            letter_spacing,
+        );
+    }
+
+    #[test]
+    fn letter_spacing_goes_between_clusters() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let width = |letter_spacing: f32| {
+            let mut layout = Layout::new();
+            push_text(&mut layout, font, 0, 0, "hello", letter_spacing, false);
+            layout.finish();
+            layout.total_size().0
+        };
+        assert_eq!(width(2.) - width(0.), 4. * 2.);
+    }
+
+    #[test]
+    fn hyphen_keeps_its_advance_with_letter_spacing() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let hyphen = char_glyph(font, 16., '-').unwrap().advance;
+        let mut layout = Layout::new();
+        layout.set_wrap_width(Some(33.));
+        push_text(&mut layout, font, 0, 0, "abcd", 2., true);
+        layout.finish();
+        // "ab-" fits in 33, "abc" doesn't
+        assert_eq!(layout.lines[0].advance(), ADVANCE + 2. + ADVANCE + hyphen);
+    }
+
+    #[test]
+    fn runs_on_one_line_stay_in_order() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let mut layout = Layout::new();
+        push_text(&mut layout, font, 0, 0, "ab", 0., false);
+        push_text(&mut layout, font, 0, 2, "cd", 0., false);
+        layout.finish();
+        assert_eq!(layout.lines.len(), 1);
+        let ranges = layout.lines[0].runs.iter().map(|run| run.range.clone()).collect::<Vec<_>>();
+        assert_eq!(ranges, [0..2, 2..4]);
+    }
+
+    #[test]
+    fn line_metrics_come_from_the_largest_run() {
+        let (mut source, dejavu) = font_source();
+        let inter = source.load_fixture("InterVariable.ttf");
+        let mut layout = Layout::new();
+        push_text(&mut layout, source.get_font(dejavu), 0, 0, "ab", 0., false);
+        push_text(&mut layout, source.get_font(inter), 0, 2, "cd", 0., false);
+        layout.finish();
+        let line = &layout.lines[0];
+        let (a, b) = (line.runs[0].metrics, line.runs[1].metrics);
+        assert_ne!(a.descent, b.descent);
+        let ascent = a.ascent.max(b.ascent).round();
+        let descent = a.descent.max(b.descent).round();
+        // leading is rounded to an even value to split evenly
+        let leading = (a.leading.max(b.leading) * 0.5).round() * 2.;
+        assert_eq!(line.below, (descent + leading * 0.5).round());
+        assert_eq!(line.above, (ascent + leading * 0.5).round());
+    }
+
+    #[test]
+    fn wrapping_keeps_zwj_emoji_together() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let emoji = "🧙🏻\u{200D}♂\u{FE0F}";
+        let layout = |width: f32| {
+            let mut layout = Layout::new();
+            layout.set_wrap_width(Some(width));
+            push_text(&mut layout, font, 0, 0, "ab ", 0., false);
+            // a font without the sequence shapes it to one cluster of
+            // several glyphs
+            let glyph = Glyph { id: 1, advance: ADVANCE, ..Default::default() };
+            let range = 3..3 + emoji.len();
+            let cluster = Cluster {
+                range: range.clone(),
+                glyphs: 0..3,
+                is_whitespace: false,
+                is_tab: false,
+                hyphen_after: false,
+                missing: None,
+            };
+            layout.push_run(
+                0,
+                font,
+                range,
+                vec![glyph; 3],
+                vec![cluster],
+                16.,
+                0,
+                Color::default(),
+                Synthesis::default(),
+                Decoration::default(),
+                0.,
+            );
+            layout.finish();
+            layout
+        };
+        // the emoji ends right at the wrap width
+        let fits = layout(6. * ADVANCE);
+        assert_eq!(fits.lines.len(), 1);
+        assert_eq!(fits.lines[0].advance(), 6. * ADVANCE);
+        // a little less and the whole emoji moves to the next line
+        let wrapped = layout(6. * ADVANCE - 1.);
+        assert_eq!(wrapped.lines.len(), 2);
+        let last = &wrapped.lines[1].runs;
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].range, 3..3 + emoji.len());
+        assert_eq!(last[0].glyphs.len(), 3);
+    }
+
+    #[test]
+    fn double_line_height_doubles_the_baseline_gap() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let baseline_gap = |line_height: LineHeight| {
+            let mut layout = Layout::new();
+            layout.set_line_height(line_height);
+            push_text(&mut layout, font, 0, 0, "ab", 0., false);
+            push_text(&mut layout, font, 1, 2, "cd", 0., false);
+            layout.finish();
+            let [first, second] = &layout.lines[..] else {
+                panic!("expected two lines");
+            };
+            first.below + second.above
+        };
+        let normal = baseline_gap(LineHeight::Normal);
+        assert!(normal > 0.);
+        assert_eq!(baseline_gap(LineHeight::Multiple(2.)), 2. * normal);
+    }
+
+    #[test]
+    fn hanging_indent_moves_continuation_lines_right() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let mut layout = Layout::new();
+        layout.set_wrap_width(Some(6. * ADVANCE));
+        layout.set_indent(0., 2. * ADVANCE);
+        push_text(&mut layout, font, 0, 0, "abc def ghi", 0., false);
+        layout.finish();
+        assert!(layout.lines.len() > 1);
+        assert_eq!(layout.lines[0].x_offset, 0.);
+        for line in &layout.lines[1..] {
+            assert_eq!(line.x_offset, 2. * ADVANCE);
+            // the indent comes off the width available to the line
+            assert!(line.x_offset + line.advance() - line.trailing_whitespace() <= 6. * ADVANCE);
+        }
+    }
+
+    #[test]
+    fn run_bounds_add_up_to_the_line() {
+        let (source, id) = font_source();
+        let font = source.get_font(id);
+        let mut layout = Layout::new();
+        push_text(&mut layout, font, 0, 0, "ab", 0., false);
+        push_text(&mut layout, font, 0, 2, "cde", 2., false);
+        push_text(&mut layout, font, 0, 5, "f", 0., false);
+        layout.finish();
+        let bounds = layout.run_bounds().collect::<Vec<_>>();
+        assert_eq!(bounds.len(), 3);
+        let line = &layout.lines[0];
+        let width = bounds.iter().map(|(_, _, rect)| rect.width).sum::<f32>();
+        assert_eq!(width, line.advance());
+        for pair in bounds.windows(2) {
+            let ((_, _, a), (_, _, b)) = (pair[0], pair[1]);
+            assert_eq!(a.x + a.width, b.x);
         }
+        assert!(bounds.iter().all(|(_, _, rect)| rect.height == line.above + line.below));
     }
 }
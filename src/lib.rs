@@ -0,0 +1,10 @@
+/*!
+Text layout and GPU rendering for the duck editor. The `duck-wgpu` binary
+is a small window around these modules; they're a library so that tests
+under `tests/` can drive them too.
+*/
+
+pub mod document;
+pub mod fonts;
+pub mod gfx;
+pub mod layout;
@@ -1,27 +1,35 @@
-mod document;
-mod fonts;
-mod gfx;
-mod layout;
-
-use document::Document;
-use fonts::FontSource;
-use gfx::{
+use duck_wgpu::document::{measure, Document, MissingGlyphStyle};
+use itertools::Itertools;
+use duck_wgpu::fonts::{Font, FontSource, FontStack};
+use duck_wgpu::gfx::{
     color::{self, Color},
-    compositor::Compositor,
+    compositor::{Compositor, DisplayList},
     image_cache::ImageCache,
-    types::Rect, glyph_cache::GlyphCache,
+    types::Rect, glyph_cache::{GlyphCache, GlyphRenderOptions},
 };
 use winit::{
-    event::{Event, WindowEvent},
+    dpi::PhysicalSize,
+    event::{
+        ElementState, Event, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode,
+        WindowEvent,
+    },
     event_loop::EventLoop,
     window::WindowBuilder,
 };
 
-use crate::{
-    fonts::{FontFamily},
-    gfx::{wgpu_context::WgpuContext},
+use duck_wgpu::{
+    fonts::FontFamily,
+    gfx::wgpu_context::{DeviceOptions, WgpuContext, DRAW_TRANSFORM_SIZE},
 };
 
+/// Distance between the edge of the window and the text.
+const MARGIN: f32 = 12.;
+/// Background of selected text.
+const SELECTION_COLOR: Color = Color::new(180, 214, 255, 255);
+/// Most glyphs kept rendered, past which the least recently used ones are
+/// dropped from the atlases.
+const GLYPH_CACHE_CAPACITY: usize = 4096;
+
 fn main() {
     env_logger::init();
     let event_loop = EventLoop::new();
@@ -31,108 +39,60 @@ fn main() {
         .build(&event_loop)
         .expect("failed to create window");
 
-    let mut wgpu = WgpuContext::new(&window, color::WHITE);
+    let mut wgpu = WgpuContext::new(
+        &window,
+        color::WHITE,
+        wgpu::PresentMode::Fifo,
+        None,
+        &DeviceOptions {
+            features: wgpu::Features::PUSH_CONSTANTS,
+            limits: wgpu::Limits {
+                max_push_constant_size: DRAW_TRANSFORM_SIZE,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
 
     let mut fontsource = FontSource::new();
-    let default_monospace_font = fontsource
-        .load(&[FontFamily::Monospace])
-        .expect("Failed to load monospace font");
-    let prefered_font = fontsource
-        .load(&[FontFamily::Title("Jetbrains Mono".to_string())])
-        .expect("Failed to load monospace font");
-    let emoji_font = fontsource
-        .load(&[FontFamily::Title("Noto Color Emoji".to_string())])
-        .expect("failed to load emoji font family");
-    let jp_font = fontsource
-        .load(&[FontFamily::Title("VL Gothic".to_string())])
-        .expect("failed to load emoji font family");
+    let font_stack = FontStack::load(
+        &mut fontsource,
+        &[
+            FontFamily::Title("Jetbrains Mono".to_string()),
+            FontFamily::Monospace,
+            FontFamily::Title("Noto Color Emoji".to_string()),
+            FontFamily::Title("VL Gothic".to_string()),
+        ],
+    )
+    .expect("Failed to load fonts");
     let mut compositor = Compositor::new();
-    //let mut shape_context = ShapeContext::new();
-    //let mut parse_context = ParseContext::new();
     let mut image_cache = ImageCache::new(wgpu.device.limits().max_texture_dimension_2d);
-    let mut glyph_cache = GlyphCache::new();
+    let mut glyph_cache = GlyphCache::with_capacity(GLYPH_CACHE_CAPACITY);
 
     //let document = Document::from_reader(std::fs::File::open("../../v0/emoji-zwj-sequences.txt").unwrap()).unwrap();
     //let mut document = Document::from_str("Simple String!");
-    // TODO: fix rendering extra empty character after 0 and * (and similar) emojis
     let s = "y̆es 0️<=1(*️)2*3*#️⃣ 🧙🏻‍♂️⭐😶‍🌫️ *️*️*️ + 🦆&🙂😶\n🦆🦆🦆🦆🦆😶‍🌫️\ra\r\r\nSimple String!\n#️⃣#️⃣#️⃣#️⃣#️⃣\n\nHI!\tHi!\thi!\nこんにちは🇯🇵";
     let mut document = Document::from_str(s);
     //let mut document = Document::from_str("🦆🦆🦆🦆🦆😶‍🌫️");
     //let mut document = Document::from_str("#️⃣");
 
-    let fonts = [&prefered_font, &default_monospace_font, &emoji_font, &jp_font];
-    let scale = window.scale_factor() as f32;
-    document.parse(
-        &fonts,
-        32. * scale, // TODO: if the scale changes we need to update things!
-        //&mut shape_context,
-        //&mut parse_context,
-    );
-    document.layout.finish();
-    compositor.begin();
-    let subpx_bias = (0.125, 0.);
-    let screen_size = window.inner_size();
-    let margin = 12.;
-    let buffer_window = Rect::new(
-        margin,
-        margin,
-        screen_size.width as f32 - margin,
-        screen_size.height as f32 - margin,
+    let mut scale = window.scale_factor() as f32;
+    document.set_scale(scale);
+    let render_options = GlyphRenderOptions::default();
+    let mut modifiers = ModifiersState::empty();
+    let mut mouse_position = (0., 0.);
+    let mut needs_layout = false;
+    let mut display_list = draw_document(
+        &mut document,
+        &fontsource,
+        &font_stack,
+        &wgpu,
+        &mut glyph_cache,
+        &mut image_cache,
+        &mut compositor,
+        window.inner_size(),
+        render_options,
     );
-    let mut y = buffer_window.y;
-    for line in &document.layout.lines {
-        let baseline = line.above;
-        let mut px = buffer_window.x;
-        for run in &line.runs {
-            let font = fonts[run.font_index].fontref();
-            let mut session =
-                glyph_cache.session(&wgpu, &mut image_cache, font, run.size, &run.coords);
-            let py = baseline + y;
-            //println!("{:?}", run.glyphs);
-            for g in &run.glyphs {
-                let gx = px + g.x;
-                let gy = py - g.y;
-                px += g.advance;
-                if let Some(entry) = session.get(g.id, gx, gy) {
-                    if let Some(tex_loc) = session.get_texture_location(entry.image_id) {
-                        let ix = (gx + subpx_bias.0).floor() + entry.left as f32;
-                        let iy = (gy + subpx_bias.1).floor() - entry.top as f32;
-                        if entry.is_bitmap {
-                            compositor.add_image_rect(
-                                [ix, iy, entry.width as f32, entry.height as f32],
-                                0.01,
-                                color::WHITE, // always needs to be white, unless you want to tint the image, which you probably don't want to do.
-                                tex_loc,
-                            );
-                        } else {
-                            compositor.add_subpixel_rect(
-                                [ix, iy, entry.width as f32, entry.height as f32],
-                                0.01,
-                                color::BLACK,
-                                tex_loc,
-                            );
-                        }
-                    }
-                }
-            }
-        }
-        y += line.above + line.below;
-    }
-
-    // compositor.draw_rect([0.0f32, 0.0, 200.0, 200.0], 0.1, Color::new(255, 0, 0, 128));
-    // compositor.draw_rect(
-    //     [100.0f32, 100.0, 200.0, 200.0],
-    //     0.1,
-    //     Color::new(0, 255, 0, 64),
-    // );
-    // compositor.draw_rect(
-    //     [200.0f32, 200.0, 200.0, 200.0],
-    //     0.6,
-    //     Color::new(0, 0, 255, 255),
-    // );
-    compositor.draw_rect([300.0f32, 300.0, 200.0, 200.0], 0.4, color::YELLOW);
-    compositor.draw_rect([700.0f32, 500.0, 100.0, 100.0], 0.5, color::AQUA);
-    let display_list = compositor.build_display_list();
 
     event_loop.run(move |event, _, control_flow| {
         control_flow.set_wait();
@@ -150,13 +110,132 @@ fn main() {
                     if new_size.width > 0 && new_size.height > 0 {
                         println!("{}x{} @ {}", new_size.width, new_size.height, scale_factor);
                         wgpu.resize(new_size.width, new_size.height, scale_factor);
+                        let scale_changed = scale_factor != scale;
+                        if scale_changed {
+                            // e.g. the window moved to a monitor with a different dpi
+                            scale = scale_factor;
+                            document.set_scale(scale);
+                        }
+                        // lines are wrapped to the new width
+                        display_list = draw_document(
+                            &mut document,
+                            &fontsource,
+                            &font_stack,
+                            &wgpu,
+                            &mut glyph_cache,
+                            &mut image_cache,
+                            &mut compositor,
+                            *new_size,
+                            render_options,
+                        );
+                        if scale_changed {
+                            let sizes = document
+                                .layout
+                                .lines
+                                .iter()
+                                .flat_map(|line| line.runs.iter().map(|run| run.size))
+                                .collect::<Vec<_>>();
+                            glyph_cache.retain_sizes(&mut image_cache, &sizes);
+                        }
+                        window.request_redraw();
+                    }
+                }
+                WindowEvent::ReceivedCharacter(ch) => {
+                    // backspace and delete arrive as characters too, they're
+                    // handled as key presses
+                    let text = match ch {
+                        '\r' => document.line_ending().as_str().to_string(),
+                        '\t' => "\t".to_string(),
+                        ch if ch.is_control() => return,
+                        ch => ch.to_string(),
+                    };
+                    let selection = document.selection();
+                    document.edit(selection.clone(), &text);
+                    let cursor = selection.start + text.len();
+                    document.set_selection(cursor, cursor);
+                    needs_layout = true;
+                    window.request_redraw();
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => {
+                    match key {
+                        VirtualKeyCode::Back => {
+                            let mut range = document.selection();
+                            if range.is_empty() {
+                                range.start = document.prev_cursor_position(range.end);
+                            }
+                            document.delete(range);
+                        }
+                        VirtualKeyCode::Delete => {
+                            let mut range = document.selection();
+                            if range.is_empty() {
+                                range.end = document.next_cursor_position(range.start);
+                            }
+                            document.delete(range);
+                        }
+                        VirtualKeyCode::Left if modifiers.shift() => document.extend_left(),
+                        VirtualKeyCode::Right if modifiers.shift() => document.extend_right(),
+                        VirtualKeyCode::Up if modifiers.shift() => document.extend_up(),
+                        VirtualKeyCode::Down if modifiers.shift() => document.extend_down(),
+                        VirtualKeyCode::Left => document.move_left(),
+                        VirtualKeyCode::Right => document.move_right(),
+                        VirtualKeyCode::Up => document.move_up(),
+                        VirtualKeyCode::Down => document.move_down(),
+                        VirtualKeyCode::Home => document.set_selection(0, 0),
+                        VirtualKeyCode::End => {
+                            let end = document.len_bytes();
+                            document.set_selection(end, end);
+                        }
+                        _ => return,
+                    }
+                    needs_layout = true;
+                    window.request_redraw();
+                }
+                WindowEvent::ModifiersChanged(state) => modifiers = state,
+                WindowEvent::CursorMoved { position, .. } => {
+                    mouse_position = (position.x as f32, position.y as f32);
+                }
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: MouseButton::Left,
+                    ..
+                } => {
+                    let layout = &document.layout;
+                    if let Some(hit) =
+                        layout.hit_test(mouse_position.0 - MARGIN, mouse_position.1 - MARGIN)
+                    {
+                        let cursor = layout.hit_offset(&hit);
+                        document.set_selection(cursor, cursor);
+                        needs_layout = true;
+                        window.request_redraw();
                     }
                 }
                 _ => {}
             },
 
             Event::RedrawRequested(window_id) if window_id == window.id() => {
-                if wgpu.render(&mut image_cache, &display_list).is_err() {
+                if needs_layout {
+                    needs_layout = false;
+                    display_list = draw_document(
+                        &mut document,
+                        &fontsource,
+                        &font_stack,
+                        &wgpu,
+                        &mut glyph_cache,
+                        &mut image_cache,
+                        &mut compositor,
+                        window.inner_size(),
+                        render_options,
+                    );
+                }
+                if wgpu.render(&mut image_cache, &display_list, None).is_err() {
                     control_flow.set_exit_with_code(1);
                 }
             }
@@ -167,3 +246,211 @@ fn main() {
         }
     })
 }
+
+/// Lays out `document` to fit a window of `screen_size` and draws it with
+/// a caret at its cursor.
+#[allow(clippy::too_many_arguments)]
+fn draw_document(
+    document: &mut Document,
+    fontsource: &FontSource,
+    font_stack: &FontStack,
+    wgpu: &WgpuContext,
+    glyph_cache: &mut GlyphCache,
+    image_cache: &mut ImageCache,
+    compositor: &mut Compositor,
+    screen_size: PhysicalSize<u32>,
+    render_options: GlyphRenderOptions,
+) -> DisplayList {
+    let font_size = 32.;
+    let fonts = font_stack.fonts(fontsource);
+    document
+        .parse(&fonts, font_size)
+        .expect("failed to parse document");
+    compositor.begin();
+    let subpx_bias = render_options.subpixel_bias;
+    let buffer_window = Rect::new(
+        MARGIN,
+        MARGIN,
+        screen_size.width as f32 - MARGIN * 2.,
+        screen_size.height as f32 - MARGIN * 2.,
+    );
+    document.layout.set_wrap_width(Some(buffer_window.width));
+    document.layout.finish();
+    // glyphs drawn with the same font and size share a session
+    let glyphs = document
+        .layout
+        .positioned_glyphs((buffer_window.x, buffer_window.y));
+    let groups = glyphs.group_by(|g| (g.font_id, g.size, g.coords, g.synthesis));
+    for ((font_id, size, coords, synthesis), glyphs) in &groups {
+        let font = fontsource.get_fontref(font_id);
+        let options = GlyphRenderOptions {
+            synthetic_bold: synthesis.embolden,
+            synthetic_skew: synthesis.skew,
+            ..render_options
+        };
+        let mut session = glyph_cache.session(wgpu, image_cache, font, size, coords, options);
+        for g in glyphs {
+            if let Some(entry) = session.get(g.id, g.x, g.y) {
+                if let Some(tex_loc) = session.get_texture_location(entry.image_id) {
+                    let ix = (g.x + subpx_bias.0).floor() + entry.left as f32;
+                    let iy = (g.y + subpx_bias.1).floor() - entry.top as f32;
+                    if entry.is_bitmap {
+                        compositor.add_image_rect(
+                            [ix, iy, entry.width as f32, entry.height as f32],
+                            0.01,
+                            color::WHITE, // always needs to be white, unless you want to tint the image, which you probably don't want to do.
+                            tex_loc,
+                        );
+                    } else if entry.is_subpixel {
+                        compositor.add_subpixel_rect(
+                            [ix, iy, entry.width as f32, entry.height as f32],
+                            0.01,
+                            g.color,
+                            tex_loc,
+                        );
+                    } else {
+                        compositor.add_grayscale_rect(
+                            [ix, iy, entry.width as f32, entry.height as f32],
+                            0.01,
+                            g.color,
+                            tex_loc,
+                        );
+                    }
+                }
+            }
+        }
+    }
+    for (rect, color) in document
+        .layout
+        .decoration_rects((buffer_window.x, buffer_window.y))
+    {
+        compositor.draw_rect([rect.x, rect.y, rect.width, rect.height], 0.01, color);
+    }
+    let missing_glyph_style = document.missing_glyph_style();
+    for (rect, ch, color) in document
+        .layout
+        .missing_glyph_rects((buffer_window.x, buffer_window.y))
+    {
+        draw_missing_glyph(
+            compositor,
+            rect,
+            ch,
+            color,
+            missing_glyph_style,
+            fonts[0],
+            glyph_cache,
+            image_cache,
+            wgpu,
+        );
+    }
+    let mut y = buffer_window.y;
+    for line in &document.layout.lines {
+        let baseline = line.above;
+        if document.show_whitespace() {
+            let primary = fonts[0].fontref();
+            let line_x = buffer_window.x + line.x_offset;
+            let py = baseline + y;
+            for marker in line.whitespace_markers(font_size * document.scale()) {
+                let id = primary.charmap().map(marker.kind.marker());
+                if id == 0 {
+                    continue;
+                }
+                let advance = primary.glyph_metrics(&[]).scale(marker.size).advance_width(id);
+                // markers are centered over the whitespace, the line break
+                // marker just follows the end of the line
+                let gx = line_x + marker.x + ((marker.advance - advance) * 0.5).max(0.);
+                let mut session =
+                    glyph_cache.session(wgpu, image_cache, primary, marker.size, &[], render_options);
+                let Some(entry) = session.get(id, gx, py) else {
+                    continue;
+                };
+                if let Some(tex_loc) = session.get_texture_location(entry.image_id) {
+                    let ix = (gx + subpx_bias.0).floor() + entry.left as f32;
+                    let iy = (py + subpx_bias.1).floor() - entry.top as f32;
+                    let rect = [ix, iy, entry.width as f32, entry.height as f32];
+                    if entry.is_subpixel {
+                        compositor.add_subpixel_rect(rect, 0.01, document.whitespace_color(), tex_loc);
+                    } else {
+                        compositor.add_grayscale_rect(rect, 0.01, document.whitespace_color(), tex_loc);
+                    }
+                }
+            }
+        }
+        y += line.above + line.below;
+    }
+    let selection = document.selection();
+    for rect in document.layout.selection_rects(selection.start, selection.end) {
+        compositor.draw_rect(
+            [buffer_window.x + rect.x, buffer_window.y + rect.y, rect.width, rect.height],
+            0.5,
+            SELECTION_COLOR,
+        );
+    }
+    if let Some(caret) = document.layout.cursor_rect(document.cursor()) {
+        compositor.draw_rect(
+            [buffer_window.x + caret.x, buffer_window.y + caret.y, caret.width, caret.height],
+            0.01,
+            color::BLACK,
+        );
+    }
+
+    // compositor.draw_rect([0.0f32, 0.0, 200.0, 200.0], 0.1, Color::new(255, 0, 0, 128));
+    // compositor.draw_rect(
+    //     [100.0f32, 100.0, 200.0, 200.0],
+    //     0.1,
+    //     Color::new(0, 255, 0, 64),
+    // );
+    // compositor.draw_rect(
+    //     [200.0f32, 200.0, 200.0, 200.0],
+    //     0.6,
+    //     Color::new(0, 0, 255, 255),
+    // );
+    compositor.draw_rect([300.0f32, 300.0, 200.0, 200.0], 0.4, color::YELLOW);
+    compositor.draw_rect([700.0f32, 500.0, 100.0, 100.0], 0.5, color::AQUA);
+    compositor.end()
+}
+
+/// Draws the box standing in for a cluster no font has glyphs for. Hex
+/// boxes show the code point of `ch` in two rows of digits set in `font`.
+#[allow(clippy::too_many_arguments)]
+fn draw_missing_glyph(
+    compositor: &mut Compositor,
+    rect: Rect,
+    ch: char,
+    color: Color,
+    style: MissingGlyphStyle,
+    font: &Font,
+    glyph_cache: &mut GlyphCache,
+    image_cache: &mut ImageCache,
+    wgpu: &WgpuContext,
+) {
+    match style {
+        MissingGlyphStyle::SolidBox => compositor.draw_rect(rect, 0.01, color),
+        MissingGlyphStyle::HexBox => {
+            let Rect { x, y, width, height } = rect;
+            let stroke = 1.;
+            for edge in [
+                Rect::new(x, y, width, stroke),
+                Rect::new(x, y + height - stroke, width, stroke),
+                Rect::new(x, y, stroke, height),
+                Rect::new(x + width - stroke, y, stroke, height),
+            ] {
+                compositor.draw_rect(edge, 0.01, color);
+            }
+            let digits = format!("{:04X}", ch as u32);
+            let (top_row, bottom_row) = digits.split_at(digits.len() / 2);
+            let size = height * 0.3;
+            for (row, text) in [top_row, bottom_row].into_iter().enumerate() {
+                let Ok((text_width, text_height)) = measure(text, &[font], size) else {
+                    continue;
+                };
+                let origin = (
+                    x + (width - text_width) * 0.5,
+                    y + height * 0.5 + (row as f32 - 1.) * text_height,
+                );
+                compositor.draw_text(text, origin, size, color, font, glyph_cache, image_cache, wgpu);
+            }
+        }
+        MissingGlyphStyle::Notdef | MissingGlyphStyle::Hidden => {}
+    }
+}
@@ -0,0 +1,206 @@
+//! Renders documents without a window and compares them against the golden
+//! images in `tests/fixtures`. A missing golden image fails the test; set
+//! `DUCK_UPDATE_GOLDEN=1` to record the images, e.g. for a new test or
+//! after an intended change in output. On a mismatch the rendered image
+//! and a diff are written to a temporary directory. The tests need a GPU or
+//! software adapter, so they're ignored by default; run them with
+//! `cargo test -- --ignored`.
+
+use std::{path::PathBuf, sync::Arc};
+
+use duck_wgpu::{
+    document::Document,
+    fonts::FontSource,
+    gfx::{
+        color,
+        compositor::Compositor,
+        glyph_cache::{GlyphCache, GlyphRenderOptions},
+        image_cache::ImageCache,
+        wgpu_context::{DeviceOptions, WgpuContext},
+    },
+};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 64;
+const FONT_SIZE: f32 = 16.;
+/// Largest difference allowed in any channel of a pixel by default, to
+/// absorb differences between drivers. Set `DUCK_GOLDEN_MAX_DELTA` to
+/// allow more, e.g. on a software adapter.
+const MAX_DELTA: u8 = 8;
+
+/// Returns the largest difference allowed in any channel of a pixel, from
+/// `DUCK_GOLDEN_MAX_DELTA` or else [`MAX_DELTA`].
+fn max_delta() -> u8 {
+    match std::env::var("DUCK_GOLDEN_MAX_DELTA") {
+        Ok(value) => value
+            .parse()
+            .expect("DUCK_GOLDEN_MAX_DELTA should be a number from 0 to 255"),
+        Err(_) => MAX_DELTA,
+    }
+}
+
+/// Lays out `text` with the fonts `fonts` from `tests/fixtures/fonts`, in
+/// fallback order, and renders it, returning the pixels as RGBA rows.
+fn render(text: &str, fonts: &[&str]) -> Vec<u8> {
+    let mut context =
+        WgpuContext::new_headless(WIDTH, HEIGHT, 1., color::WHITE, &DeviceOptions::default());
+    let mut source = FontSource::new();
+    let ids = fonts
+        .iter()
+        .map(|name| {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/fonts")
+                .join(name);
+            let data = std::fs::read(&path).expect("failed to read font");
+            source
+                .load_from_bytes(Arc::new(data), 0)
+                .expect("failed to load font")
+        })
+        .collect::<Vec<_>>();
+    let fonts = ids
+        .iter()
+        .map(|&id| source.get_font(id))
+        .collect::<Vec<_>>();
+    let mut document = Document::from_str(text);
+    document
+        .parse(&fonts, FONT_SIZE)
+        .expect("failed to parse document");
+    document.layout.set_wrap_width(Some(WIDTH as f32));
+    document.layout.finish();
+
+    let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+    let mut glyph_cache = GlyphCache::new();
+    let mut compositor = Compositor::new();
+    compositor.begin();
+    let render_options = GlyphRenderOptions::default();
+    let bias = render_options.subpixel_bias;
+    for g in document.layout.positioned_glyphs((0., 0.)) {
+        let options = GlyphRenderOptions {
+            synthetic_bold: g.synthesis.embolden,
+            synthetic_skew: g.synthesis.skew,
+            ..render_options
+        };
+        let mut session = glyph_cache.session(
+            context.renderer(),
+            &mut image_cache,
+            source.get_fontref(g.font_id),
+            g.size,
+            g.coords,
+            options,
+        );
+        let Some(entry) = session.get(g.id, g.x, g.y) else {
+            continue;
+        };
+        let Some(location) = session.get_texture_location(entry.image_id) else {
+            continue;
+        };
+        let rect = [
+            (g.x + bias.0).floor() + entry.left as f32,
+            (g.y + bias.1).floor() - entry.top as f32,
+            entry.width as f32,
+            entry.height as f32,
+        ];
+        if entry.is_bitmap {
+            compositor.add_image_rect(rect, 0.01, color::WHITE, location);
+        } else if entry.is_subpixel {
+            compositor.add_subpixel_rect(rect, 0.01, g.color, location);
+        } else {
+            compositor.add_grayscale_rect(rect, 0.01, g.color, location);
+        }
+    }
+    let display_list = compositor.end();
+    context
+        .render_to_buffer(&mut image_cache, &display_list)
+        .expect("failed to render")
+}
+
+/// Compares `pixels` against the golden image `name`, failing if any
+/// channel of any pixel is off by more than `max_delta`.
+fn assert_matches_golden(name: &str, pixels: &[u8], max_delta: u8) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(format!("{name}.png"));
+    if std::env::var_os("DUCK_UPDATE_GOLDEN").is_some() {
+        image::save_buffer(&path, pixels, WIDTH, HEIGHT, image::ColorType::Rgba8)
+            .expect("failed to write golden image");
+        eprintln!("recorded {}", path.display());
+        return;
+    }
+    assert!(
+        path.exists(),
+        "{name}: no golden image at {}, run with DUCK_UPDATE_GOLDEN=1 to record it",
+        path.display()
+    );
+    let golden = image::open(&path)
+        .expect("failed to read golden image")
+        .to_rgba8();
+    assert_eq!(
+        golden.dimensions(),
+        (WIDTH, HEIGHT),
+        "{name}: golden image has the wrong size"
+    );
+
+    // mismatched pixels in red over a faded copy of the rendered image
+    let mut diff = Vec::with_capacity(pixels.len());
+    let mut mismatched = 0;
+    for (actual, expected) in pixels.chunks_exact(4).zip(golden.as_raw().chunks_exact(4)) {
+        let delta = actual
+            .iter()
+            .zip(expected)
+            .map(|(a, e)| a.abs_diff(*e))
+            .max()
+            .unwrap_or(0);
+        if delta > max_delta {
+            mismatched += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            diff.extend(actual[..3].iter().map(|&c| 255 - (255 - c) / 4));
+            diff.push(255);
+        }
+    }
+    if mismatched > 0 {
+        let dir = std::env::temp_dir().join("duck-golden");
+        std::fs::create_dir_all(&dir).expect("failed to create diff directory");
+        let actual_path = dir.join(format!("{name}.png"));
+        let diff_path = dir.join(format!("{name}-diff.png"));
+        image::save_buffer(&actual_path, pixels, WIDTH, HEIGHT, image::ColorType::Rgba8)
+            .expect("failed to write rendered image");
+        image::save_buffer(&diff_path, &diff, WIDTH, HEIGHT, image::ColorType::Rgba8)
+            .expect("failed to write diff image");
+        panic!(
+            "{name}: {mismatched} pixels differ from {} by more than {max_delta}, see {}",
+            path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn plain_ascii() {
+    let pixels = render(
+        "The quick brown fox jumps over the lazy dog.",
+        &["DejaVuSans.ttf"],
+    );
+    assert_matches_golden("plain_ascii", &pixels, max_delta());
+}
+
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn emoji_sequence() {
+    let fonts = ["DejaVuSans.ttf", "NotoEmoji-Regular.ttf"];
+    let pixels = render("\u{1F680} #\u{FE0F}\u{20E3} \u{1F1EF}\u{1F1F5}", &fonts);
+    assert_matches_golden("emoji_sequence", &pixels, max_delta());
+}
+
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn mixed_font_line() {
+    let fonts = [
+        "DejaVuSans.ttf",
+        "FDArrayTest257.otf",
+        "NotoEmoji-Regular.ttf",
+    ];
+    let pixels = render("Hello, こんにちは \u{1F680}!", &fonts);
+    assert_matches_golden("mixed_font_line", &pixels, max_delta());
+}
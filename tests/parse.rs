@@ -0,0 +1,48 @@
+//! Parses the demo text of the editor through the public API, so that the
+//! signatures `main.rs` relies on can't drift from the library's.
+
+use std::{path::PathBuf, sync::Arc};
+
+use duck_wgpu::{document::Document, fonts::FontSource};
+
+/// The text the editor opens with, see `main.rs`.
+const DEMO_TEXT: &str = "y̆es 0️<=1(*️)2*3*#️⃣ 🧙🏻‍♂️⭐😶‍🌫️ *️*️*️ + 🦆&🙂😶\n🦆🦆🦆🦆🦆😶‍🌫️\ra\r\r\nSimple String!\n#️⃣#️⃣#️⃣#️⃣#️⃣\n\nHI!\tHi!\thi!\nこんにちは🇯🇵";
+
+#[test]
+fn demo_text_parses() {
+    let mut source = FontSource::new();
+    let ids = [
+        "DejaVuSans.ttf",
+        "NotoEmoji-Regular.ttf",
+        "FDArrayTest257.otf",
+    ]
+    .map(|name| {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/fonts")
+            .join(name);
+        let data = std::fs::read(&path).expect("failed to read font");
+        source
+            .load_from_bytes(Arc::new(data), 0)
+            .expect("failed to load font")
+    });
+    let fonts = ids.map(|id| source.get_font(id));
+
+    let mut document = Document::from_str(DEMO_TEXT);
+    document.set_scale(2.);
+    document.parse(&fonts, 16.).expect("failed to parse");
+    document.layout.finish();
+
+    assert!(document.layout.paragraph_count() > 1);
+    let (width, height) = document.layout.total_size();
+    assert!(width > 0. && height > 0.);
+    for line in &document.layout.lines {
+        for run in &line.runs {
+            assert!(ids.contains(&run.font_id));
+            assert!(
+                DEMO_TEXT.get(run.range.clone()).is_some(),
+                "{:?}",
+                run.range
+            );
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Renders display lists without a window and checks pixels of the result.
+//! Like the golden image tests these need a GPU or software adapter, so
+//! they're ignored by default; run them with `cargo test -- --ignored`.
+
+use duck_wgpu::gfx::{
+    color,
+    compositor::{Compositor, DisplayList},
+    image_cache::{ImageCache, MASK_FORMAT},
+    wgpu_context::{DeviceOptions, WgpuContext},
+};
+
+const WIDTH: u32 = 80;
+const HEIGHT: u32 = 8;
+
+/// Returns the RGBA pixel at `(x, y)` of `pixels`.
+fn pixel(pixels: &[u8], x: u32, y: u32) -> [u8; 4] {
+    let i = ((y * WIDTH + x) * 4) as usize;
+    pixels[i..i + 4].try_into().unwrap()
+}
+
+fn assert_black(pixels: &[u8], x: u32) {
+    let p = pixel(pixels, x, HEIGHT / 2);
+    assert!(
+        p[..3].iter().all(|&c| c < 32),
+        "pixel at {x} is {p:?}, not black"
+    );
+}
+
+fn assert_white(pixels: &[u8], x: u32) {
+    let p = pixel(pixels, x, HEIGHT / 2);
+    assert!(
+        p[..3].iter().all(|&c| c > 223),
+        "pixel at {x} is {p:?}, not white"
+    );
+}
+
+/// Subpixel draws are replayed once per color channel, so the clip and
+/// transform changes between them have to be replayed too, starting from
+/// the state before the first draw every time.
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn subpixel_draws_keep_clips_and_transforms() {
+    let mut context =
+        WgpuContext::new_headless(WIDTH, HEIGHT, 1., color::WHITE, &DeviceOptions::default());
+    let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+    // full coverage in every channel, so the glyphs come out black
+    let image_id = image_cache
+        .allocate(context.renderer(), 4, 4, &[255; 4 * 4 * 4], MASK_FORMAT)
+        .unwrap();
+    let location = image_cache.get_image_location(image_id).unwrap();
+
+    let mut compositor = Compositor::new();
+    compositor.begin();
+    // back to front by depth, alternating between batches
+    compositor.add_subpixel_rect([0., 0., 8., 8.], 0.5, color::BLACK, location);
+    compositor.push_clip([20., 0., 4., 8.]);
+    compositor.add_subpixel_rect([16., 0., 16., 8.], 0.4, color::BLACK, location);
+    compositor.pop_clip();
+    compositor.set_draw_transform([40., 0.], 1.);
+    compositor.add_subpixel_rect([0., 0., 8., 8.], 0.3, color::BLACK, location);
+    // the last draw leaves both a clip and a transform set
+    compositor.push_clip([60., 0., 8., 8.]);
+    compositor.set_draw_transform([56., 0.], 1.);
+    compositor.add_subpixel_rect([0., 0., 16., 8.], 0.2, color::BLACK, location);
+    compositor.set_draw_transform([0., 0.], 1.);
+    compositor.pop_clip();
+    let display_list = compositor.end();
+    let pixels = context
+        .render_to_buffer(&mut image_cache, &display_list)
+        .expect("failed to render");
+
+    assert_black(&pixels, 4);
+    // clipped
+    assert_white(&pixels, 18);
+    assert_black(&pixels, 21);
+    assert_white(&pixels, 26);
+    // transformed
+    assert_black(&pixels, 44);
+    // clipped and transformed
+    assert_white(&pixels, 58);
+    assert_black(&pixels, 64);
+    assert_white(&pixels, 70);
+}
+
+/// A minimized window has no size, so a context can be created at 0x0;
+/// it's clamped to a single pixel instead of failing in wgpu.
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn zero_size_context_renders_a_pixel() {
+    let mut context = WgpuContext::new_headless(0, 0, 1., color::WHITE, &DeviceOptions::default());
+    let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+    let pixels = context
+        .render_to_buffer(&mut image_cache, &DisplayList::new())
+        .expect("failed to render");
+    assert_eq!(pixels, [255; 4]);
+}
+
+/// Antialiasing only changes the edges of rects at fractional positions;
+/// a rect on whole pixels covers exactly the same pixels.
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn antialiased_rect_on_whole_pixels_is_unchanged() {
+    let mut context =
+        WgpuContext::new_headless(WIDTH, HEIGHT, 1., color::WHITE, &DeviceOptions::default());
+    let mut image_cache = ImageCache::new(context.device.limits().max_texture_dimension_2d);
+    let mut compositor = Compositor::new();
+    let mut render = |antialiased: bool| {
+        compositor.begin();
+        if antialiased {
+            compositor.draw_rect_aa([10., 2., 20., 4.], 0.5, color::BLACK);
+        } else {
+            compositor.draw_rect([10., 2., 20., 4.], 0.5, color::BLACK);
+        }
+        let display_list = compositor.end();
+        context
+            .render_to_buffer(&mut image_cache, &display_list)
+            .expect("failed to render")
+    };
+    let hard = render(false);
+    let antialiased = render(true);
+    assert_black(&hard, 10);
+    assert_white(&hard, 9);
+    for (i, (a, b)) in antialiased.iter().zip(&hard).enumerate() {
+        assert!(a.abs_diff(*b) <= 2, "byte {i} is {a}, not {b}");
+    }
+}
+
+/// Contexts sharing a renderer keep their own targets, so rendering into
+/// one doesn't show in the other.
+#[test]
+#[ignore = "needs a GPU or software adapter"]
+fn shared_renderer_renders_to_both_targets() {
+    let mut first =
+        WgpuContext::new_headless(WIDTH, HEIGHT, 1., color::WHITE, &DeviceOptions::default());
+    let mut second = WgpuContext::with_renderer_headless(
+        first.renderer().clone(),
+        WIDTH,
+        HEIGHT,
+        1.,
+        color::WHITE,
+    );
+    let mut image_cache = ImageCache::new(first.device.limits().max_texture_dimension_2d);
+    let mut compositor = Compositor::new();
+    let mut draw = |rect: [f32; 4]| {
+        compositor.begin();
+        compositor.draw_rect(rect, 0.5, color::BLACK);
+        compositor.end()
+    };
+    let left = draw([0., 0., 10., 8.]);
+    let right = draw([40., 0., 10., 8.]);
+    let first_pixels = first
+        .render_to_buffer(&mut image_cache, &left)
+        .expect("failed to render");
+    let second_pixels = second
+        .render_to_buffer(&mut image_cache, &right)
+        .expect("failed to render");
+
+    assert_black(&first_pixels, 4);
+    assert_white(&first_pixels, 44);
+    assert_white(&second_pixels, 4);
+    assert_black(&second_pixels, 44);
+}